@@ -0,0 +1,561 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use chrono::{DateTime, Duration, Local, SecondsFormat};
+use serde_json::json;
+
+use crate::opts::AlarmOptions;
+use crate::recording::Sample;
+
+
+/// The condition that raised an alarm.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AlarmKind {
+    Spo2Low,
+    PulseLow,
+    PulseHigh,
+    FingerOut,
+    NoData,
+}
+impl AlarmKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Spo2Low => "spo2-low",
+            Self::PulseLow => "pulse-low",
+            Self::PulseHigh => "pulse-high",
+            Self::FingerOut => "finger-out",
+            Self::NoData => "no-data",
+        }
+    }
+}
+
+
+/// Whether an alarm has been raised or cleared.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AlarmState {
+    Raised,
+    Cleared,
+}
+impl AlarmState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Raised => "raised",
+            Self::Cleared => "cleared",
+        }
+    }
+}
+
+
+/// An alarm being raised or cleared.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AlarmEvent {
+    pub timestamp: DateTime<Local>,
+    pub kind: AlarmKind,
+    pub state: AlarmState,
+
+    /// The SpO2 or pulse value that raised or cleared the alarm; `None` for alarms that are not
+    /// about a value.
+    pub value: Option<u8>,
+
+    /// The threshold of the alarm: a value for SpO2 and pulse alarms, a number of seconds for the
+    /// finger-out and no-data alarms.
+    pub threshold: u32,
+}
+impl AlarmEvent {
+    /// A human-readable description of the event.
+    pub fn describe(&self) -> String {
+        let value = self.value.map(|v| v.to_string()).unwrap_or_else(|| "?".to_owned());
+        match (self.kind, self.state) {
+            (AlarmKind::Spo2Low, AlarmState::Raised) => format!("SpO2 {} % below {} %", value, self.threshold),
+            (AlarmKind::Spo2Low, AlarmState::Cleared) => format!("SpO2 back at {} %", value),
+            (AlarmKind::PulseLow, AlarmState::Raised) => format!("pulse {} bpm below {} bpm", value, self.threshold),
+            (AlarmKind::PulseHigh, AlarmState::Raised) => format!("pulse {} bpm above {} bpm", value, self.threshold),
+            (AlarmKind::PulseLow, AlarmState::Cleared)|(AlarmKind::PulseHigh, AlarmState::Cleared) => format!("pulse back at {} bpm", value),
+            (AlarmKind::FingerOut, AlarmState::Raised) => format!("finger out for {} s", self.threshold),
+            (AlarmKind::FingerOut, AlarmState::Cleared) => "finger back in".to_owned(),
+            (AlarmKind::NoData, AlarmState::Raised) => format!("no data for {} s", self.threshold),
+            (AlarmKind::NoData, AlarmState::Cleared) => "data arriving again".to_owned(),
+        }
+    }
+
+    /// The line describing the event in the alarm log.
+    pub fn log_line(&self) -> String {
+        format!(
+            "{} alarm {} {}: {}",
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Secs, false),
+            self.kind.as_str(), self.state.as_str(), self.describe(),
+        )
+    }
+
+    /// The event as passed to the alarm command.
+    pub fn to_json(self, device_id: Option<&str>) -> serde_json::Value {
+        json!({
+            "timestamp": self.timestamp.to_rfc3339_opts(SecondsFormat::Secs, false),
+            "device_id": device_id,
+            "alarm": self.kind.as_str(),
+            "state": self.state.as_str(),
+            "value": self.value,
+            "threshold": self.threshold,
+            "description": self.describe(),
+        })
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Direction {
+    Below,
+    Above,
+}
+
+
+/// An alarm on SpO2 or pulse crossing a threshold.
+///
+/// The alarm is raised once the value has been beyond the threshold for the minimum duration and
+/// only cleared once it has come back by at least the hysteresis, so that a value hovering around
+/// the threshold does not raise the alarm over and over again.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct ThresholdAlarm {
+    kind: AlarmKind,
+    direction: Direction,
+    threshold: u8,
+    hysteresis: u8,
+    min_duration: Duration,
+    beyond_since: Option<DateTime<Local>>,
+    raised: bool,
+}
+impl ThresholdAlarm {
+    fn new(kind: AlarmKind, direction: Direction, threshold: u8, options: &AlarmOptions) -> Self {
+        Self {
+            kind,
+            direction,
+            threshold,
+            hysteresis: options.hysteresis,
+            min_duration: Duration::seconds(options.min_duration.into()),
+            beyond_since: None,
+            raised: false,
+        }
+    }
+
+    fn feed(&mut self, timestamp: DateTime<Local>, value: Option<u8>) -> Option<AlarmEvent> {
+        let value = match value {
+            Some(v) => v,
+            None => {
+                // invalid values neither raise nor clear the alarm, but interrupt the countdown
+                self.beyond_since = None;
+                return None;
+            },
+        };
+
+        let state = if self.raised {
+            let recovered = match self.direction {
+                Direction::Below => value >= self.threshold.saturating_add(self.hysteresis),
+                Direction::Above => value <= self.threshold.saturating_sub(self.hysteresis),
+            };
+            if !recovered {
+                return None;
+            }
+            self.raised = false;
+            self.beyond_since = None;
+            AlarmState::Cleared
+        } else {
+            let beyond = match self.direction {
+                Direction::Below => value < self.threshold,
+                Direction::Above => value > self.threshold,
+            };
+            if !beyond {
+                self.beyond_since = None;
+                return None;
+            }
+            let beyond_since = *self.beyond_since.get_or_insert(timestamp);
+            if timestamp - beyond_since < self.min_duration {
+                return None;
+            }
+            self.raised = true;
+            AlarmState::Raised
+        };
+
+        Some(AlarmEvent {
+            timestamp,
+            kind: self.kind,
+            state,
+            value: Some(value),
+            threshold: self.threshold.into(),
+        })
+    }
+}
+
+
+/// An alarm on a condition lasting for too long, such as the finger being out.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct DurationAlarm {
+    kind: AlarmKind,
+    limit_secs: u32,
+    since: Option<DateTime<Local>>,
+    raised: bool,
+}
+impl DurationAlarm {
+    fn new(kind: AlarmKind, limit_secs: u32) -> Self {
+        Self {
+            kind,
+            limit_secs,
+            since: None,
+            raised: false,
+        }
+    }
+
+    /// Notes whether the condition holds at the given time.
+    fn feed(&mut self, timestamp: DateTime<Local>, condition: bool) -> Option<AlarmEvent> {
+        let state = if condition {
+            let since = *self.since.get_or_insert(timestamp);
+            if self.raised || timestamp - since < Duration::seconds(self.limit_secs.into()) {
+                return None;
+            }
+            self.raised = true;
+            AlarmState::Raised
+        } else {
+            self.since = None;
+            if !self.raised {
+                return None;
+            }
+            self.raised = false;
+            AlarmState::Cleared
+        };
+
+        Some(AlarmEvent {
+            timestamp,
+            kind: self.kind,
+            state,
+            value: None,
+            threshold: self.limit_secs,
+        })
+    }
+}
+
+
+/// Watches the live samples and decides when to raise and clear alarms.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct AlarmMonitor {
+    threshold_alarms: Vec<ThresholdAlarm>,
+    finger_out: Option<DurationAlarm>,
+    no_data: Option<DurationAlarm>,
+    last_data: DateTime<Local>,
+}
+impl AlarmMonitor {
+    /// Creates a monitor for the alarms configured in the options. Returns `None` if no alarms are
+    /// configured. The session is assumed to start at `start`.
+    pub fn new(options: &AlarmOptions, start: DateTime<Local>) -> Option<Self> {
+        let mut threshold_alarms = Vec::new();
+        if let Some(threshold) = options.spo2_below {
+            threshold_alarms.push(ThresholdAlarm::new(AlarmKind::Spo2Low, Direction::Below, threshold, options));
+        }
+        if let Some(threshold) = options.pulse_below {
+            threshold_alarms.push(ThresholdAlarm::new(AlarmKind::PulseLow, Direction::Below, threshold, options));
+        }
+        if let Some(threshold) = options.pulse_above {
+            threshold_alarms.push(ThresholdAlarm::new(AlarmKind::PulseHigh, Direction::Above, threshold, options));
+        }
+        let finger_out = options.finger_out
+            .map(|secs| DurationAlarm::new(AlarmKind::FingerOut, secs));
+        let no_data = options.no_data
+            .map(|secs| DurationAlarm::new(AlarmKind::NoData, secs));
+
+        if threshold_alarms.is_empty() && finger_out.is_none() && no_data.is_none() {
+            return None;
+        }
+        Some(Self {
+            threshold_alarms,
+            finger_out,
+            no_data,
+            last_data: start,
+        })
+    }
+
+    /// Processes a live sample. Returns the alarms that have been raised or cleared.
+    pub fn feed(&mut self, sample: &Sample) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        self.last_data = sample.timestamp;
+        if let Some(no_data) = self.no_data.as_mut() {
+            events.extend(no_data.feed(sample.timestamp, false));
+        }
+
+        let finger_out = sample.spo2.is_none() || sample.pulse.is_none();
+        if let Some(alarm) = self.finger_out.as_mut() {
+            events.extend(alarm.feed(sample.timestamp, finger_out));
+        }
+        for alarm in &mut self.threshold_alarms {
+            let value = match alarm.kind {
+                AlarmKind::Spo2Low => sample.spo2,
+                _ => sample.pulse,
+            };
+            events.extend(alarm.feed(sample.timestamp, value));
+        }
+        events
+    }
+
+    /// Checks whether data has stopped arriving. Call this regularly, even if no samples arrive.
+    pub fn check(&mut self, now: DateTime<Local>) -> Option<AlarmEvent> {
+        let last_data = self.last_data;
+        let no_data = self.no_data.as_mut()?;
+        if now - last_data < Duration::seconds(no_data.limit_secs.into()) {
+            return None;
+        }
+        // count from the last sample, not from the first check that noticed the gap
+        no_data.feed(last_data, true);
+        no_data.feed(now, true)
+    }
+}
+
+
+/// Announces alarm events: writes them to the alarm log, runs the alarm command and rings the
+/// bell.
+pub struct AlarmNotifier {
+    device_id: Option<String>,
+    log: Option<BufWriter<File>>,
+    command: Option<String>,
+    bell: bool,
+}
+impl AlarmNotifier {
+    pub fn new(options: &AlarmOptions, device_id: Option<String>) -> Self {
+        let log = options.log.as_ref().map(|path| {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("failed to open alarm log");
+            BufWriter::new(file)
+        });
+        Self {
+            device_id,
+            log,
+            command: options.command.clone(),
+            bell: options.bell,
+        }
+    }
+
+    /// Whether the log lines are written to a file (and not expected to be shown by the caller).
+    pub fn has_log_file(&self) -> bool {
+        self.log.is_some()
+    }
+
+    /// Announces the event. Returns the log line describing it.
+    pub fn notify(&mut self, event: &AlarmEvent) -> String {
+        let line = event.log_line();
+        if let Some(log) = self.log.as_mut() {
+            writeln!(log, "{}", line)
+                .expect("failed to write alarm log");
+            log.flush()
+                .expect("failed to flush alarm log");
+        }
+
+        if let Some(command) = self.command.as_ref() {
+            let input = event.to_json(self.device_id.as_deref()).to_string();
+            run_alarm_command(command, input);
+        }
+
+        if self.bell && event.state == AlarmState::Raised {
+            eprint!("\x07");
+        }
+
+        line
+    }
+}
+
+
+/// Runs the alarm command in the background, passing it the given input.
+fn run_alarm_command(command: &str, input: String) {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let child = shell
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("failed to run alarm command: {}", e);
+            return;
+        },
+    };
+
+    // don't hold up the live data while the command is running
+    thread::spawn(move || {
+        if let Some(mut stdin) = child.stdin.take() {
+            // the command is free to ignore its input
+            let _ = stdin.write_all(input.as_bytes());
+            let _ = stdin.write_all(b"\n");
+        }
+        match child.wait() {
+            Ok(status) if !status.success() => eprintln!("alarm command failed: {}", status),
+            Ok(_) => {},
+            Err(e) => eprintln!("failed to wait for alarm command: {}", e),
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::recording::ArtifactFlags;
+
+    fn at(seconds: i64) -> DateTime<Local> {
+        Local.ymd(2021, 3, 1).and_hms(22, 0, 0) + Duration::seconds(seconds)
+    }
+
+    fn sample(seconds: i64, pulse: Option<u8>, spo2: Option<u8>) -> Sample {
+        Sample {
+            timestamp: at(seconds),
+            pulse,
+            spo2,
+            artifacts: ArtifactFlags::default(),
+        }
+    }
+
+    fn options() -> AlarmOptions {
+        AlarmOptions {
+            spo2_below: None,
+            pulse_below: None,
+            pulse_above: None,
+            min_duration: 10,
+            hysteresis: 2,
+            finger_out: None,
+            no_data: None,
+            command: None,
+            log: None,
+            bell: false,
+        }
+    }
+
+    /// Feeds the values at one per second, starting at `first_second`, and returns the seconds at
+    /// which events occurred along with their states.
+    fn feed_all(alarm: &mut ThresholdAlarm, first_second: i64, values: &[Option<u8>]) -> Vec<(i64, AlarmState)> {
+        values.iter().enumerate()
+            .filter_map(|(i, value)| {
+                let second = first_second + i as i64;
+                alarm.feed(at(second), *value).map(|e| (second, e.state))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn threshold_waits_for_min_duration() {
+        let mut alarm = ThresholdAlarm::new(AlarmKind::Spo2Low, Direction::Below, 90, &options());
+        assert_eq!(feed_all(&mut alarm, 0, &[Some(89); 10]), vec![]);
+        let event = alarm.feed(at(10), Some(88)).unwrap();
+        assert_eq!(event.kind, AlarmKind::Spo2Low);
+        assert_eq!(event.state, AlarmState::Raised);
+        assert_eq!(event.value, Some(88));
+        assert_eq!(event.threshold, 90);
+
+        // a short dip does not count
+        let mut alarm = ThresholdAlarm::new(AlarmKind::Spo2Low, Direction::Below, 90, &options());
+        let values = [Some(89), Some(89), Some(90), Some(89), Some(89)];
+        assert_eq!(feed_all(&mut alarm, 0, &values), vec![]);
+    }
+
+    #[test]
+    fn threshold_hysteresis() {
+        let mut alarm = ThresholdAlarm::new(AlarmKind::Spo2Low, Direction::Below, 90, &options());
+        assert_eq!(feed_all(&mut alarm, 0, &[Some(85); 11]), vec![(10, AlarmState::Raised)]);
+        // back at the threshold, but not by the hysteresis
+        assert_eq!(feed_all(&mut alarm, 11, &[Some(90), Some(91), Some(89), Some(92)]), vec![(14, AlarmState::Cleared)]);
+        // after clearing, the minimum duration applies again
+        assert_eq!(feed_all(&mut alarm, 15, &[Some(85); 11]), vec![(25, AlarmState::Raised)]);
+
+        let mut alarm = ThresholdAlarm::new(AlarmKind::PulseHigh, Direction::Above, 120, &options());
+        assert_eq!(feed_all(&mut alarm, 0, &[Some(130); 11]), vec![(10, AlarmState::Raised)]);
+        assert_eq!(feed_all(&mut alarm, 11, &[Some(120), Some(119), Some(118)]), vec![(13, AlarmState::Cleared)]);
+    }
+
+    #[test]
+    fn invalid_value_interrupts_countdown() {
+        let mut alarm = ThresholdAlarm::new(AlarmKind::PulseLow, Direction::Below, 50, &options());
+        let mut values = vec![Some(45); 6];
+        values.push(None);
+        values.extend([Some(45); 11]);
+        assert_eq!(feed_all(&mut alarm, 0, &values), vec![(17, AlarmState::Raised)]);
+
+        // but do not clear a raised alarm
+        assert_eq!(feed_all(&mut alarm, 18, &[None, None]), vec![]);
+        assert_eq!(feed_all(&mut alarm, 20, &[Some(52)]), vec![(20, AlarmState::Cleared)]);
+    }
+
+    #[test]
+    fn duration_alarm() {
+        let mut alarm = DurationAlarm::new(AlarmKind::FingerOut, 5);
+        let states: Vec<(i64, AlarmState)> = [false, true, true, true, true, true, true, true, false, false]
+            .iter().enumerate()
+            .filter_map(|(i, condition)| alarm.feed(at(i as i64), *condition).map(|e| (i as i64, e.state)))
+            .collect();
+        assert_eq!(states, vec![(6, AlarmState::Raised), (8, AlarmState::Cleared)]);
+    }
+
+    #[test]
+    fn monitor_needs_alarms() {
+        assert!(AlarmMonitor::new(&options(), at(0)).is_none());
+    }
+
+    #[test]
+    fn monitor_finger_out() {
+        let options = AlarmOptions {
+            spo2_below: Some(90),
+            finger_out: Some(3),
+            ..options()
+        };
+        let mut monitor = AlarmMonitor::new(&options, at(0)).unwrap();
+        let mut events = Vec::new();
+        for second in 0..5 {
+            events.extend(monitor.feed(&sample(second, None, None)));
+        }
+        events.extend(monitor.feed(&sample(5, Some(60), Some(97))));
+
+        let kinds: Vec<(AlarmKind, AlarmState)> = events.iter().map(|e| (e.kind, e.state)).collect();
+        assert_eq!(kinds, vec![
+            (AlarmKind::FingerOut, AlarmState::Raised),
+            (AlarmKind::FingerOut, AlarmState::Cleared),
+        ]);
+        assert_eq!(events[0].timestamp, at(3));
+    }
+
+    #[test]
+    fn no_data_counts_from_last_sample() {
+        let options = AlarmOptions {
+            no_data: Some(10),
+            ..options()
+        };
+        let mut monitor = AlarmMonitor::new(&options, at(0)).unwrap();
+        assert_eq!(monitor.feed(&sample(2, Some(60), Some(97))), vec![]);
+
+        assert_eq!(monitor.check(at(8)), None);
+        // ten seconds after the last sample, not after the first check
+        let event = monitor.check(at(12)).unwrap();
+        assert_eq!((event.kind, event.state, event.timestamp), (AlarmKind::NoData, AlarmState::Raised, at(12)));
+        assert_eq!(monitor.check(at(13)), None);
+
+        let events = monitor.feed(&sample(14, Some(60), Some(97)));
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].kind, events[0].state), (AlarmKind::NoData, AlarmState::Cleared));
+    }
+
+    #[test]
+    fn no_data_counts_from_session_start() {
+        let options = AlarmOptions {
+            no_data: Some(10),
+            ..options()
+        };
+        let mut monitor = AlarmMonitor::new(&options, at(0)).unwrap();
+        assert_eq!(monitor.check(at(9)), None);
+        assert!(monitor.check(at(10)).is_some());
+    }
+}
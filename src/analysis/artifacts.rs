@@ -0,0 +1,335 @@
+use chrono::{DateTime, Duration, Local};
+
+use crate::analysis::SAMPLE_PERIOD_SECONDS;
+use crate::opts::ArtifactMode;
+use crate::recording::{ArtifactFlags, Sample};
+
+
+/// Settings for the artifact filter.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ArtifactParams {
+    pub mode: ArtifactMode,
+
+    /// The largest plausible change of SpO2 per second, in percentage points.
+    pub max_spo2_rate: u8,
+
+    /// The largest plausible change of the pulse rate per second, in beats per minute.
+    pub max_pulse_rate: u8,
+
+    /// How long SpO2 and pulse may stay exactly the same before they are considered a flatline.
+    /// Zero disables flatline detection.
+    pub flatline: Duration,
+
+    /// The plausible SpO2 values (inclusive).
+    pub spo2_range: (u8, u8),
+
+    /// The plausible pulse rates (inclusive).
+    pub pulse_range: (u8, u8),
+}
+
+
+/// Flags the values of one series (SpO2 or pulse) that are out of range or changed implausibly
+/// fast. The rate of change is measured against the last value that was not flagged, so that a
+/// single outlier does not cause the value after it to be flagged as well.
+fn flag_series(samples: &[Sample], value_of: fn(&Sample) -> Option<u8>, max_rate: u8, range: (u8, u8)) -> Vec<ArtifactFlags> {
+    let mut flags = vec![ArtifactFlags::default(); samples.len()];
+    let mut last_accepted: Option<(DateTime<Local>, u8)> = None;
+    for (i, sample) in samples.iter().enumerate() {
+        let value = match value_of(sample) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        if value < range.0 || value > range.1 {
+            flags[i].out_of_range = true;
+            continue;
+        }
+
+        if let Some((last_timestamp, last_value)) = last_accepted {
+            // live samples do not arrive exactly one second apart; do not let jitter inflate the rate
+            let elapsed_ms = (sample.timestamp - last_timestamp).num_milliseconds()
+                .max(SAMPLE_PERIOD_SECONDS * 1000);
+            let change = (i16::from(value) - i16::from(last_value)).abs();
+            let rate = f64::from(change) * 1000.0 / (elapsed_ms as f64);
+            if rate > f64::from(max_rate) {
+                flags[i].rate_of_change = true;
+                continue;
+            }
+        }
+        last_accepted = Some((sample.timestamp, value));
+    }
+    flags
+}
+
+/// Flags runs in which both SpO2 and pulse stay exactly the same for at least the given duration;
+/// real readings fluctuate at least a little.
+fn flag_flatlines(samples: &[Sample], min_duration: Duration) -> Vec<bool> {
+    let sample_period = Duration::seconds(SAMPLE_PERIOD_SECONDS);
+    let mut flags = vec![false; samples.len()];
+    if min_duration <= Duration::zero() {
+        return flags;
+    }
+
+    let mut run_start = 0;
+    for i in 1..=samples.len() {
+        let continues = i < samples.len()
+            && samples[i].spo2.is_some()
+            && samples[i].pulse.is_some()
+            && samples[i].spo2 == samples[run_start].spo2
+            && samples[i].pulse == samples[run_start].pulse;
+        if continues {
+            continue;
+        }
+
+        let run_valid = samples[run_start].spo2.is_some() && samples[run_start].pulse.is_some();
+        let run_duration = samples[i - 1].timestamp - samples[run_start].timestamp + sample_period;
+        if run_valid && run_duration >= min_duration {
+            for flag in &mut flags[run_start..i] {
+                *flag = true;
+            }
+        }
+        run_start = i;
+    }
+    flags
+}
+
+/// Replaces the flagged values of one series by values linearly interpolated (over time) from the
+/// nearest valid values on either side. Flagged values without a valid neighbor on both sides are
+/// made invalid. Returns which values have been interpolated.
+fn interpolate_series(samples: &mut [Sample], flagged: &[bool], value_of: fn(&Sample) -> Option<u8>, set_value: fn(&mut Sample, Option<u8>)) -> Vec<bool> {
+    let mut interpolated = vec![false; samples.len()];
+    let mut i = 0;
+    while i < samples.len() {
+        if !flagged[i] {
+            i += 1;
+            continue;
+        }
+
+        let run_start = i;
+        while i < samples.len() && flagged[i] {
+            i += 1;
+        }
+        let run_end = i;
+
+        let before = run_start.checked_sub(1)
+            .and_then(|b| value_of(&samples[b]).map(|v| (samples[b].timestamp, v)));
+        let after = samples.get(run_end)
+            .and_then(|a| value_of(a).map(|v| (a.timestamp, v)));
+        for j in run_start..run_end {
+            match (before, after) {
+                (Some((before_time, before_value)), Some((after_time, after_value))) => {
+                    let total_ms = (after_time - before_time).num_milliseconds() as f64;
+                    let elapsed_ms = (samples[j].timestamp - before_time).num_milliseconds() as f64;
+                    let value = f64::from(before_value)
+                        + (f64::from(after_value) - f64::from(before_value)) * elapsed_ms / total_ms;
+                    set_value(&mut samples[j], Some(value.round() as u8));
+                    interpolated[j] = true;
+                },
+                _ => set_value(&mut samples[j], None),
+            }
+        }
+    }
+    interpolated
+}
+
+
+/// Runs the artifact filter over the given samples.
+///
+/// Implausible values are flagged in the samples' artifact flags. Depending on the mode, they are
+/// then left as they are, made invalid or replaced by interpolated values; the flags are kept in
+/// any case.
+pub fn filter_artifacts(samples: &mut [Sample], params: &ArtifactParams) {
+    let spo2_flags = flag_series(samples, |s| s.spo2, params.max_spo2_rate, params.spo2_range);
+    let pulse_flags = flag_series(samples, |s| s.pulse, params.max_pulse_rate, params.pulse_range);
+    let flatline_flags = flag_flatlines(samples, params.flatline);
+
+    for (i, sample) in samples.iter_mut().enumerate() {
+        sample.artifacts.rate_of_change |= spo2_flags[i].rate_of_change || pulse_flags[i].rate_of_change;
+        sample.artifacts.out_of_range |= spo2_flags[i].out_of_range || pulse_flags[i].out_of_range;
+        sample.artifacts.flatline |= flatline_flags[i];
+    }
+
+    let spo2_flagged: Vec<bool> = (0..samples.len())
+        .map(|i| spo2_flags[i].is_artifact() || flatline_flags[i])
+        .collect();
+    let pulse_flagged: Vec<bool> = (0..samples.len())
+        .map(|i| pulse_flags[i].is_artifact() || flatline_flags[i])
+        .collect();
+
+    match params.mode {
+        ArtifactMode::Mark => {},
+        ArtifactMode::Drop => {
+            for (i, sample) in samples.iter_mut().enumerate() {
+                if spo2_flagged[i] {
+                    sample.spo2 = None;
+                }
+                if pulse_flagged[i] {
+                    sample.pulse = None;
+                }
+            }
+        },
+        ArtifactMode::Interpolate => {
+            let spo2_interpolated = interpolate_series(samples, &spo2_flagged, |s| s.spo2, |s, v| s.spo2 = v);
+            let pulse_interpolated = interpolate_series(samples, &pulse_flagged, |s| s.pulse, |s, v| s.pulse = v);
+            for (i, sample) in samples.iter_mut().enumerate() {
+                sample.artifacts.interpolated |= spo2_interpolated[i] || pulse_interpolated[i];
+            }
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn start() -> DateTime<Local> {
+        Local.ymd(2021, 3, 1).and_hms(22, 0, 0)
+    }
+
+    /// Builds a once-per-second series from (pulse, SpO2) pairs.
+    fn series(values: &[(Option<u8>, Option<u8>)]) -> Vec<Sample> {
+        values.iter().enumerate()
+            .map(|(i, (pulse, spo2))| Sample {
+                timestamp: start() + Duration::seconds(i as i64),
+                pulse: *pulse,
+                spo2: *spo2,
+                artifacts: ArtifactFlags::default(),
+            })
+            .collect()
+    }
+
+    /// Builds a series with a steady pulse from SpO2 values.
+    fn spo2_series(values: &[Option<u8>]) -> Vec<Sample> {
+        let pairs: Vec<(Option<u8>, Option<u8>)> = values.iter()
+            .enumerate()
+            .map(|(i, spo2)| (Some(60 + (i % 2) as u8), *spo2))
+            .collect();
+        series(&pairs)
+    }
+
+    /// The defaults of the command line options, without flatline detection.
+    fn params(mode: ArtifactMode) -> ArtifactParams {
+        ArtifactParams {
+            mode,
+            max_spo2_rate: 4,
+            max_pulse_rate: 10,
+            flatline: Duration::zero(),
+            spo2_range: (50, 100),
+            pulse_range: (25, 250),
+        }
+    }
+
+    fn flagged(samples: &[Sample]) -> Vec<usize> {
+        samples.iter().enumerate()
+            .filter(|(_, s)| s.artifacts.is_artifact())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn pulse_jump() {
+        // the jump from 113 to 98 bpm in the protocol notes
+        let pulses = [113, 98, 99, 100];
+        let pairs: Vec<(Option<u8>, Option<u8>)> = pulses.iter()
+            .zip([97, 96, 97, 96])
+            .map(|(p, s)| (Some(*p), Some(s)))
+            .collect();
+        let mut samples = series(&pairs);
+        filter_artifacts(&mut samples, &params(ArtifactMode::Mark));
+
+        assert_eq!(flagged(&samples), vec![1]);
+        assert!(samples[1].artifacts.rate_of_change);
+        // 99 is measured against 113 two seconds earlier, which is plausible again
+        assert!(samples[2].artifacts.is_empty());
+    }
+
+    #[test]
+    fn rate_is_measured_against_last_accepted_value() {
+        // a single outlier does not get the value after it flagged too
+        let mut samples = spo2_series(&[Some(97), Some(80), Some(97), Some(96)]);
+        filter_artifacts(&mut samples, &params(ArtifactMode::Mark));
+        assert_eq!(flagged(&samples), vec![1]);
+
+        // while a lasting drop is only flagged until it has become plausible over time
+        let mut samples = spo2_series(&[Some(97), Some(88), Some(88), Some(88), Some(88)]);
+        filter_artifacts(&mut samples, &params(ArtifactMode::Mark));
+        assert_eq!(flagged(&samples), vec![1, 2]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut samples = series(&[
+            (Some(60), Some(97)),
+            (Some(61), Some(40)),
+            (Some(251), Some(97)),
+            (None, None),
+            (Some(62), Some(96)),
+        ]);
+        filter_artifacts(&mut samples, &params(ArtifactMode::Mark));
+        assert_eq!(flagged(&samples), vec![1, 2]);
+        assert!(samples[1].artifacts.out_of_range && !samples[1].artifacts.rate_of_change);
+        assert!(samples[2].artifacts.out_of_range);
+        // invalid values are not artifacts
+        assert!(samples[3].artifacts.is_empty());
+    }
+
+    #[test]
+    fn flatline() {
+        let mut values = vec![(Some(60), Some(97)); 6];
+        values.extend([(Some(61), Some(96)); 4]);
+        values.extend([(None, None); 10]);
+        values.push((Some(62), Some(97)));
+        let mut samples = series(&values);
+        let params = ArtifactParams {
+            flatline: Duration::seconds(5),
+            ..params(ArtifactMode::Mark)
+        };
+        filter_artifacts(&mut samples, &params);
+
+        assert_eq!(flagged(&samples), vec![0, 1, 2, 3, 4, 5]);
+        assert!(samples[..6].iter().all(|s| s.artifacts.flatline));
+    }
+
+    #[test]
+    fn modes() {
+        let values = [Some(97), Some(80), Some(95), Some(96)];
+
+        let mut marked = spo2_series(&values);
+        filter_artifacts(&mut marked, &params(ArtifactMode::Mark));
+        assert_eq!(marked[1].spo2, Some(80));
+        assert!(marked[1].artifacts.rate_of_change);
+
+        let mut dropped = spo2_series(&values);
+        filter_artifacts(&mut dropped, &params(ArtifactMode::Drop));
+        assert_eq!(dropped[1].spo2, None);
+        assert_eq!(dropped[1].pulse, Some(61));
+        assert!(dropped[1].artifacts.rate_of_change);
+
+        let mut interpolated = spo2_series(&values);
+        filter_artifacts(&mut interpolated, &params(ArtifactMode::Interpolate));
+        assert_eq!(interpolated[1].spo2, Some(96));
+        assert!(interpolated[1].artifacts.rate_of_change && interpolated[1].artifacts.interpolated);
+        assert!(!interpolated[0].artifacts.interpolated && !interpolated[2].artifacts.interpolated);
+    }
+
+    #[test]
+    fn interpolation_over_run() {
+        let mut samples = spo2_series(&[Some(96), Some(40), Some(40), Some(40), Some(92)]);
+        filter_artifacts(&mut samples, &params(ArtifactMode::Interpolate));
+        let spo2: Vec<Option<u8>> = samples.iter().map(|s| s.spo2).collect();
+        assert_eq!(spo2, vec![Some(96), Some(95), Some(94), Some(93), Some(92)]);
+    }
+
+    #[test]
+    fn interpolation_at_edges() {
+        // runs without a neighbor on one side cannot be interpolated and become invalid
+        let mut samples = spo2_series(&[Some(40), Some(96), Some(97), Some(40)]);
+        filter_artifacts(&mut samples, &params(ArtifactMode::Interpolate));
+        let spo2: Vec<Option<u8>> = samples.iter().map(|s| s.spo2).collect();
+        assert_eq!(spo2, vec![None, Some(96), Some(97), None]);
+        assert!(samples[0].artifacts.out_of_range && !samples[0].artifacts.interpolated);
+        assert!(samples[3].artifacts.out_of_range && !samples[3].artifacts.interpolated);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use chrono::{DateTime, Duration, Local};
+
+use crate::analysis::{SAMPLE_PERIOD_SECONDS, valid_spo2_duration};
+use crate::clock::TimestampFormatter;
+use crate::output::csv_field;
+use crate::recording::Sample;
+
+
+/// Settings for the detection of desaturation events.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DesaturationParams {
+    /// By how many percentage points SpO2 must drop below the baseline (commonly 3 or 4).
+    pub drop: u8,
+
+    /// The period before each sample over which the baseline is averaged.
+    pub baseline_window: Duration,
+
+    /// How long SpO2 must stay below the threshold for the drop to count as an event.
+    pub min_duration: Duration,
+}
+
+
+/// A period during which SpO2 was at least the configured drop below the baseline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DesaturationEvent {
+    pub start: DateTime<Local>,
+
+    /// The end of the event (exclusive): when SpO2 recovered or, if the event was cut short by
+    /// invalid readings or the end of the recording, one sample period after the last low reading.
+    pub end: DateTime<Local>,
+
+    pub nadir_time: DateTime<Local>,
+    pub nadir_spo2: u8,
+
+    /// The baseline from which the drop was measured.
+    pub baseline_spo2: f64,
+}
+impl DesaturationEvent {
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+}
+
+
+/// Detects desaturation events in the given samples.
+///
+/// The baseline at each sample is the mean of the valid SpO2 values within the baseline window
+/// before it, not counting those that are part of an event. An event begins once SpO2 is at least
+/// the configured drop below the baseline and lasts (against the baseline at its beginning) until
+/// SpO2 recovers. Invalid readings are never counted as drops; they end an ongoing event, since
+/// there is no telling what happened in the meantime.
+pub fn detect_desaturations(samples: &[Sample], params: &DesaturationParams) -> Vec<DesaturationEvent> {
+    let sample_period = Duration::seconds(SAMPLE_PERIOD_SECONDS);
+    let drop = f64::from(params.drop);
+
+    let mut events = Vec::new();
+    let mut window: VecDeque<(DateTime<Local>, u8)> = VecDeque::new();
+    let mut window_sum: u32 = 0;
+    let mut current: Option<DesaturationEvent> = None;
+
+    let finish_event = |event: DesaturationEvent, events: &mut Vec<DesaturationEvent>| {
+        if event.duration() >= params.min_duration {
+            events.push(event);
+        }
+    };
+
+    for sample in samples {
+        let spo2 = match sample.spo2 {
+            Some(s) => s,
+            None => {
+                if let Some(event) = current.take() {
+                    finish_event(event, &mut events);
+                }
+                continue;
+            },
+        };
+
+        let window_start = sample.timestamp - params.baseline_window;
+        while let Some((timestamp, value)) = window.front() {
+            if *timestamp >= window_start {
+                break;
+            }
+            window_sum -= u32::from(*value);
+            window.pop_front();
+        }
+
+        match current.as_mut() {
+            Some(event) => {
+                if f64::from(spo2) <= event.baseline_spo2 - drop {
+                    // still desaturated
+                    event.end = sample.timestamp + sample_period;
+                    if spo2 < event.nadir_spo2 {
+                        event.nadir_spo2 = spo2;
+                        event.nadir_time = sample.timestamp;
+                    }
+                } else {
+                    // recovered
+                    let mut event = current.take().unwrap();
+                    event.end = sample.timestamp;
+                    finish_event(event, &mut events);
+                }
+            },
+            None => {
+                if !window.is_empty() {
+                    let baseline = f64::from(window_sum) / (window.len() as f64);
+                    if f64::from(spo2) <= baseline - drop {
+                        current = Some(DesaturationEvent {
+                            start: sample.timestamp,
+                            end: sample.timestamp + sample_period,
+                            nadir_time: sample.timestamp,
+                            nadir_spo2: spo2,
+                            baseline_spo2: baseline,
+                        });
+                    }
+                }
+            },
+        }
+
+        if current.is_none() {
+            window.push_back((sample.timestamp, spo2));
+            window_sum += u32::from(spo2);
+        }
+    }
+
+    if let Some(event) = current.take() {
+        finish_event(event, &mut events);
+    }
+
+    events
+}
+
+
+/// The oxygen desaturation index: the number of events per hour of valid SpO2 readings. Returns
+/// `None` if there are no valid readings.
+pub fn oxygen_desaturation_index(events: &[DesaturationEvent], samples: &[Sample]) -> Option<f64> {
+    let valid_seconds = valid_spo2_duration(samples).num_seconds();
+    if valid_seconds == 0 {
+        return None;
+    }
+    Some((events.len() as f64) * 3600.0 / (valid_seconds as f64))
+}
+
+
+/// Writes the given events as comma-separated values with a header row.
+pub fn write_events_csv<W: Write>(writer: &mut W, events: &[DesaturationEvent], formatter: &TimestampFormatter) -> io::Result<()> {
+    writeln!(writer, "start,end,duration_s,nadir_time,nadir_spo2,baseline_spo2")?;
+    for event in events {
+        writeln!(
+            writer, "{},{},{},{},{},{:.1}",
+            csv_field(&formatter.format(&event.start)),
+            csv_field(&formatter.format(&event.end)),
+            event.duration().num_seconds(),
+            csv_field(&formatter.format(&event.nadir_time)),
+            event.nadir_spo2,
+            event.baseline_spo2,
+        )?;
+    }
+    writer.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::recording::ArtifactFlags;
+
+    fn start() -> DateTime<Local> {
+        Local.ymd(2021, 3, 1).and_hms(22, 0, 0)
+    }
+
+    /// Builds a once-per-second series from runs of (count, SpO2).
+    fn series(runs: &[(usize, Option<u8>)]) -> Vec<Sample> {
+        runs.iter()
+            .flat_map(|(count, spo2)| std::iter::repeat_n(*spo2, *count))
+            .enumerate()
+            .map(|(i, spo2)| Sample {
+                timestamp: start() + Duration::seconds(i as i64),
+                pulse: spo2.map(|_| 60),
+                spo2,
+                artifacts: ArtifactFlags::default(),
+            })
+            .collect()
+    }
+
+    fn params(min_duration: i64) -> DesaturationParams {
+        DesaturationParams {
+            drop: 3,
+            baseline_window: Duration::seconds(60),
+            min_duration: Duration::seconds(min_duration),
+        }
+    }
+
+    #[test]
+    fn single_event() {
+        let samples = series(&[(30, Some(97)), (12, Some(93)), (1, Some(92)), (20, Some(97))]);
+        let events = detect_desaturations(&samples, &params(10));
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.start, start() + Duration::seconds(30));
+        assert_eq!(event.end, start() + Duration::seconds(43));
+        assert_eq!(event.duration(), Duration::seconds(13));
+        assert_eq!(event.nadir_spo2, 92);
+        assert_eq!(event.nadir_time, start() + Duration::seconds(42));
+        assert_eq!(event.baseline_spo2, 97.0);
+    }
+
+    #[test]
+    fn drop_too_small_or_too_short() {
+        let samples = series(&[(30, Some(97)), (20, Some(95)), (30, Some(97)), (9, Some(90)), (30, Some(97))]);
+        assert!(detect_desaturations(&samples, &params(10)).is_empty());
+    }
+
+    #[test]
+    fn invalid_gap_ends_event() {
+        let samples = series(&[(30, Some(97)), (8, Some(92)), (3, None), (8, Some(92)), (30, Some(97))]);
+
+        // together, the low readings would be long enough, but the gap splits them
+        assert!(detect_desaturations(&samples, &params(10)).is_empty());
+
+        let events = detect_desaturations(&samples, &params(5));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].start, start() + Duration::seconds(30));
+        assert_eq!(events[0].end, start() + Duration::seconds(38));
+        assert_eq!(events[1].start, start() + Duration::seconds(41));
+        assert_eq!(events[1].end, start() + Duration::seconds(49));
+    }
+
+    #[test]
+    fn event_at_end_of_recording() {
+        let samples = series(&[(30, Some(96)), (15, Some(91))]);
+        let events = detect_desaturations(&samples, &params(10));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].end, start() + Duration::seconds(45));
+    }
+
+    #[test]
+    fn events_do_not_lower_baseline() {
+        // the second drop is measured against the readings before the first one, not the first drop
+        let samples = series(&[(30, Some(97)), (15, Some(93)), (10, Some(97)), (15, Some(93)), (10, Some(97))]);
+        let events = detect_desaturations(&samples, &params(10));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].baseline_spo2, 97.0);
+    }
+
+    #[test]
+    fn odi() {
+        let samples = series(&[(1700, Some(97)), (100, None), (100, Some(97))]);
+        let events = detect_desaturations(&series(&[(30, Some(97)), (15, Some(93)), (30, Some(97)), (15, Some(93))]), &params(10));
+        assert_eq!(events.len(), 2);
+        assert_eq!(oxygen_desaturation_index(&events, &samples), Some(4.0));
+        assert_eq!(oxygen_desaturation_index(&events, &series(&[(10, None)])), None);
+    }
+}
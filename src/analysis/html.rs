@@ -0,0 +1,296 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Duration, Local, Timelike};
+
+use crate::analysis::{Analysis, format_hms, SAMPLE_PERIOD_SECONDS, SeriesStats};
+use crate::recording::Sample;
+
+
+const CHART_WIDTH: f64 = 960.0;
+const CHART_HEIGHT: f64 = 240.0;
+const MARGIN_LEFT: f64 = 50.0;
+const MARGIN_RIGHT: f64 = 10.0;
+const MARGIN_TOP: f64 = 10.0;
+const MARGIN_BOTTOM: f64 = 30.0;
+
+/// The candidate intervals between the time axis ticks, in seconds.
+const TIME_TICK_STEPS: [i64; 7] = [60, 300, 600, 900, 1800, 3600, 7200];
+
+/// The most ticks on the time axis.
+const MAX_TIME_TICKS: i64 = 12;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.4em; }
+h2 { font-size: 1.1em; margin-top: 1.5em; }
+table { border-collapse: collapse; }
+th, td { text-align: left; padding: 0.2em 1em 0.2em 0; border-bottom: 1px solid #ddd; }
+td.num { text-align: right; }
+svg { display: block; margin-bottom: 1em; }
+svg text { font-size: 11px; fill: #444; }
+.axis { stroke: #888; stroke-width: 1; }
+.grid { stroke: #eee; stroke-width: 1; }
+.spo2 { fill: none; stroke: #1f5fbf; stroke-width: 1; }
+.pulse { fill: none; stroke: #bf2f1f; stroke-width: 1; }
+.invalid { fill: #ccc; fill-opacity: 0.6; }
+.desaturation { fill: #f0a030; fill-opacity: 0.35; }
+.legend span { display: inline-block; width: 1em; height: 1em; vertical-align: middle; margin: 0 0.3em 0 1em; }
+";
+
+
+/// Escapes text for inclusion in HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+
+/// The mapping from time and values to chart coordinates.
+struct Scale {
+    start: DateTime<Local>,
+    duration_ms: f64,
+    y_min: f64,
+    y_max: f64,
+}
+impl Scale {
+    fn x(&self, timestamp: &DateTime<Local>) -> f64 {
+        let offset_ms = (*timestamp - self.start).num_milliseconds() as f64;
+        MARGIN_LEFT + (CHART_WIDTH - MARGIN_LEFT - MARGIN_RIGHT) * offset_ms / self.duration_ms
+    }
+
+    fn y(&self, value: f64) -> f64 {
+        let clamped = value.max(self.y_min).min(self.y_max);
+        CHART_HEIGHT - MARGIN_BOTTOM
+            - (CHART_HEIGHT - MARGIN_TOP - MARGIN_BOTTOM) * (clamped - self.y_min) / (self.y_max - self.y_min)
+    }
+}
+
+
+/// Returns the start and end of each period in which the given value is invalid.
+fn invalid_periods(samples: &[Sample], value_of: fn(&Sample) -> Option<u8>) -> Vec<(DateTime<Local>, DateTime<Local>)> {
+    let sample_period = Duration::seconds(SAMPLE_PERIOD_SECONDS);
+    let mut periods = Vec::new();
+    let mut period_start = None;
+    for sample in samples {
+        match (value_of(sample), period_start) {
+            (None, None) => period_start = Some(sample.timestamp),
+            (Some(_), Some(start)) => {
+                periods.push((start, sample.timestamp));
+                period_start = None;
+            },
+            _ => {},
+        }
+    }
+    if let (Some(start), Some(last)) = (period_start, samples.last()) {
+        periods.push((start, last.timestamp + sample_period));
+    }
+    periods
+}
+
+
+/// Renders a line chart of one series as SVG.
+fn render_chart(
+    samples: &[Sample],
+    scale: &Scale,
+    value_of: fn(&Sample) -> Option<u8>,
+    class: &str,
+    y_step: f64,
+    desaturations: &[(DateTime<Local>, DateTime<Local>)],
+) -> String {
+    let plot_top = MARGIN_TOP;
+    let plot_bottom = CHART_HEIGHT - MARGIN_BOTTOM;
+    let plot_height = plot_bottom - plot_top;
+
+    let mut svg = String::new();
+    write!(
+        svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = CHART_WIDTH, h = CHART_HEIGHT,
+    ).unwrap();
+
+    // shaded periods
+    for (start, end) in invalid_periods(samples, value_of) {
+        let x1 = scale.x(&start);
+        write!(
+            svg, r#"<rect class="invalid" x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}"/>"#,
+            x1, plot_top, (scale.x(&end) - x1).max(0.5), plot_height,
+        ).unwrap();
+    }
+    for (start, end) in desaturations {
+        let x1 = scale.x(start);
+        write!(
+            svg, r#"<rect class="desaturation" x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}"/>"#,
+            x1, plot_top, (scale.x(end) - x1).max(0.5), plot_height,
+        ).unwrap();
+    }
+
+    // value axis with grid lines
+    let mut tick = (scale.y_min / y_step).ceil() * y_step;
+    while tick <= scale.y_max {
+        let y = scale.y(tick);
+        write!(
+            svg, r#"<line class="grid" x1="{:.1}" y1="{y:.1}" x2="{:.1}" y2="{y:.1}"/><text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#,
+            MARGIN_LEFT, CHART_WIDTH - MARGIN_RIGHT, MARGIN_LEFT - 5.0, y + 4.0, tick, y = y,
+        ).unwrap();
+        tick += y_step;
+    }
+
+    // time axis
+    let duration_s = (scale.duration_ms / 1000.0) as i64;
+    let time_step = TIME_TICK_STEPS.iter()
+        .copied()
+        .find(|step| duration_s / step <= MAX_TIME_TICKS)
+        .unwrap_or(TIME_TICK_STEPS[TIME_TICK_STEPS.len() - 1]);
+    let seconds_of_day = i64::from(scale.start.num_seconds_from_midnight());
+    let mut tick_offset = (time_step - seconds_of_day % time_step) % time_step;
+    while tick_offset <= duration_s {
+        let timestamp = scale.start + Duration::seconds(tick_offset);
+        let x = scale.x(&timestamp);
+        write!(
+            svg, r#"<line class="axis" x1="{x:.1}" y1="{:.1}" x2="{x:.1}" y2="{:.1}"/><text x="{x:.1}" y="{:.1}" text-anchor="middle">{}</text>"#,
+            plot_bottom, plot_bottom + 4.0, plot_bottom + 16.0, timestamp.format("%H:%M"), x = x,
+        ).unwrap();
+        tick_offset += time_step;
+    }
+    write!(
+        svg, r#"<line class="axis" x1="{l:.1}" y1="{:.1}" x2="{l:.1}" y2="{b:.1}"/><line class="axis" x1="{l:.1}" y1="{b:.1}" x2="{:.1}" y2="{b:.1}"/>"#,
+        plot_top, CHART_WIDTH - MARGIN_RIGHT, l = MARGIN_LEFT, b = plot_bottom,
+    ).unwrap();
+
+    // the series itself, interrupted where it is invalid
+    let mut path = String::new();
+    let mut in_segment = false;
+    for sample in samples {
+        match value_of(sample) {
+            Some(value) => {
+                let command = if in_segment { 'L' } else { 'M' };
+                write!(path, "{}{:.1} {:.1}", command, scale.x(&sample.timestamp), scale.y(f64::from(value))).unwrap();
+                in_segment = true;
+            },
+            None => in_segment = false,
+        }
+    }
+    write!(svg, r#"<path class="{}" d="{}"/>"#, class, path).unwrap();
+
+    svg.push_str("</svg>");
+    svg
+}
+
+
+fn series_cells(stats: Option<&SeriesStats>, unit: &str) -> String {
+    match stats {
+        Some(s) => format!(
+            r#"<td class="num">{}{unit}</td><td class="num">{:.1}{unit}</td><td class="num">{}{unit}</td>"#,
+            s.min, s.mean, s.max, unit = unit,
+        ),
+        None => r#"<td colspan="3">no valid readings</td>"#.to_owned(),
+    }
+}
+
+fn duration_cell(analysis: &Analysis, duration: Duration) -> String {
+    match analysis.summary.percent_of_valid(duration) {
+        Some(percent) => format!(r#"<td class="num">{} ({:.1}%)</td>"#, format_hms(duration), percent),
+        None => format!(r#"<td class="num">{}</td>"#, format_hms(duration)),
+    }
+}
+
+
+/// Renders a self-contained HTML report (with the charts as inline SVG) for the given analysis and
+/// the samples it was calculated from.
+pub fn render_html_report(analysis: &Analysis, samples: &[Sample]) -> String {
+    let metadata = &analysis.metadata;
+    let summary = &analysis.summary;
+    let params = &analysis.desaturation_params;
+
+    let start = samples.first()
+        .map(|s| s.timestamp)
+        .unwrap_or(metadata.start_time);
+    let duration_ms = (summary.recording_duration.num_milliseconds() as f64).max(1000.0);
+
+    let spo2_min = summary.spo2.map(|s| f64::from(s.min)).unwrap_or(80.0);
+    let spo2_scale = Scale {
+        start,
+        duration_ms,
+        y_min: ((spo2_min.min(80.0) / 5.0).floor() * 5.0).max(0.0),
+        y_max: 100.0,
+    };
+    let (pulse_min, pulse_max) = summary.pulse
+        .map(|s| (f64::from(s.min), f64::from(s.max)))
+        .unwrap_or((40.0, 120.0));
+    let pulse_scale = Scale {
+        start,
+        duration_ms,
+        y_min: ((pulse_min / 10.0).floor() * 10.0 - 10.0).max(0.0),
+        y_max: (pulse_max / 10.0).ceil() * 10.0 + 10.0,
+    };
+    let pulse_step = if pulse_scale.y_max - pulse_scale.y_min > 100.0 { 20.0 } else { 10.0 };
+
+    let desaturations: Vec<(DateTime<Local>, DateTime<Local>)> = analysis.desaturations.iter()
+        .map(|e| (e.start, e.end))
+        .collect();
+
+    let title = format!("Oximetry report {}", metadata.start_time.format("%Y-%m-%d %H:%M"));
+
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>").unwrap();
+    writeln!(html, r#"<html><head><meta charset="utf-8"><title>{}</title><style>{}</style></head><body>"#, escape(&title), STYLE).unwrap();
+    writeln!(html, "<h1>{}</h1>", escape(&title)).unwrap();
+
+    writeln!(html, "<h2>Recording</h2><table>").unwrap();
+    writeln!(html, "<tr><th>Device ID</th><td>{}</td></tr>", escape(metadata.device_id.as_deref().unwrap_or("-"))).unwrap();
+    writeln!(html, "<tr><th>Start time</th><td>{}</td></tr>", metadata.start_time.format("%Y-%m-%d %H:%M:%S")).unwrap();
+    writeln!(html, "<tr><th>Mode</th><td>{}</td></tr>", metadata.mode.as_str()).unwrap();
+    writeln!(html, "<tr><th>Recording duration</th><td>{}</td></tr>", format_hms(summary.recording_duration)).unwrap();
+    writeln!(html, "<tr><th>Valid duration</th><td>{}</td></tr>", format_hms(summary.valid_duration)).unwrap();
+    writeln!(
+        html, "<tr><th>Invalid periods</th><td>{} ({} in total)</td></tr>",
+        summary.invalid_periods, format_hms(summary.invalid_duration),
+    ).unwrap();
+    writeln!(html, "<tr><th>Artifacts</th><td>{} samples</td></tr>", summary.artifact_count).unwrap();
+    writeln!(html, "</table>").unwrap();
+
+    writeln!(html, "<h2>SpO2 (%)</h2>").unwrap();
+    writeln!(html, "{}", render_chart(samples, &spo2_scale, |s| s.spo2, "spo2", 5.0, &desaturations)).unwrap();
+    writeln!(html, "<h2>Pulse (bpm)</h2>").unwrap();
+    writeln!(html, "{}", render_chart(samples, &pulse_scale, |s| s.pulse, "pulse", pulse_step, &[])).unwrap();
+    writeln!(
+        html, r#"<p class="legend"><span style="background: #f0a030; opacity: 0.5"></span>desaturation <span style="background: #ccc"></span>invalid</p>"#,
+    ).unwrap();
+
+    writeln!(html, "<h2>Summary</h2><table>").unwrap();
+    writeln!(html, r#"<tr><th></th><th>min</th><th>mean</th><th>max</th></tr>"#).unwrap();
+    writeln!(html, "<tr><th>SpO2</th>{}</tr>", series_cells(summary.spo2.as_ref(), "%")).unwrap();
+    writeln!(html, "<tr><th>Pulse</th>{}</tr>", series_cells(summary.pulse.as_ref(), " bpm")).unwrap();
+    writeln!(html, "</table><table>").unwrap();
+    writeln!(html, "<tr><th>T90</th>{}</tr>", duration_cell(analysis, summary.t90)).unwrap();
+    writeln!(html, "<tr><th>T88</th>{}</tr>", duration_cell(analysis, summary.t88)).unwrap();
+    for band in &summary.spo2_bands {
+        writeln!(html, "<tr><th>SpO2 {}-{}%</th>{}</tr>", band.lower, band.upper, duration_cell(analysis, band.duration)).unwrap();
+    }
+    writeln!(
+        html, r#"<tr><th>Desaturations (&ge;{}% for &ge;{} s)</th><td class="num">{}</td></tr>"#,
+        params.drop, params.min_duration.num_seconds(), analysis.desaturations.len(),
+    ).unwrap();
+    match analysis.odi {
+        Some(odi) => writeln!(html, r#"<tr><th>ODI</th><td class="num">{:.1}/h</td></tr>"#, odi).unwrap(),
+        None => writeln!(html, r#"<tr><th>ODI</th><td class="num">-</td></tr>"#).unwrap(),
+    }
+    writeln!(html, "</table>").unwrap();
+
+    if !analysis.desaturations.is_empty() {
+        writeln!(html, "<h2>Desaturation events</h2><table>").unwrap();
+        writeln!(html, "<tr><th>start</th><th>duration</th><th>nadir</th><th>baseline</th></tr>").unwrap();
+        for event in &analysis.desaturations {
+            writeln!(
+                html, r#"<tr><td>{}</td><td class="num">{} s</td><td class="num">{}% at {}</td><td class="num">{:.1}%</td></tr>"#,
+                event.start.format("%H:%M:%S"), event.duration().num_seconds(),
+                event.nadir_spo2, event.nadir_time.format("%H:%M:%S"), event.baseline_spo2,
+            ).unwrap();
+        }
+        writeln!(html, "</table>").unwrap();
+    }
+
+    writeln!(html, "</body></html>").unwrap();
+    html
+}
@@ -0,0 +1,68 @@
+mod artifacts;
+mod desat;
+mod html;
+mod report;
+mod stats;
+
+
+use chrono::Duration;
+
+use crate::recording::{Recording, RecordingMetadata, Sample};
+
+pub use self::artifacts::{ArtifactParams, filter_artifacts};
+pub use self::desat::{
+    DesaturationEvent, DesaturationParams, detect_desaturations, oxygen_desaturation_index,
+    write_events_csv,
+};
+pub use self::html::render_html_report;
+pub use self::report::{report_json, write_text_report};
+pub use self::stats::{SeriesStats, summarize, SummaryStats};
+
+
+/// The interval between two samples in stored recordings and (nominally) in live mode, in seconds.
+pub const SAMPLE_PERIOD_SECONDS: i64 = 1;
+
+
+/// The results of analyzing a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Analysis {
+    pub metadata: RecordingMetadata,
+    pub summary: SummaryStats,
+    pub desaturation_params: DesaturationParams,
+    pub desaturations: Vec<DesaturationEvent>,
+
+    /// The oxygen desaturation index, if there are valid SpO2 readings.
+    pub odi: Option<f64>,
+}
+impl Analysis {
+    pub fn of(recording: &Recording, desaturation_params: DesaturationParams) -> Self {
+        let desaturations = detect_desaturations(&recording.samples, &desaturation_params);
+        let odi = oxygen_desaturation_index(&desaturations, &recording.samples);
+        Self {
+            metadata: recording.metadata.clone(),
+            summary: summarize(&recording.samples),
+            desaturation_params,
+            desaturations,
+            odi,
+        }
+    }
+}
+
+
+/// The total duration covered by samples with a valid SpO2 value.
+pub fn valid_spo2_duration(samples: &[Sample]) -> Duration {
+    let valid_count = samples.iter()
+        .filter(|s| s.spo2.is_some())
+        .count();
+    Duration::seconds(valid_count as i64 * SAMPLE_PERIOD_SECONDS)
+}
+
+
+/// Formats a duration as hours, minutes and seconds (`H:MM:SS`).
+pub fn format_hms(duration: Duration) -> String {
+    let total_seconds = duration.num_seconds();
+    format!(
+        "{}:{:02}:{:02}",
+        total_seconds / 3600, (total_seconds / 60) % 60, total_seconds % 60,
+    )
+}
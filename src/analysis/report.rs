@@ -0,0 +1,134 @@
+use std::io::{self, Write};
+
+use chrono::Duration;
+use serde_json::{json, Value};
+
+use crate::analysis::{Analysis, format_hms, SeriesStats};
+use crate::clock::TimestampFormatter;
+
+
+fn series_text(stats: Option<&SeriesStats>, unit: &str) -> String {
+    match stats {
+        Some(s) => format!("min {}{unit}, mean {:.1}{unit}, max {}{unit}", s.min, s.mean, s.max, unit = unit),
+        None => "no valid readings".to_owned(),
+    }
+}
+
+fn series_json(stats: Option<&SeriesStats>) -> Value {
+    match stats {
+        Some(s) => json!({
+            "min": s.min,
+            "mean": s.mean,
+            "max": s.max,
+        }),
+        None => Value::Null,
+    }
+}
+
+fn duration_text(analysis: &Analysis, duration: Duration) -> String {
+    match analysis.summary.percent_of_valid(duration) {
+        Some(percent) => format!("{} ({:.1}%)", format_hms(duration), percent),
+        None => format_hms(duration),
+    }
+}
+
+
+/// Writes the results of an analysis as a human-readable text report.
+pub fn write_text_report<W: Write>(writer: &mut W, analysis: &Analysis, formatter: &TimestampFormatter) -> io::Result<()> {
+    let summary = &analysis.summary;
+    let params = &analysis.desaturation_params;
+
+    writeln!(writer, "device ID:          {}", analysis.metadata.device_id.as_deref().unwrap_or("-"))?;
+    writeln!(writer, "start time:         {}", analysis.metadata.start_time.format("%Y-%m-%d %H:%M:%S"))?;
+    writeln!(writer, "mode:               {}", analysis.metadata.mode.as_str())?;
+    writeln!(writer, "recording duration: {}", format_hms(summary.recording_duration))?;
+    writeln!(writer, "valid duration:     {}", format_hms(summary.valid_duration))?;
+    writeln!(
+        writer, "invalid periods:    {} ({} in total)",
+        summary.invalid_periods, format_hms(summary.invalid_duration),
+    )?;
+    writeln!(writer, "artifacts:          {} samples", summary.artifact_count)?;
+    writeln!(writer, "SpO2:               {}", series_text(summary.spo2.as_ref(), "%"))?;
+    writeln!(writer, "pulse:              {}", series_text(summary.pulse.as_ref(), " bpm"))?;
+    writeln!(writer, "T90:                {}", duration_text(analysis, summary.t90))?;
+    writeln!(writer, "T88:                {}", duration_text(analysis, summary.t88))?;
+    writeln!(writer, "time in SpO2 bands:")?;
+    for band in &summary.spo2_bands {
+        let label = format!("{}-{}%", band.lower, band.upper);
+        writeln!(writer, "  {:<18}{}", label, duration_text(analysis, band.duration))?;
+    }
+    writeln!(
+        writer, "desaturations:      {} (drop of at least {}% for at least {} s)",
+        analysis.desaturations.len(), params.drop, params.min_duration.num_seconds(),
+    )?;
+    match analysis.odi {
+        Some(odi) => writeln!(writer, "ODI:                {:.1}/h", odi)?,
+        None => writeln!(writer, "ODI:                -")?,
+    }
+    for event in &analysis.desaturations {
+        writeln!(
+            writer, "  {}  {:>4} s  nadir {}% at {} (baseline {:.1}%)",
+            formatter.format(&event.start), event.duration().num_seconds(),
+            event.nadir_spo2, formatter.format(&event.nadir_time), event.baseline_spo2,
+        )?;
+    }
+    writer.flush()
+}
+
+
+/// Represents the results of an analysis as JSON. Durations are in seconds.
+pub fn report_json(analysis: &Analysis, formatter: &TimestampFormatter) -> Value {
+    let summary = &analysis.summary;
+    let params = &analysis.desaturation_params;
+
+    let bands: Vec<Value> = summary.spo2_bands.iter()
+        .map(|band| json!({
+            "lower": band.lower,
+            "upper": band.upper,
+            "duration_s": band.duration.num_seconds(),
+            "percent": summary.percent_of_valid(band.duration),
+        }))
+        .collect();
+    let events: Vec<Value> = analysis.desaturations.iter()
+        .map(|event| json!({
+            "start": formatter.format(&event.start),
+            "end": formatter.format(&event.end),
+            "duration_s": event.duration().num_seconds(),
+            "nadir_time": formatter.format(&event.nadir_time),
+            "nadir_spo2": event.nadir_spo2,
+            "baseline_spo2": event.baseline_spo2,
+        }))
+        .collect();
+
+    json!({
+        "device_id": analysis.metadata.device_id,
+        "start_time": analysis.metadata.start_time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        "mode": analysis.metadata.mode.as_str(),
+        "recording_duration_s": summary.recording_duration.num_seconds(),
+        "valid_duration_s": summary.valid_duration.num_seconds(),
+        "invalid_periods": {
+            "count": summary.invalid_periods,
+            "duration_s": summary.invalid_duration.num_seconds(),
+        },
+        "artifact_count": summary.artifact_count,
+        "spo2": series_json(summary.spo2.as_ref()),
+        "pulse": series_json(summary.pulse.as_ref()),
+        "t90": {
+            "duration_s": summary.t90.num_seconds(),
+            "percent": summary.percent_of_valid(summary.t90),
+        },
+        "t88": {
+            "duration_s": summary.t88.num_seconds(),
+            "percent": summary.percent_of_valid(summary.t88),
+        },
+        "spo2_bands": bands,
+        "desaturations": {
+            "drop": params.drop,
+            "baseline_window_s": params.baseline_window.num_seconds(),
+            "min_duration_s": params.min_duration.num_seconds(),
+            "count": analysis.desaturations.len(),
+            "odi": analysis.odi,
+            "events": events,
+        },
+    })
+}
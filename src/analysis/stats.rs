@@ -0,0 +1,197 @@
+use chrono::Duration;
+
+use crate::analysis::{SAMPLE_PERIOD_SECONDS, valid_spo2_duration};
+use crate::recording::Sample;
+
+
+/// The SpO2 bands (inclusive lower and upper bound) for which the time spent in them is reported.
+pub const SPO2_BANDS: [(u8, u8); 5] = [(95, 100), (90, 94), (85, 89), (80, 84), (0, 79)];
+
+
+/// Minimum, mean and maximum of a series of values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SeriesStats {
+    pub min: u8,
+    pub mean: f64,
+    pub max: u8,
+}
+impl SeriesStats {
+    /// Calculates the statistics over the given values. Returns `None` if there are none.
+    pub fn from_values<I: Iterator<Item = u8>>(values: I) -> Option<Self> {
+        let mut count: u64 = 0;
+        let mut sum: u64 = 0;
+        let mut min = u8::MAX;
+        let mut max = u8::MIN;
+        for value in values {
+            count += 1;
+            sum += u64::from(value);
+            min = min.min(value);
+            max = max.max(value);
+        }
+        if count == 0 {
+            return None;
+        }
+        Some(Self {
+            min,
+            mean: (sum as f64) / (count as f64),
+            max,
+        })
+    }
+}
+
+
+/// The time spent within an SpO2 band.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct BandDuration {
+    pub lower: u8,
+    pub upper: u8,
+    pub duration: Duration,
+}
+
+
+/// The standard oximetry summary metrics of a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SummaryStats {
+    /// From the first to the end of the last sample.
+    pub recording_duration: Duration,
+
+    /// The time covered by valid SpO2 readings.
+    pub valid_duration: Duration,
+
+    pub spo2: Option<SeriesStats>,
+    pub pulse: Option<SeriesStats>,
+
+    /// The time spent below 90% SpO2.
+    pub t90: Duration,
+
+    /// The time spent below 88% SpO2.
+    pub t88: Duration,
+
+    /// The time spent in each of the `SPO2_BANDS`.
+    pub spo2_bands: Vec<BandDuration>,
+
+    /// The number of periods in which SpO2 or pulse were invalid (e.g. finger out).
+    pub invalid_periods: usize,
+    pub invalid_duration: Duration,
+
+    /// The number of samples flagged by the artifact filter.
+    pub artifact_count: usize,
+}
+impl SummaryStats {
+    /// The given duration as a percentage of the valid duration, or `None` if there are no valid
+    /// readings.
+    pub fn percent_of_valid(&self, duration: Duration) -> Option<f64> {
+        let valid_seconds = self.valid_duration.num_seconds();
+        if valid_seconds == 0 {
+            None
+        } else {
+            Some(100.0 * (duration.num_seconds() as f64) / (valid_seconds as f64))
+        }
+    }
+}
+
+
+/// Calculates the summary metrics of the given samples.
+pub fn summarize(samples: &[Sample]) -> SummaryStats {
+    let sample_period = Duration::seconds(SAMPLE_PERIOD_SECONDS);
+    let time_where = |predicate: &dyn Fn(u8) -> bool| {
+        let count = samples.iter()
+            .filter_map(|s| s.spo2)
+            .filter(|s| predicate(*s))
+            .count();
+        sample_period * (count as i32)
+    };
+
+    let recording_duration = match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) => last.timestamp - first.timestamp + sample_period,
+        _ => Duration::zero(),
+    };
+
+    let spo2_bands = SPO2_BANDS.iter()
+        .map(|(lower, upper)| BandDuration {
+            lower: *lower,
+            upper: *upper,
+            duration: time_where(&|s| s >= *lower && s <= *upper),
+        })
+        .collect();
+
+    let mut invalid_periods = 0;
+    let mut invalid_count: i32 = 0;
+    let mut in_invalid_period = false;
+    for sample in samples {
+        let invalid = sample.spo2.is_none() || sample.pulse.is_none();
+        if invalid {
+            invalid_count += 1;
+            if !in_invalid_period {
+                invalid_periods += 1;
+            }
+        }
+        in_invalid_period = invalid;
+    }
+
+    SummaryStats {
+        recording_duration,
+        valid_duration: valid_spo2_duration(samples),
+        spo2: SeriesStats::from_values(samples.iter().filter_map(|s| s.spo2)),
+        pulse: SeriesStats::from_values(samples.iter().filter_map(|s| s.pulse)),
+        t90: time_where(&|s| s < 90),
+        t88: time_where(&|s| s < 88),
+        spo2_bands,
+        invalid_periods,
+        invalid_duration: sample_period * invalid_count,
+        artifact_count: samples.iter().filter(|s| s.artifacts.is_artifact()).count(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use crate::recording::ArtifactFlags;
+
+    fn series(values: &[(Option<u8>, Option<u8>)]) -> Vec<Sample> {
+        let start = Local.ymd(2021, 3, 1).and_hms(22, 0, 0);
+        values.iter().enumerate()
+            .map(|(i, (spo2, pulse))| Sample {
+                timestamp: start + Duration::seconds(i as i64),
+                pulse: *pulse,
+                spo2: *spo2,
+                artifacts: ArtifactFlags::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn summary() {
+        let samples = series(&[
+            (Some(97), Some(60)), (Some(91), Some(62)), (Some(89), Some(64)), (None, None),
+            (None, Some(66)), (Some(87), Some(70)), (Some(80), None), (Some(96), Some(58)),
+        ]);
+        let stats = summarize(&samples);
+
+        assert_eq!(stats.recording_duration, Duration::seconds(8));
+        assert_eq!(stats.valid_duration, Duration::seconds(6));
+        assert_eq!(stats.spo2, Some(SeriesStats { min: 80, mean: 90.0, max: 97 }));
+        assert_eq!(stats.pulse.map(|p| (p.min, p.max)), Some((58, 70)));
+        assert_eq!(stats.t90, Duration::seconds(3));
+        assert_eq!(stats.t88, Duration::seconds(2));
+        assert_eq!(stats.percent_of_valid(stats.t90), Some(50.0));
+
+        let bands: Vec<i64> = stats.spo2_bands.iter().map(|b| b.duration.num_seconds()).collect();
+        assert_eq!(bands, vec![2, 1, 2, 1, 0]);
+
+        // (None, None) and (None, 66) form one period, (80, None) another
+        assert_eq!(stats.invalid_periods, 2);
+        assert_eq!(stats.invalid_duration, Duration::seconds(3));
+    }
+
+    #[test]
+    fn no_valid_readings() {
+        let stats = summarize(&series(&[(None, None), (None, None)]));
+        assert_eq!(stats.spo2, None);
+        assert_eq!(stats.t90, Duration::zero());
+        assert_eq!(stats.percent_of_valid(stats.t90), None);
+        assert_eq!(stats.invalid_periods, 1);
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Local, NaiveDateTime};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::files::FileHeader;
+use crate::recording::{
+    ArtifactFlags, local_from_naive, Recording, RecordingMetadata, Sample, SourceMode,
+};
+
+
+/// The format in which start times (which come from the oximeter's clock and are therefore in
+/// local time) are stored in the database.
+const START_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS recordings
+    ( id INTEGER NOT NULL PRIMARY KEY
+    , device_id TEXT NOT NULL
+    , start_time TEXT NOT NULL
+    , length INTEGER NOT NULL
+    , mode TEXT NOT NULL
+    , header BLOB NOT NULL
+    , downloaded_at TEXT NOT NULL
+    , UNIQUE (device_id, start_time, length)
+    );
+    CREATE TABLE IF NOT EXISTS samples
+    ( recording_id INTEGER NOT NULL REFERENCES recordings (id) ON DELETE CASCADE
+    , offset INTEGER NOT NULL
+    , pulse INTEGER NULL
+    , spo2 INTEGER NULL
+    , PRIMARY KEY (recording_id, offset)
+    );
+";
+
+
+/// Information about a recording stored in the archive.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ArchivedRecording {
+    pub id: i64,
+    pub device_id: String,
+    pub start_time: DateTime<Local>,
+    pub length: usize,
+    pub mode: SourceMode,
+    pub downloaded_at: DateTime<Local>,
+}
+
+
+/// A SQLite database collecting the recordings downloaded from the oximeter.
+///
+/// A recording is identified by the ID of the device it was downloaded from as well as the start
+/// time and length from its file header; the same recording is only stored once, however often it
+/// is downloaded. The samples are stored one row per second, with invalid values as `NULL`, so
+/// that they can be queried directly.
+pub struct Archive {
+    connection: Connection,
+}
+impl Archive {
+    /// Opens the archive at the given path, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection,
+        })
+    }
+
+    /// Whether the recording with the given header, downloaded from the given device, is already
+    /// stored in the archive.
+    pub fn contains(&self, device_id: &str, header: &FileHeader) -> rusqlite::Result<bool> {
+        let id: Option<i64> = self.connection.query_row(
+            "SELECT id FROM recordings WHERE device_id = ?1 AND start_time = ?2 AND length = ?3",
+            params![
+                device_id,
+                header.start_time.format(START_TIME_FORMAT).to_string(),
+                header.length as i64,
+            ],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(id.is_some())
+    }
+
+    /// Stores a recording downloaded from the oximeter along with its file header. Returns the ID
+    /// of the new entry.
+    pub fn insert(&mut self, recording: &Recording, header: &FileHeader, downloaded_at: DateTime<Local>) -> rusqlite::Result<i64> {
+        let device_id = recording.metadata.device_id.as_deref().unwrap_or("");
+
+        let txn = self.connection.transaction()?;
+        txn.execute(
+            "INSERT INTO recordings (device_id, start_time, length, mode, header, downloaded_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                device_id,
+                header.start_time.format(START_TIME_FORMAT).to_string(),
+                header.length as i64,
+                recording.metadata.mode.as_str(),
+                header.raw,
+                downloaded_at.to_rfc3339(),
+            ],
+        )?;
+        let recording_id = txn.last_insert_rowid();
+
+        {
+            let mut statement = txn.prepare(
+                "INSERT INTO samples (recording_id, offset, pulse, spo2) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for sample in &recording.samples {
+                let offset = (sample.timestamp - recording.metadata.start_time).num_seconds();
+                statement.execute(params![recording_id, offset, sample.pulse, sample.spo2])?;
+            }
+        }
+
+        txn.commit()?;
+        Ok(recording_id)
+    }
+
+    /// Lists the recordings stored in the archive, oldest first.
+    pub fn list(&self) -> rusqlite::Result<Vec<ArchivedRecording>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, device_id, start_time, length, mode, downloaded_at FROM recordings ORDER BY start_time, id",
+        )?;
+        let rows = statement.query_map([], |row| {
+            let start_time: String = row.get(2)?;
+            let length: i64 = row.get(3)?;
+            let mode: String = row.get(4)?;
+            let downloaded_at: String = row.get(5)?;
+            Ok(ArchivedRecording {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                start_time: parse_start_time(&start_time),
+                length: length as usize,
+                mode: SourceMode::from_name(&mode)
+                    .expect("unknown recording mode in archive"),
+                downloaded_at: DateTime::parse_from_rfc3339(&downloaded_at)
+                    .expect("invalid download time in archive")
+                    .with_timezone(&Local),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Loads the recording with the given ID from the archive. Returns `None` if there is no such
+    /// recording.
+    pub fn load(&self, recording_id: i64) -> rusqlite::Result<Option<Recording>> {
+        let entry = self.connection.query_row(
+            "SELECT device_id, start_time, mode FROM recordings WHERE id = ?1",
+            params![recording_id],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)),
+        ).optional()?;
+        let (device_id, start_time, mode) = match entry {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+
+        let metadata = RecordingMetadata {
+            device_id: Some(device_id).filter(|d| !d.is_empty()),
+            start_time: parse_start_time(&start_time),
+            mode: SourceMode::from_name(&mode)
+                .expect("unknown recording mode in archive"),
+        };
+
+        let mut statement = self.connection.prepare(
+            "SELECT offset, pulse, spo2 FROM samples WHERE recording_id = ?1 ORDER BY offset",
+        )?;
+        let samples = statement.query_map(params![recording_id], |row| {
+            let offset: i64 = row.get(0)?;
+            Ok(Sample {
+                timestamp: metadata.start_time + Duration::seconds(offset),
+                pulse: row.get(1)?,
+                spo2: row.get(2)?,
+                artifacts: ArtifactFlags::default(),
+            })
+        })?.collect::<rusqlite::Result<Vec<Sample>>>()?;
+
+        Ok(Some(Recording {
+            metadata,
+            samples,
+        }))
+    }
+}
+
+
+fn parse_start_time(start_time: &str) -> DateTime<Local> {
+    let naive = NaiveDateTime::parse_from_str(start_time, START_TIME_FORMAT)
+        .expect("invalid start time in archive");
+    local_from_naive(&naive)
+}
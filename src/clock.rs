@@ -0,0 +1,150 @@
+use chrono::{DateTime, Duration, Local, SecondsFormat, Utc};
+
+use crate::opts::TimestampMode;
+
+
+/// By which fraction of the deviation from the host clock the sample clock is corrected with each
+/// batch. Larger values give smoother timestamps but follow the host clock more slowly.
+const DRIFT_CORRECTION_DIVISOR: i32 = 16;
+
+
+/// Assigns timestamps to samples that the oximeter delivers at a fixed cadence.
+///
+/// The host only knows when a USB report has been read, which is neither regular (reads are
+/// bursty and a report often contains multiple samples) nor, when live data is streamed without
+/// the pulse curve, anywhere near the time of measurement (the oximeter then queues up its values
+/// and transfers them every few seconds). Instead, the clock anchors on the first batch of samples
+/// and advances by the nominal sample period for each sample. With every batch, it is nudged
+/// towards the host clock by a fraction of the deviation to compensate for drift between the two
+/// clocks. If the deviation is larger than the tolerance (e.g. because samples were dropped), the
+/// clock resynchronizes with the host clock.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SampleClock {
+    period: Duration,
+    tolerance: Duration,
+    next: Option<DateTime<Local>>,
+}
+impl SampleClock {
+    /// Creates a new clock for samples arriving every `period`, resynchronizing with the host
+    /// clock if it deviates by more than `tolerance`.
+    pub fn new(period: Duration, tolerance: Duration) -> Self {
+        Self {
+            period,
+            tolerance,
+            next: None,
+        }
+    }
+
+    /// Returns the timestamps for a batch of `count` samples that has arrived at `arrival`. The
+    /// last sample of the batch is assumed to have been measured just before its arrival.
+    pub fn stamp_batch(&mut self, arrival: DateTime<Local>, count: usize) -> Vec<DateTime<Local>> {
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let batch_length = self.period * ((count - 1) as i32);
+        let ideal_start = arrival - batch_length;
+        let start = match self.next {
+            Some(next) => {
+                let deviation = ideal_start - next;
+                if deviation.num_milliseconds().abs() <= self.tolerance.num_milliseconds() {
+                    next + deviation / DRIFT_CORRECTION_DIVISOR
+                } else {
+                    ideal_start
+                }
+            },
+            None => ideal_start,
+        };
+
+        let timestamps: Vec<DateTime<Local>> = (0..count)
+            .map(|i| start + self.period * (i as i32))
+            .collect();
+        self.next = Some(start + self.period * (count as i32));
+        timestamps
+    }
+}
+
+
+/// Formats timestamps according to the selected `TimestampMode`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TimestampFormatter {
+    mode: TimestampMode,
+    origin: DateTime<Local>,
+    millis: bool,
+}
+impl TimestampFormatter {
+    /// Creates a new formatter. Monotonic timestamps are relative to `origin`. If `millis` is set,
+    /// timestamps are output with millisecond precision, otherwise with second precision.
+    pub fn new(mode: TimestampMode, origin: DateTime<Local>, millis: bool) -> Self {
+        Self {
+            mode,
+            origin,
+            millis,
+        }
+    }
+
+    /// Whether the formatted timestamps are plain numbers (seconds since the origin).
+    pub fn is_numeric(&self) -> bool {
+        self.mode == TimestampMode::Monotonic
+    }
+
+    /// Formats the given timestamp.
+    pub fn format(&self, timestamp: &DateTime<Local>) -> String {
+        match (self.mode, self.millis) {
+            (TimestampMode::Local, true) => timestamp.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            (TimestampMode::Local, false) => timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+            (TimestampMode::Utc, true) => timestamp.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Millis, true),
+            (TimestampMode::Utc, false) => timestamp.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true),
+            (TimestampMode::Monotonic, true) => {
+                // the first live samples may be stamped slightly before the origin
+                let elapsed_ms = (*timestamp - self.origin).num_milliseconds();
+                let sign = if elapsed_ms < 0 { "-" } else { "" };
+                format!("{}{}.{:03}", sign, elapsed_ms.abs() / 1000, elapsed_ms.abs() % 1000)
+            },
+            (TimestampMode::Monotonic, false) => (*timestamp - self.origin).num_seconds().to_string(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(ms: i64) -> DateTime<Local> {
+        Local.ymd(2021, 3, 1).and_hms(22, 0, 0) + Duration::milliseconds(ms)
+    }
+
+    #[test]
+    fn sample_clock_follows_host_clock_slowly() {
+        let mut clock = SampleClock::new(Duration::seconds(1), Duration::seconds(2));
+        assert!(clock.stamp_batch(at(10_000), 0).is_empty());
+
+        // the first batch ends at its arrival
+        assert_eq!(clock.stamp_batch(at(10_000), 3), vec![at(8_000), at(9_000), at(10_000)]);
+
+        // a batch arriving 160 ms late is only nudged by a sixteenth of that
+        assert_eq!(clock.stamp_batch(at(13_160), 3), vec![at(11_010), at(12_010), at(13_010)]);
+
+        // a batch arriving much too late (e.g. after dropped samples) resynchronizes the clock
+        assert_eq!(clock.stamp_batch(at(30_000), 2), vec![at(29_000), at(30_000)]);
+    }
+
+    #[test]
+    fn formatting() {
+        let origin = at(0);
+        let timestamp = at(61_250);
+        assert_eq!(TimestampFormatter::new(TimestampMode::Local, origin, true).format(&timestamp), "2021-03-01 22:01:01.250");
+        assert_eq!(TimestampFormatter::new(TimestampMode::Local, origin, false).format(&timestamp), "2021-03-01 22:01:01");
+        assert_eq!(TimestampFormatter::new(TimestampMode::Monotonic, origin, true).format(&timestamp), "61.250");
+        assert_eq!(TimestampFormatter::new(TimestampMode::Monotonic, origin, false).format(&timestamp), "61");
+    }
+
+    #[test]
+    fn monotonic_before_origin() {
+        let formatter = TimestampFormatter::new(TimestampMode::Monotonic, at(0), true);
+        assert_eq!(formatter.format(&at(-500)), "-0.500");
+        assert_eq!(formatter.format(&at(-1_250)), "-1.250");
+    }
+}
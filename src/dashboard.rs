@@ -0,0 +1,543 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{DateTime, Local};
+use ratatui::{DefaultTerminal, Frame};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Axis, Block, Chart, Dataset, GraphType, Paragraph, Sparkline};
+
+use crate::clock::TimestampFormatter;
+use crate::live::LinkStats;
+use crate::opts::{OutputFormat, TimestampMode};
+use crate::output::{create_sink, file_extension, OutputSink};
+use crate::recording::{RecordingMetadata, Sample, SourceMode, WaveformPoint};
+
+
+/// The minimum interval between two redraws of the dashboard, in milliseconds.
+const REDRAW_INTERVAL_MS: u64 = 100;
+
+/// How many pulse curve points are shown (ten seconds' worth).
+const WAVEFORM_POINTS: usize = 200;
+
+/// How many samples are kept for the trend sparklines (ten minutes' worth).
+const TREND_SAMPLES: usize = 600;
+
+/// The SpO2 value shown as the bottom of the SpO2 trend sparkline; lower values are clipped.
+const SPO2_TREND_FLOOR: u8 = 70;
+
+/// How long after the last sample the data is considered stale, in milliseconds.
+const STALE_AFTER_MS: u64 = 3000;
+
+/// How long the beat indicator stays lit after a beat, in milliseconds.
+const BEAT_INDICATOR_MS: u64 = 200;
+
+/// The glyphs for the large digits, five rows of three cells each.
+const BIG_DIGITS: [[&str; 5]; 10] = [
+    ["███", "█ █", "█ █", "█ █", "███"],
+    ["  █", "  █", "  █", "  █", "  █"],
+    ["███", "  █", "███", "█  ", "███"],
+    ["███", "  █", "███", "  █", "███"],
+    ["█ █", "█ █", "███", "  █", "  █"],
+    ["███", "█  ", "███", "  █", "███"],
+    ["███", "█  ", "███", "█ █", "███"],
+    ["███", "  █", "  █", "  █", "  █"],
+    ["███", "█ █", "███", "█ █", "███"],
+    ["███", "█ █", "███", "  █", "███"],
+];
+
+/// The glyph for a missing value in large digits.
+const BIG_DASH: [&str; 5] = ["   ", "   ", "███", "   ", "   "];
+
+
+/// A file into which the live samples are currently being written.
+struct ActiveRecording {
+    path: PathBuf,
+    started: DateTime<Local>,
+    sample_count: u64,
+    sink: Box<dyn OutputSink>,
+}
+
+
+/// What the dashboard knows about the live session, fed by a `DashboardSink`.
+struct DashboardState {
+    device_id: Option<String>,
+    latest: Option<Sample>,
+    last_sample_received: Option<Instant>,
+    last_beat: Option<Instant>,
+    waveform: VecDeque<u8>,
+    spo2_trend: VecDeque<Option<u8>>,
+    pulse_trend: VecDeque<Option<u8>>,
+    recording: Option<ActiveRecording>,
+
+    /// A message for the status panel from the sink, which has no access to the dashboard.
+    pending_notice: Option<String>,
+}
+impl DashboardState {
+    fn new() -> Self {
+        Self {
+            device_id: None,
+            latest: None,
+            last_sample_received: None,
+            last_beat: None,
+            waveform: VecDeque::with_capacity(WAVEFORM_POINTS),
+            spo2_trend: VecDeque::with_capacity(TREND_SAMPLES),
+            pulse_trend: VecDeque::with_capacity(TREND_SAMPLES),
+            recording: None,
+            pending_notice: None,
+        }
+    }
+
+    /// Whether no sample has been received recently.
+    fn is_stale(&self) -> bool {
+        self.last_sample_received
+            .map(|t| t.elapsed() >= StdDuration::from_millis(STALE_AFTER_MS))
+            .unwrap_or(true)
+    }
+
+    /// Finishes the current recording, if any. Returns the path of the finished file.
+    fn stop_recording(&mut self) -> io::Result<Option<PathBuf>> {
+        match self.recording.take() {
+            Some(mut recording) => {
+                recording.sink.finish()?;
+                Ok(Some(recording.path))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Stops the current recording after writing to it has failed, finishing the file as far as
+    /// possible, and leaves a message for the status panel. The live session itself goes on.
+    fn abandon_recording(&mut self, error: io::Error) {
+        let mut recording = match self.recording.take() {
+            Some(r) => r,
+            None => return,
+        };
+        let message = match recording.sink.finish() {
+            Ok(()) => format!("recording stopped; failed to write {}: {}", recording.path.display(), error),
+            Err(e) => format!(
+                "recording stopped; failed to write {}: {} (and to finish it: {})",
+                recording.path.display(), error, e,
+            ),
+        };
+        self.pending_notice = Some(message);
+    }
+}
+
+
+fn push_bounded<T>(queue: &mut VecDeque<T>, value: T, capacity: usize) {
+    if queue.len() == capacity {
+        queue.pop_front();
+    }
+    queue.push_back(value);
+}
+
+
+/// An output sink that feeds the dashboard and, while recording, passes the samples on to the
+/// sink of the recording file. If the recording file cannot be written (e.g. because the disk is
+/// full), the recording is stopped and the error shown on the dashboard instead of ending the
+/// session.
+pub struct DashboardSink {
+    state: Rc<RefCell<DashboardState>>,
+}
+impl OutputSink for DashboardSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        self.state.borrow_mut().device_id = metadata.device_id.clone();
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.latest = Some(*sample);
+        state.last_sample_received = Some(Instant::now());
+        push_bounded(&mut state.spo2_trend, sample.spo2, TREND_SAMPLES);
+        push_bounded(&mut state.pulse_trend, sample.pulse, TREND_SAMPLES);
+        if let Some(recording) = state.recording.as_mut() {
+            match recording.sink.write_sample(sample) {
+                Ok(()) => recording.sample_count += 1,
+                Err(e) => state.abandon_recording(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_waveform(&mut self, point: &WaveformPoint) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        push_bounded(&mut state.waveform, point.value, WAVEFORM_POINTS);
+        if point.beat {
+            state.last_beat = Some(Instant::now());
+        }
+        if let Some(recording) = state.recording.as_mut() {
+            if let Err(e) = recording.sink.write_waveform(point) {
+                state.abandon_recording(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        if let Some(recording) = state.recording.as_mut() {
+            if let Err(e) = recording.sink.flush() {
+                state.abandon_recording(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        match state.stop_recording() {
+            Ok(Some(path)) => state.pending_notice = Some(format!("recording saved to {}", path.display())),
+            Ok(None) => {},
+            Err(e) => state.pending_notice = Some(format!("failed to finish recording: {}", e)),
+        }
+        Ok(())
+    }
+}
+
+
+/// A full-screen terminal dashboard for live mode.
+///
+/// Shows the current SpO2 and pulse in large digits, the pulse curve, trends of the last few
+/// minutes, the finger and link status and whether the samples are being recorded. Pressing `r`
+/// starts writing the samples into a new file in the current directory (in the chosen output
+/// format) and pressing it again finishes the file; `q`, Escape or Ctrl+C end the session.
+///
+/// The terminal is switched into raw mode and the alternate screen while the dashboard exists and
+/// restored when it is dropped.
+pub struct Dashboard {
+    terminal: DefaultTerminal,
+    state: Rc<RefCell<DashboardState>>,
+    format: OutputFormat,
+    timestamps: TimestampMode,
+    last_draw: Option<Instant>,
+    message: Option<String>,
+}
+impl Dashboard {
+    /// Takes over the terminal. Recordings are written in the given format.
+    pub fn new(format: OutputFormat, timestamps: TimestampMode) -> Self {
+        Self {
+            terminal: ratatui::init(),
+            state: Rc::new(RefCell::new(DashboardState::new())),
+            format,
+            timestamps,
+            last_draw: None,
+            message: None,
+        }
+    }
+
+    /// Returns a sink which feeds the live data into the dashboard.
+    pub fn sink(&self) -> DashboardSink {
+        DashboardSink {
+            state: Rc::clone(&self.state),
+        }
+    }
+
+    /// Shows a message in the status panel, replacing the previous one.
+    pub fn notice(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    /// Processes the pending key presses without blocking. Returns whether the user asked to end
+    /// the session.
+    pub fn handle_input(&mut self) -> bool {
+        while event::poll(StdDuration::ZERO).expect("failed to poll terminal events") {
+            let key = match event::read().expect("failed to read terminal event") {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return true,
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+                KeyCode::Char('r') => self.toggle_recording(),
+                _ => {},
+            }
+        }
+        false
+    }
+
+    fn toggle_recording(&mut self) {
+        let mut state = self.state.borrow_mut();
+        if state.recording.is_some() {
+            self.message = Some(match state.stop_recording() {
+                Ok(Some(path)) => format!("recording saved to {}", path.display()),
+                Ok(None) => unreachable!(),
+                Err(e) => format!("failed to finish recording: {}", e),
+            });
+            return;
+        }
+
+        let started = Local::now();
+        let path = PathBuf::from(format!(
+            "poxymeter-live-{}.{}",
+            started.format("%Y%m%d-%H%M%S"), file_extension(self.format),
+        ));
+        let file = match File::create(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                self.message = Some(format!("failed to create {}: {}", path.display(), e));
+                return;
+            },
+        };
+        let formatter = TimestampFormatter::new(self.timestamps, started, true);
+        let mut sink = create_sink(self.format, Box::new(BufWriter::new(file)), formatter);
+        let metadata = RecordingMetadata {
+            device_id: state.device_id.clone(),
+            start_time: started,
+            mode: SourceMode::Live,
+        };
+        if let Err(e) = sink.begin(&metadata) {
+            self.message = Some(format!("failed to write {}: {}", path.display(), e));
+            return;
+        }
+
+        self.message = Some(format!("recording to {}", path.display()));
+        state.recording = Some(ActiveRecording {
+            path,
+            started,
+            sample_count: 0,
+            sink,
+        });
+    }
+
+    /// Redraws the dashboard unless it has been redrawn very recently.
+    pub fn draw_if_due(&mut self, link_stats: &LinkStats) {
+        let is_due = self.last_draw
+            .map(|t| t.elapsed() >= StdDuration::from_millis(REDRAW_INTERVAL_MS))
+            .unwrap_or(true);
+        if !is_due {
+            return;
+        }
+
+        if let Some(notice) = self.state.borrow_mut().pending_notice.take() {
+            self.message = Some(notice);
+        }
+        let state = self.state.borrow();
+        let message = self.message.as_deref();
+        self.terminal.draw(|frame| render(frame, &state, link_stats, message))
+            .expect("failed to draw dashboard");
+        self.last_draw = Some(Instant::now());
+    }
+}
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        ratatui::restore();
+
+        // e.g. where the recording has been saved when the session ended
+        if let Some(notice) = self.state.borrow_mut().pending_notice.take() {
+            eprintln!("{}", notice);
+        }
+    }
+}
+
+
+fn render(frame: &mut Frame, state: &DashboardState, link_stats: &LinkStats, message: Option<&str>) {
+    let [top_area, waveform_area, trend_area, help_area] = Layout::vertical([
+        Constraint::Length(9),
+        Constraint::Min(6),
+        Constraint::Length(8),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+    let [spo2_area, pulse_area, status_area] = Layout::horizontal([
+        Constraint::Length(21),
+        Constraint::Length(21),
+        Constraint::Min(30),
+    ]).areas(top_area);
+    let [spo2_trend_area, pulse_trend_area] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Length(4),
+    ]).areas(trend_area);
+
+    let (spo2, pulse) = match (&state.latest, state.is_stale()) {
+        (Some(sample), false) => (sample.spo2, sample.pulse),
+        _ => (None, None),
+    };
+    let spo2_color = match spo2 {
+        Some(v) if v < 90 => Color::Red,
+        Some(v) if v < 94 => Color::Yellow,
+        Some(_) => Color::Green,
+        None => Color::DarkGray,
+    };
+    let beating = state.last_beat
+        .map(|t| t.elapsed() < StdDuration::from_millis(BEAT_INDICATOR_MS))
+        .unwrap_or(false);
+
+    render_big_value(frame, spo2_area, "SpO2 %", spo2, spo2_color);
+    render_big_value(
+        frame, pulse_area, if beating { "Pulse bpm ♥" } else { "Pulse bpm" },
+        pulse, if pulse.is_some() { Color::Cyan } else { Color::DarkGray },
+    );
+    render_status(frame, status_area, state, link_stats, message);
+    render_waveform(frame, waveform_area, state);
+
+    let spo2_trend: Vec<Option<u64>> = state.spo2_trend.iter()
+        .map(|v| v.map(|v| v.saturating_sub(SPO2_TREND_FLOOR).into()))
+        .collect();
+    render_trend(
+        frame, spo2_trend_area, &format!("SpO2 trend ({}–100 %)", SPO2_TREND_FLOOR),
+        &spo2_trend, (100 - SPO2_TREND_FLOOR).into(), Color::Green,
+    );
+    let pulse_trend: Vec<Option<u64>> = state.pulse_trend.iter()
+        .map(|v| v.map(u64::from))
+        .collect();
+    let pulse_max = pulse_trend.iter().flatten().copied().max().unwrap_or(0).max(100);
+    render_trend(
+        frame, pulse_trend_area, &format!("Pulse trend (0–{} bpm)", pulse_max),
+        &pulse_trend, pulse_max, Color::Cyan,
+    );
+
+    let help = Line::from(vec![
+        Span::styled(" r ", Style::default().add_modifier(Modifier::REVERSED)),
+        Span::raw(" start/stop recording  "),
+        Span::styled(" q ", Style::default().add_modifier(Modifier::REVERSED)),
+        Span::raw(" quit"),
+    ]);
+    frame.render_widget(Paragraph::new(help), help_area);
+}
+
+fn render_big_value(frame: &mut Frame, area: Rect, title: &str, value: Option<u8>, color: Color) {
+    let glyphs: Vec<[&str; 5]> = match value {
+        Some(v) => v.to_string()
+            .bytes()
+            .map(|b| BIG_DIGITS[usize::from(b - b'0')])
+            .collect(),
+        None => vec![BIG_DASH, BIG_DASH],
+    };
+    let mut lines = vec![Line::raw("")];
+    for row in 0..5 {
+        let text: Vec<&str> = glyphs.iter()
+            .map(|g| g[row])
+            .collect();
+        lines.push(Line::styled(text.join(" "), Style::default().fg(color)));
+    }
+
+    let paragraph = Paragraph::new(Text::from(lines))
+        .centered()
+        .block(Block::bordered().title(title));
+    frame.render_widget(paragraph, area);
+}
+
+fn render_status(frame: &mut Frame, area: Rect, state: &DashboardState, link_stats: &LinkStats, message: Option<&str>) {
+    let finger = if state.is_stale() {
+        Span::styled("no data", Style::default().fg(Color::Yellow))
+    } else if state.latest.map(|s| s.spo2.is_some() && s.pulse.is_some()).unwrap_or(false) {
+        Span::styled("finger in, signal OK", Style::default().fg(Color::Green))
+    } else {
+        Span::styled("finger out or no signal", Style::default().fg(Color::Red))
+    };
+    let recording = match state.recording.as_ref() {
+        Some(r) => {
+            let elapsed = (Local::now() - r.started).num_seconds();
+            Span::styled(
+                format!(
+                    "● {} ({:02}:{:02}, {} samples)",
+                    r.path.display(), elapsed / 60, elapsed % 60, r.sample_count,
+                ),
+                Style::default().fg(Color::Red),
+            )
+        },
+        None => Span::raw("off"),
+    };
+
+    let lines = vec![
+        Line::from(vec![Span::raw("Device:    "), Span::raw(state.device_id.as_deref().unwrap_or("?"))]),
+        Line::from(vec![Span::raw("Finger:    "), finger]),
+        Line::raw(format!(
+            "Link:      {} packets, {} samples",
+            link_stats.packets, link_stats.samples,
+        )),
+        Line::raw(format!(
+            "           {} checksum failures, {} restarts, {} keepalives",
+            link_stats.checksum_failures, link_stats.restarts, link_stats.keepalives,
+        )),
+        Line::from(vec![Span::raw("Recording: "), recording]),
+        Line::styled(message.unwrap_or(""), Style::default().fg(Color::Gray)),
+    ];
+    let paragraph = Paragraph::new(Text::from(lines))
+        .block(Block::bordered().title("Status"));
+    frame.render_widget(paragraph, area);
+}
+
+fn render_waveform(frame: &mut Frame, area: Rect, state: &DashboardState) {
+    let points: Vec<(f64, f64)> = state.waveform.iter()
+        .enumerate()
+        .map(|(i, v)| (i as f64, f64::from(*v)))
+        .collect();
+    let dataset = Dataset::default()
+        .marker(Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::default().fg(Color::Yellow))
+        .data(&points);
+    let chart = Chart::new(vec![dataset])
+        .block(Block::bordered().title("Pulse curve"))
+        .x_axis(Axis::default().bounds([0.0, (WAVEFORM_POINTS - 1) as f64]))
+        .y_axis(Axis::default().bounds([0.0, 127.0]));
+    frame.render_widget(chart, area);
+}
+
+fn render_trend(frame: &mut Frame, area: Rect, title: &str, values: &[Option<u64>], max: u64, color: Color) {
+    // show the most recent values that fit
+    let width = usize::from(area.width.saturating_sub(2));
+    let shown = &values[values.len().saturating_sub(width)..];
+    let sparkline = Sparkline::default()
+        .block(Block::bordered().title(title))
+        .data(shown)
+        .max(max)
+        .style(Style::default().fg(color));
+    frame.render_widget(sparkline, area);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::output::{test_metadata, test_sample};
+
+    /// A writer that always fails, like one on a full disk.
+    struct FullDisk;
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_recording_is_stopped() {
+        let state = Rc::new(RefCell::new(DashboardState::new()));
+        let start = test_metadata().start_time;
+        let formatter = TimestampFormatter::new(TimestampMode::Local, start, true);
+        state.borrow_mut().recording = Some(ActiveRecording {
+            path: PathBuf::from("poxymeter-live.csv"),
+            started: start,
+            sample_count: 0,
+            sink: create_sink(OutputFormat::Csv, Box::new(FullDisk), formatter),
+        });
+
+        let mut sink = DashboardSink {
+            state: Rc::clone(&state),
+        };
+        sink.begin(&test_metadata()).unwrap();
+        sink.write_sample(&test_sample(0, Some(61), Some(97))).unwrap();
+        sink.write_sample(&test_sample(1, Some(62), Some(96))).unwrap();
+        sink.finish().unwrap();
+
+        let state = state.borrow();
+        assert!(state.recording.is_none());
+        assert_eq!(state.latest, Some(test_sample(1, Some(62), Some(96))));
+        assert!(state.pending_notice.as_deref().unwrap().contains("disk full"));
+    }
+}
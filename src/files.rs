@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Local, NaiveDate};
+use hidapi::HidDevice;
+use log::{self, debug, log_enabled};
+
+use crate::oximeter::{
+    calculate_checksum, CommandCode, CommandQueue, is_checksum_ok, PropertyCode,
+    receive_from_oximeter, receive_from_oximeter_timeout, RecordingMode, send_to_oximeter,
+};
+use crate::recording::{local_from_naive, Recording, RecordingMetadata, SourceMode};
+
+
+/// How long a single read may block while waiting for the number of files.
+const FILE_COUNT_READ_TIMEOUT_MS: i32 = 250;
+
+/// How many reads to wait for the number of files before giving up.
+const FILE_COUNT_READ_ATTEMPTS: usize = 8;
+
+/// How long a single read may block while waiting for the file store information.
+const FILE_STORE_INFO_READ_TIMEOUT_MS: i32 = 250;
+
+/// How many reads to wait for the file store information before giving up.
+const FILE_STORE_INFO_READ_ATTEMPTS: usize = 8;
+
+
+/// The header of a file stored on the oximeter, as obtained before the file itself is read.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FileHeader {
+    /// The (1-based) index of the file on the oximeter.
+    pub index: usize,
+
+    /// The recording mode in which the file was stored.
+    pub mode: SourceMode,
+
+    pub start_time: DateTime<Local>,
+
+    /// The number of samples in the file.
+    pub length: usize,
+
+    /// The response in which the oximeter delivered the header, as received.
+    pub raw: Vec<u8>,
+}
+
+
+/// Asks the oximeter which recording mode it is set to.
+pub fn read_recording_mode(oxdev: &HidDevice, queue: &mut CommandQueue) -> RecordingMode {
+    let mut rec_mode_command = vec![
+        CommandCode::ReadPropertyCommand.into(),
+        PropertyCode::RecordingMode.into(),
+    ];
+    rec_mode_command.push(calculate_checksum(&rec_mode_command));
+    send_to_oximeter(oxdev, &rec_mode_command)
+        .expect("failed to send recording mode request");
+
+    loop {
+        receive_from_oximeter(oxdev, queue)
+            .expect("failed to receive response to file metadata request");
+        let mut rm = None;
+        while let Some(response) = queue.dequeue_command() {
+            if !is_checksum_ok(&response) {
+                continue;
+            }
+            if response.len() < 2 {
+                continue;
+            }
+            if response[0] != u8::from(CommandCode::ReadPropertyResponse) {
+                continue;
+            }
+            if response[1] != u8::from(PropertyCode::RecordingMode) {
+                continue;
+            }
+            if response.len() != 5 {
+                continue;
+            }
+
+            let rec_mode_code
+                = (response[2] as u16)
+                | ((response[3] as u16) << 7)
+                ;
+            rm = Some(RecordingMode::from(rec_mode_code));
+        }
+
+        if let Some(concrete_rm) = rm {
+            return concrete_rm;
+        }
+    }
+}
+
+/// Obtains the headers of all the files stored on the oximeter in the given recording mode.
+pub fn list_files(oxdev: &HidDevice, queue: &mut CommandQueue, rec_mode: RecordingMode) -> Vec<FileHeader> {
+    match rec_mode {
+        RecordingMode::Automatic => list_auto_files(oxdev, queue),
+        RecordingMode::Manual => read_manual_file_header(oxdev, queue).into_iter().collect(),
+        RecordingMode::Other(o) => panic!("unknown recording mode {}", o),
+    }
+}
+
+/// Reads the file with the given header from the oximeter.
+pub fn download_file(oxdev: &HidDevice, queue: &mut CommandQueue, header: &FileHeader) -> Recording {
+    match header.mode {
+        SourceMode::Automatic => read_auto_file(oxdev, queue, header),
+        SourceMode::Manual => read_manual_file(oxdev, queue, header),
+        SourceMode::Live => panic!("live data is not stored on the oximeter"),
+    }
+}
+
+/// Asks the oximeter to delete all the files stored in automatic recording mode.
+///
+/// This is the sequence SpO2 Assistant sends after it has downloaded the files in automatic
+/// recording mode. The oximeter does not acknowledge it.
+pub fn delete_auto_files(oxdev: &HidDevice) {
+    let mut delete_command = vec![
+        CommandCode::ReadAutoRecordedFileCommand.into(),
+        0x02, 0x7F, 0x7F, 0x7F, 0x00, 0x00,
+    ];
+    delete_command.push(calculate_checksum(&delete_command));
+    send_to_oximeter(oxdev, &delete_command)
+        .expect("failed to send delete request");
+}
+
+/// Asks the oximeter how many files it has stored in automatic recording mode. Returns `None` if
+/// the oximeter does not answer in time.
+pub fn count_auto_files(oxdev: &HidDevice, queue: &mut CommandQueue) -> Option<usize> {
+    {
+        let mut count_command = vec![
+            CommandCode::GetAuxiliaryDataCommand.into(),
+            PropertyCode::AutoRecordedFiles.into(),
+        ];
+        count_command.push(calculate_checksum(&count_command));
+        send_to_oximeter(oxdev, &count_command)
+            .expect("failed to send count request");
+    }
+
+    for _ in 0..FILE_COUNT_READ_ATTEMPTS {
+        let received = receive_from_oximeter_timeout(oxdev, queue, FILE_COUNT_READ_TIMEOUT_MS)
+            .expect("failed to receive response to file count request");
+        if !received {
+            continue;
+        }
+
+        while let Some(response) = queue.dequeue_command() {
+            if !is_checksum_ok(&response) {
+                continue;
+            }
+            if response.len() < 7 {
+                continue;
+            }
+            if response[0] != u8::from(CommandCode::GetAuxiliaryDataResponse) {
+                continue;
+            }
+
+            // topmost bit is not set, so shift up by 7
+            let pulse_count = (response[2] as usize) | ((response[3] as usize) << 7);
+            let spo2_count = (response[4] as usize) | ((response[5] as usize) << 7);
+            return Some(pulse_count.min(spo2_count));
+        }
+    }
+    None
+}
+
+/// Asks the oximeter for information about its file store. Returns the payload of the response
+/// (without the command code and checksum), or `None` if the oximeter does not answer in time.
+///
+/// The meaning of the individual bytes is unknown; they are all zero if no data is stored.
+pub fn read_file_store_info(oxdev: &HidDevice, queue: &mut CommandQueue) -> Option<Vec<u8>> {
+    let mut info_command = vec![CommandCode::FileStoreInfoCommand.into()];
+    info_command.push(calculate_checksum(&info_command));
+    send_to_oximeter(oxdev, &info_command)
+        .expect("failed to send file store info request");
+
+    for _ in 0..FILE_STORE_INFO_READ_ATTEMPTS {
+        let received = receive_from_oximeter_timeout(oxdev, queue, FILE_STORE_INFO_READ_TIMEOUT_MS)
+            .expect("failed to receive response to file store info request");
+        if !received {
+            continue;
+        }
+
+        while let Some(response) = queue.dequeue_command() {
+            if !is_checksum_ok(&response) {
+                continue;
+            }
+            if response[0] != u8::from(CommandCode::FileStoreInfoResponse) {
+                continue;
+            }
+
+            return Some(response[1..response.len()-1].to_vec());
+        }
+    }
+    None
+}
+
+fn list_auto_files(oxdev: &HidDevice, queue: &mut CommandQueue) -> Vec<FileHeader> {
+    let file_count = count_auto_files(oxdev, queue)
+        .expect("oximeter did not report the number of files");
+
+    // ask for metadata
+    let mut headers = Vec::with_capacity(file_count);
+    for i in 1..=file_count {
+        {
+            let mut advance_and_show_command = vec![
+                CommandCode::AdvanceAndShowAutoRecordedFileHeaderCommand.into(),
+                0x01, // advance by 1
+            ];
+            advance_and_show_command.push(calculate_checksum(&advance_and_show_command));
+            send_to_oximeter(oxdev, &advance_and_show_command)
+                .expect("failed to send advance-and-show request");
+        }
+
+        receive_from_oximeter(oxdev, queue)
+            .expect("failed to receive response to advance-and-show request");
+        while let Some(response) = queue.dequeue_command() {
+            if !is_checksum_ok(&response) {
+                continue;
+            }
+            if response[0] != u8::from(CommandCode::AdvanceAndShowAutoRecordedFileHeaderResponse) {
+                continue;
+            }
+
+            let start_time = NaiveDate::from_ymd(
+                (response[4] as i32) + 2000,
+                response[5] as u32,
+                response[6] as u32,
+            ).and_hms(
+                response[7] as u32,
+                response[8] as u32,
+                response[9] as u32
+            );
+            let this_file_length =
+                (response[10] as usize)
+                | ((response[11] as usize) << 7)
+                | ((response[12] as usize) << 14)
+            ;
+
+            headers.push(FileHeader {
+                index: i,
+                mode: SourceMode::Automatic,
+                start_time: local_from_naive(&start_time),
+                length: this_file_length,
+                raw: response,
+            });
+            break;
+        }
+    }
+
+    headers
+}
+
+fn read_auto_file(oxdev: &HidDevice, queue: &mut CommandQueue, header: &FileHeader) -> Recording {
+    let i = header.index;
+    let this_file_length = header.length;
+
+    let mut mode_to_values: HashMap<u8, Vec<u8>> = HashMap::new();
+    for mode in &[1, 2] {
+        let mut values = Vec::new();
+        let mut base_value = 0;
+        let mut base_value_top_nibble = false;
+
+        {
+            let mut get_file_command = vec![
+                CommandCode::ReadAutoRecordedFileCommand.into(),
+                0x04, // unknown constant
+                *mode,
+                0x01, // unknown constant
+                i.try_into().expect("file number too large"),
+                0x00,
+                0x00,
+                0x00,
+            ];
+            get_file_command.push(calculate_checksum(&get_file_command));
+            send_to_oximeter(oxdev, &get_file_command)
+                .expect("failed to send read-auto-file request");
+        }
+
+        while values.len() < this_file_length {
+            // we have more data to fetch
+            receive_from_oximeter(oxdev, queue)
+                .expect("failed to receive response to read-auto-file request");
+            while let Some(response) = queue.dequeue_command() {
+                if !is_checksum_ok(&response) {
+                    continue;
+                }
+                if response[0] != u8::from(CommandCode::ReadAutoRecordedFileResponse) {
+                    continue;
+                }
+                if response.len() != 30 {
+                    continue;
+                }
+
+                // again, since the top bit may not be set except at the beginning of a command,
+                // the sign bits for the top nibbles have been moved to the front
+                let sign_bits
+                    = (response[5] as u32)
+                    | ((response[6] as u32) << 7)
+                    | ((response[7] as u32) << 14)
+                    ;
+
+                let mut debug_all_bytes = Vec::new();
+                for (j, b) in response[8..29].iter().enumerate() {
+                    // top nibble needs the additional bit from the sign_bits
+                    let mut top_nibble = (*b >> 4) & 0x0F;
+                    if sign_bits & (1 << j) != 0 {
+                        top_nibble |= 0b1000;
+                    }
+
+                    // bottom nibble does not
+                    let bottom_nibble = *b & 0x0F;
+
+                    debug_all_bytes.push(top_nibble << 4 | bottom_nibble);
+
+                    if top_nibble == 0x0F {
+                        if bottom_nibble == 0x0F && !base_value_top_nibble {
+                            // invalid value
+                            // (unless we are waiting for the bottom nibble of the new base value)
+                            values.push(0xFF);
+                            values.push(0xFF);
+                            continue;
+                        }
+
+                        // we are changing the base value!
+                        if base_value_top_nibble {
+                            base_value |= bottom_nibble;
+                            base_value_top_nibble = false;
+                        } else {
+                            base_value = bottom_nibble << 4;
+                            base_value_top_nibble = true;
+                        }
+
+                        // note that this does not generate a value
+                    } else {
+                        // the nibbles are (downward) deltas from the current base value
+                        values.push(base_value - top_nibble);
+                        if bottom_nibble != 0x0F {
+                            // 0x0F is invalid
+                            values.push(base_value - bottom_nibble);
+                        }
+
+                        if values.len() == this_file_length {
+                            // we are done
+                            break;
+                        }
+                    }
+                }
+
+                if log_enabled!(log::Level::Debug) {
+                    let bstrs: Vec<String> = debug_all_bytes.iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect();
+                    debug!("DATA IS {}", bstrs.join(" "));
+                }
+            }
+        }
+
+        // and we're done
+        mode_to_values.insert(*mode, values);
+    }
+
+    // zip the values together
+    let metadata = RecordingMetadata {
+        device_id: None,
+        start_time: header.start_time,
+        mode: SourceMode::Automatic,
+    };
+    Recording::from_series(metadata, &mode_to_values[&2], &mode_to_values[&1])
+}
+
+fn read_manual_file_header(oxdev: &HidDevice, queue: &mut CommandQueue) -> Option<FileHeader> {
+    {
+        let mut metadata_command = vec![
+            CommandCode::ManuallyRecordedFileMetadataCommand.into(),
+            0x00, // there is only one file
+        ];
+        metadata_command.push(calculate_checksum(&metadata_command));
+        send_to_oximeter(oxdev, &metadata_command)
+            .expect("failed to send metadata request");
+    }
+
+    loop {
+        receive_from_oximeter(oxdev, queue)
+            .expect("failed to receive response to file metadata request");
+        let mut header = None;
+        while let Some(response) = queue.dequeue_command() {
+            if !is_checksum_ok(&response) {
+                continue;
+            }
+            if response.len() < 2 {
+                continue;
+            }
+            if response[0] != u8::from(CommandCode::ManuallyRecordedFileMetadataResponse) {
+                continue;
+            }
+            if response.len() != 14 {
+                continue;
+            }
+
+            let file_length
+                = (response[10] as usize)
+                | ((response[11] as usize) << 7)
+                | ((response[12] as usize) << 14)
+                ;
+
+            if file_length == 0 {
+                // no file recorded
+                return None;
+            }
+
+            // round length down to a multiple of 27
+            let full_chunk_count = file_length / 27;
+
+            let start_time = NaiveDate::from_ymd(
+                (response[2] as i32) + 2000,
+                response[3] as u32,
+                response[4] as u32,
+            ).and_hms(
+                response[5] as u32,
+                response[6] as u32,
+                response[7] as u32
+            );
+            header = Some(FileHeader {
+                index: 1,
+                mode: SourceMode::Manual,
+                start_time: local_from_naive(&start_time),
+                length: full_chunk_count * 27,
+                raw: response,
+            });
+        }
+
+        if header.is_some() {
+            return header;
+        }
+    }
+}
+
+fn read_manual_file(oxdev: &HidDevice, queue: &mut CommandQueue, header: &FileHeader) -> Recording {
+    let this_file_length = header.length;
+
+    let read_commands_responses = &[
+        (
+            CommandCode::ReadPulseFromManuallyRecordedFileCommand,
+            CommandCode::ReadPulseFromManuallyRecordedFileResponse,
+        ),
+        (
+            CommandCode::ReadOxygenFromManuallyRecordedFileCommand,
+            CommandCode::ReadOxygenFromManuallyRecordedFileResponse,
+        ),
+    ];
+
+    // list of lists of values (Gollum English)
+    let mut valueses = Vec::new();
+    for (read_command, read_response) in read_commands_responses {
+        // read the file!
+        {
+            let mut read_pulse_command = vec![
+                read_command.into(),
+                0x00,
+                0x00,
+                0x00,
+            ];
+            read_pulse_command.push(calculate_checksum(&read_pulse_command));
+            send_to_oximeter(oxdev, &read_pulse_command)
+                .expect("failed to send read-pulse request");
+        }
+
+        let mut values = Vec::new();
+        loop {
+            receive_from_oximeter(oxdev, queue)
+                .expect("failed to receive read-pulse request");
+            while let Some(response) = queue.dequeue_command() {
+                if !is_checksum_ok(&response) {
+                    continue;
+                }
+                if response.len() < 2 {
+                    continue;
+                }
+                if response[0] != u8::from(read_response) {
+                    continue;
+                }
+                if response.len() != 20 {
+                    continue;
+                }
+
+                // once more, the topmost bits have been "outsourced"
+                let signs
+                    = (response[3] as u16)
+                    | ((response[4] as u16) << 7)
+                    ;
+                let mut value_byte = response[5];
+                // the signs also contain the sign for the value byte
+                if signs & 1 != 0 {
+                    value_byte |= 0b1000_0000;
+                }
+
+                // this base value is also part of the output!
+                values.push(value_byte);
+
+                for (i, b) in response[6..19].iter().enumerate() {
+                    // take top nibble sign from signs
+                    let mut top_nibble = (*b >> 4) & 0x0F;
+                    // (adding 1 to the left-shift because 0 is used for the initial value byte)
+                    if signs & (1 << (i + 1)) != 0 {
+                        top_nibble |= 0b1000;
+                    }
+                    let bottom_nibble = *b & 0x0F;
+
+                    for nibble in [top_nibble, bottom_nibble] {
+                        // TODO: handle 0xF nibble as an invalid value
+                        if nibble == 0xF {
+                            value_byte = 0xFF;
+                        } else if nibble & 0b1000 != 0 {
+                            // subtract from base value
+                            value_byte -= nibble & 0b0111;
+                        } else {
+                            // add to base value
+                            value_byte += nibble & 0b0111;
+                        }
+
+                        values.push(value_byte);
+                    }
+                }
+            }
+
+            debug!("values.len(): {}, this_file_length: {}", values.len(), this_file_length);
+            if values.len() >= this_file_length {
+                values.truncate(this_file_length);
+                break;
+            }
+        }
+        valueses.push(values);
+    }
+
+    // merge the values
+    let metadata = RecordingMetadata {
+        device_id: None,
+        start_time: header.start_time,
+        mode: SourceMode::Manual,
+    };
+    Recording::from_series(metadata, &valueses[0], &valueses[1])
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, Local};
+
+
+/// RR intervals shorter than this (in milliseconds) are considered artifacts (above 240 bpm).
+const MIN_PLAUSIBLE_RR_MS: i64 = 250;
+
+/// RR intervals longer than this (in milliseconds) are considered artifacts (below 24 bpm); they
+/// generally mean that beats have been missed.
+const MAX_PLAUSIBLE_RR_MS: i64 = 2500;
+
+
+/// Derives beat-to-beat (RR) intervals from the beat flags of consecutive pulse curve points.
+///
+/// A beat can only be placed on a pulse curve point, and the points are 50 ms apart (their
+/// timestamps are reconstructed at that spacing and occasionally nudged towards the host clock).
+/// The intervals are therefore only accurate to about ±50 ms, which is of the same order as the
+/// RMSSD of many healthy adults at rest; the HRV metrics show trends rather than clinical values.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BeatTracker {
+    in_beat: bool,
+    last_beat: Option<DateTime<Local>>,
+}
+impl BeatTracker {
+    pub fn new() -> Self {
+        Self {
+            in_beat: false,
+            last_beat: None,
+        }
+    }
+
+    /// Feeds the beat flag of the next pulse curve point. Returns the interval since the previous
+    /// beat if this point starts a new beat and that interval is plausible.
+    pub fn feed(&mut self, timestamp: DateTime<Local>, is_beat: bool) -> Option<Duration> {
+        // the flag is generally set on multiple consecutive points; only the first one counts
+        let beat_starts = is_beat && !self.in_beat;
+        self.in_beat = is_beat;
+        if !beat_starts {
+            return None;
+        }
+
+        let previous_beat = self.last_beat.replace(timestamp)?;
+        let interval = timestamp - previous_beat;
+        let interval_ms = interval.num_milliseconds();
+        if !(MIN_PLAUSIBLE_RR_MS..=MAX_PLAUSIBLE_RR_MS).contains(&interval_ms) {
+            return None;
+        }
+        Some(interval)
+    }
+
+    /// Forgets the previous beat, e.g. because the finger has been removed.
+    pub fn reset(&mut self) {
+        self.in_beat = false;
+        self.last_beat = None;
+    }
+}
+
+
+/// Time-domain heart rate variability metrics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HrvMetrics {
+    pub interval_count: usize,
+    pub mean_rr_ms: f64,
+    pub sdnn_ms: f64,
+    pub rmssd_ms: f64,
+}
+
+
+/// A rolling window of RR intervals from which HRV metrics are calculated.
+#[derive(Clone, Debug)]
+pub struct HrvWindow {
+    length: Duration,
+    intervals: VecDeque<(DateTime<Local>, f64)>,
+}
+impl HrvWindow {
+    pub fn new(length: Duration) -> Self {
+        Self {
+            length,
+            intervals: VecDeque::new(),
+        }
+    }
+
+    /// Adds the RR interval ending at the given beat timestamp and drops the intervals that have
+    /// fallen out of the window.
+    pub fn push(&mut self, beat_timestamp: DateTime<Local>, interval: Duration) {
+        let interval_ms = interval.num_microseconds()
+            .map(|us| (us as f64) / 1000.0)
+            .unwrap_or_else(|| interval.num_milliseconds() as f64);
+        self.intervals.push_back((beat_timestamp, interval_ms));
+
+        let window_start = beat_timestamp - self.length;
+        while self.intervals.front().map(|(ts, _)| *ts < window_start).unwrap_or(false) {
+            self.intervals.pop_front();
+        }
+    }
+
+    /// Calculates the metrics over the intervals currently in the window. Returns `None` if there
+    /// are fewer than two intervals.
+    pub fn metrics(&self) -> Option<HrvMetrics> {
+        let count = self.intervals.len();
+        if count < 2 {
+            return None;
+        }
+
+        let mean = self.intervals.iter().map(|(_, rr)| *rr).sum::<f64>() / (count as f64);
+        let variance = self.intervals.iter()
+            .map(|(_, rr)| (*rr - mean) * (*rr - mean))
+            .sum::<f64>() / ((count - 1) as f64);
+        let squared_successive_diffs: Vec<f64> = self.intervals.iter()
+            .zip(self.intervals.iter().skip(1))
+            .map(|((_, a), (_, b))| (*b - *a) * (*b - *a))
+            .collect();
+        let rmssd = (
+            squared_successive_diffs.iter().sum::<f64>() / (squared_successive_diffs.len() as f64)
+        ).sqrt();
+
+        Some(HrvMetrics {
+            interval_count: count,
+            mean_rr_ms: mean,
+            sdnn_ms: variance.sqrt(),
+            rmssd_ms: rmssd,
+        })
+    }
+
+    /// Empties the window, e.g. because the finger has been removed and the successive
+    /// differences across the gap would be meaningless.
+    pub fn clear(&mut self) {
+        self.intervals.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(ms: i64) -> DateTime<Local> {
+        Local.ymd(2021, 3, 1).and_hms(22, 0, 0) + Duration::milliseconds(ms)
+    }
+
+    #[test]
+    fn intervals_from_beat_flags() {
+        let mut tracker = BeatTracker::new();
+        let points = [(0, true), (20, true), (40, false), (800, true), (820, true), (1700, true)];
+        let intervals: Vec<Option<i64>> = points.iter()
+            .map(|(ms, beat)| tracker.feed(at(*ms), *beat).map(|i| i.num_milliseconds()))
+            .collect();
+        // a beat that continues over multiple points only counts once
+        assert_eq!(intervals, vec![None, None, None, Some(800), None, None]);
+    }
+
+    #[test]
+    fn implausible_intervals_are_skipped() {
+        let mut tracker = BeatTracker::new();
+        assert_eq!(tracker.feed(at(0), true), None);
+        assert_eq!(tracker.feed(at(100), false), None);
+        assert_eq!(tracker.feed(at(200), true), None); // too short
+        assert_eq!(tracker.feed(at(220), false), None);
+        assert_eq!(tracker.feed(at(3000), true), None); // too long
+        assert_eq!(tracker.feed(at(3020), false), None);
+        assert_eq!(tracker.feed(at(3900), true), Some(Duration::milliseconds(900)));
+
+        tracker.reset();
+        assert_eq!(tracker.feed(at(4700), true), None);
+    }
+
+    #[test]
+    fn metrics() {
+        let mut window = HrvWindow::new(Duration::seconds(10));
+        window.push(at(800), Duration::milliseconds(800));
+        assert_eq!(window.metrics(), None);
+        window.push(at(1800), Duration::milliseconds(1000));
+        window.push(at(2700), Duration::milliseconds(900));
+
+        let metrics = window.metrics().unwrap();
+        assert_eq!(metrics.interval_count, 3);
+        assert!((metrics.mean_rr_ms - 900.0).abs() < 1e-9);
+        assert!((metrics.sdnn_ms - 100.0).abs() < 1e-9);
+        // successive differences 200 and -100
+        assert!((metrics.rmssd_ms - 25000.0f64.sqrt()).abs() < 1e-9);
+
+        // the first two fall out of the window
+        window.push(at(12000), Duration::milliseconds(900));
+        assert_eq!(window.metrics().map(|m| m.interval_count), Some(2));
+    }
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Local};
+
+use crate::input::{MAX_SPO2, ParsedInput, ParseError, parse_timestamp, parse_value};
+use crate::recording::{ArtifactFlags, Sample, SourceMode};
+
+
+/// The value the first versions of `live-data` wrote in place of an invalid reading.
+const LEGACY_INVALID_VALUE: &str = "127";
+
+
+/// Splits a CSV line into its fields, removing the quotes (RFC 4180).
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    // escaped quote
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else if c == '"' {
+            in_quotes = true;
+        } else if c == ',' {
+            fields.push(std::mem::take(&mut field));
+        } else {
+            field.push(c);
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+
+/// Parses a row written by the first versions of `live-data`, which separated the values with
+/// spaces instead of commas (the timestamp itself contains a space). The raw values were written,
+/// so 127 stands in for an invalid reading; as it is also a plausible pulse, it is only taken as
+/// such if the SpO2 is invalid too.
+fn parse_legacy_row(line: &str, line_number: usize, origin: Option<DateTime<Local>>) -> Result<Sample, ParseError> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 3 {
+        return Err(ParseError::new(line_number, "too few columns"));
+    }
+    let (timestamp_fields, values) = fields.split_at(fields.len() - 2);
+    let timestamp = parse_timestamp(&timestamp_fields.join(" "), origin)
+        .map_err(|e| ParseError::new(line_number, e))?;
+    let spo2 = match values[1] {
+        LEGACY_INVALID_VALUE => None,
+        value => parse_value(value, MAX_SPO2)
+            .map_err(|e| ParseError::new(line_number, e))?,
+    };
+    let pulse = match values[0] {
+        LEGACY_INVALID_VALUE if spo2.is_none() => None,
+        value => parse_value(value, u8::MAX)
+            .map_err(|e| ParseError::new(line_number, e))?,
+    };
+    Ok(Sample {
+        timestamp,
+        pulse,
+        spo2,
+        artifacts: ArtifactFlags::default(),
+    })
+}
+
+
+/// Parses the output of `CsvSink`, as well as the space-separated rows written by the first
+/// versions of `live-data`.
+pub(super) fn parse(text: &str, mut origin: Option<DateTime<Local>>) -> Result<ParsedInput, ParseError> {
+    let mut parsed = ParsedInput::default();
+    let mut lines = text.lines()
+        .map(|l| l.trim_start_matches('\u{FEFF}').trim_end())
+        .enumerate()
+        .peekable();
+
+    // the metadata comes first, as comments
+    while let Some((index, line)) = lines.next_if(|(_, l)| l.starts_with('#')) {
+        let (key, value) = match line[1..].split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "device_id" => parsed.device_id = Some(value.to_owned()),
+            "mode" => parsed.mode = SourceMode::from_name(value),
+            "start_time" => {
+                let start_time = parse_timestamp(value, origin)
+                    .map_err(|e| ParseError::new(index + 1, e))?;
+                parsed.start_time = Some(start_time);
+
+                // monotonic sample timestamps are relative to the start time
+                origin = origin.or(Some(start_time));
+            },
+            _ => {},
+        }
+    }
+
+    let (header_index, header) = lines.next()
+        .map(|(i, l)| (i, split_fields(l)))
+        .ok_or_else(|| ParseError::new(0, "file is empty"))?;
+    let column = |name: &str| header.iter().position(|h| h == name)
+        .ok_or_else(|| ParseError::new(header_index + 1, format!("column {:?} missing", name)));
+    let timestamp_column = column("timestamp")?;
+    let pulse_column = column("pulse")?;
+    let spo2_column = column("spo2")?;
+    let artifacts_column = header.iter().position(|h| h == "artifacts");
+
+    for (index, line) in lines {
+        let line_number = index + 1;
+        if line.is_empty() {
+            continue;
+        }
+
+        if !line.contains(',') {
+            parsed.samples.push(parse_legacy_row(line, line_number, origin)?);
+            continue;
+        }
+
+        let fields = split_fields(line);
+        let field = |column: usize| fields.get(column)
+            .map(|f| f.as_str())
+            .ok_or_else(|| ParseError::new(line_number, "too few columns"));
+        let timestamp = parse_timestamp(field(timestamp_column)?, origin)
+            .map_err(|e| ParseError::new(line_number, e))?;
+        let pulse = parse_value(field(pulse_column)?, u8::MAX)
+            .map_err(|e| ParseError::new(line_number, e))?;
+        let spo2 = parse_value(field(spo2_column)?, MAX_SPO2)
+            .map_err(|e| ParseError::new(line_number, e))?;
+        let artifacts = match artifacts_column.and_then(|c| fields.get(c)) {
+            Some(names) if !names.is_empty() => ArtifactFlags::from_names(names.split(';'))
+                .ok_or_else(|| ParseError::new(line_number, format!("invalid artifact flags {:?}", names)))?,
+            _ => ArtifactFlags::default(),
+        };
+        parsed.samples.push(Sample {
+            timestamp,
+            pulse,
+            spo2,
+            artifacts,
+        });
+    }
+
+    Ok(parsed)
+}
@@ -0,0 +1,100 @@
+use chrono::{DateTime, Local};
+use serde_json::Value;
+
+use crate::input::{ParsedInput, ParseError, parse_timestamp};
+use crate::recording::{ArtifactFlags, Sample, SourceMode};
+
+
+/// Obtains the timestamp from a JSON value, which is a string or, for monotonic timestamps, a
+/// number.
+fn timestamp_from_value(value: &Value, origin: Option<DateTime<Local>>) -> Result<DateTime<Local>, String> {
+    match value {
+        Value::String(s) => parse_timestamp(s, origin),
+        Value::Number(n) => parse_timestamp(&n.to_string(), origin),
+        other => Err(format!("invalid timestamp {}", other)),
+    }
+}
+
+/// Obtains a pulse or SpO2 value from a JSON value; `null` is an invalid reading.
+fn reading_from_value(value: Option<&Value>) -> Result<Option<u8>, String> {
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Number(n)) => n.as_u64()
+            .filter(|v| *v <= u8::MAX.into())
+            .map(|v| Some(v as u8))
+            .ok_or_else(|| format!("invalid value {}", n)),
+        Some(other) => Err(format!("invalid value {}", other)),
+    }
+}
+
+
+/// Obtains the artifact flags from a JSON array of their names.
+fn artifacts_from_value(value: Option<&Value>) -> Result<ArtifactFlags, String> {
+    match value {
+        None | Some(Value::Null) => Ok(ArtifactFlags::default()),
+        Some(Value::Array(names)) => {
+            let names: Option<Vec<&str>> = names.iter().map(|n| n.as_str()).collect();
+            names.and_then(|n| ArtifactFlags::from_names(n.into_iter()))
+                .ok_or_else(|| "invalid artifact flags".to_owned())
+        },
+        Some(other) => Err(format!("invalid artifact flags {}", other)),
+    }
+}
+
+
+/// Parses the output of `JsonLinesSink`.
+pub(super) fn parse(text: &str, mut origin: Option<DateTime<Local>>) -> Result<ParsedInput, ParseError> {
+    let mut parsed = ParsedInput::default();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim_start_matches('\u{FEFF}').trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let object: Value = serde_json::from_str(line)
+            .map_err(|e| ParseError::new(line_number, e.to_string()))?;
+        match object.get("type").and_then(|t| t.as_str()) {
+            Some("metadata") => {
+                parsed.device_id = object.get("device_id")
+                    .and_then(|d| d.as_str())
+                    .map(|d| d.to_owned());
+                parsed.mode = object.get("mode")
+                    .and_then(|m| m.as_str())
+                    .and_then(SourceMode::from_name);
+
+                // older versions wrote monotonic start times as 0, which says nothing
+                if let Some(Value::String(start_time)) = object.get("start_time") {
+                    let start_time = parse_timestamp(start_time, origin)
+                        .map_err(|e| ParseError::new(line_number, e))?;
+                    parsed.start_time = Some(start_time);
+
+                    // monotonic sample timestamps are relative to the start time
+                    origin = origin.or(Some(start_time));
+                }
+            },
+            Some("sample") => {
+                let timestamp = object.get("timestamp")
+                    .ok_or_else(|| "timestamp missing".to_owned())
+                    .and_then(|t| timestamp_from_value(t, origin))
+                    .map_err(|e| ParseError::new(line_number, e))?;
+                let pulse = reading_from_value(object.get("pulse"))
+                    .map_err(|e| ParseError::new(line_number, e))?;
+                let spo2 = reading_from_value(object.get("spo2"))
+                    .map_err(|e| ParseError::new(line_number, e))?;
+                let artifacts = artifacts_from_value(object.get("artifacts"))
+                    .map_err(|e| ParseError::new(line_number, e))?;
+                parsed.samples.push(Sample {
+                    timestamp,
+                    pulse,
+                    spo2,
+                    artifacts,
+                });
+            },
+            _ => {
+                // other types of line (if added later) are not relevant
+            },
+        }
+    }
+    Ok(parsed)
+}
@@ -0,0 +1,351 @@
+mod csv;
+mod jsonl;
+mod spo2_assistant;
+
+
+use std::error::Error;
+use std::fmt;
+
+use chrono::{DateTime, Duration, Local, NaiveDateTime};
+
+use crate::opts::InputFormat;
+use crate::recording::{
+    INVALID_RECORDED_VALUE, local_from_naive, Recording, RecordingMetadata, Sample, SourceMode,
+};
+
+
+/// The highest valid SpO2 value, in percent.
+const MAX_SPO2: u8 = 100;
+
+/// The formats in which local timestamps may be written.
+const LOCAL_TIMESTAMP_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d %H:%M:%S"];
+
+
+/// A problem with a file that is being read back in.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ParseError {
+    /// The (1-based) line on which the problem was found; 0 if it concerns the file as a whole.
+    pub line: usize,
+    pub message: String,
+}
+impl ParseError {
+    pub fn new<M: Into<String>>(line: usize, message: M) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+}
+impl Error for ParseError {
+}
+
+
+/// What a parser has been able to extract from a file. Most formats do not store any metadata.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+struct ParsedInput {
+    device_id: Option<String>,
+    start_time: Option<DateTime<Local>>,
+    mode: Option<SourceMode>,
+    samples: Vec<Sample>,
+}
+
+
+/// Metadata supplied by the user, overriding that stored in the file (if any).
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct MetadataOverrides {
+    pub device_id: Option<String>,
+    pub mode: Option<SourceMode>,
+
+    /// The point in time relative to which monotonic timestamps are interpreted; also the start
+    /// time of the recording.
+    pub start_time: Option<DateTime<Local>>,
+}
+
+
+/// Guesses the format of a file from its first line, skipping the metadata comments that precede
+/// the header of CSV files.
+pub fn detect_format(text: &str) -> Option<InputFormat> {
+    let first_line = text.lines()
+        .map(|l| l.trim_start_matches('\u{FEFF}').trim())
+        .find(|l| !l.starts_with('#'))?;
+    if first_line.starts_with('{') {
+        Some(InputFormat::Jsonl)
+    } else if first_line.starts_with("timestamp,") {
+        Some(InputFormat::Csv)
+    } else if spo2_assistant::is_header(first_line) {
+        Some(InputFormat::Spo2Assistant)
+    } else {
+        None
+    }
+}
+
+
+/// Parses a file previously output by poxymeter (or exported by SpO2 Assistant) back into a
+/// recording.
+///
+/// The start time is taken from the overrides, the file's metadata or the first sample, in that
+/// order. If the file contains no information about the recording mode, it is assumed to have been
+/// downloaded in automatic recording mode.
+pub fn parse_recording(format: InputFormat, text: &str, overrides: &MetadataOverrides) -> Result<Recording, ParseError> {
+    let origin = overrides.start_time;
+    let parsed = match format {
+        InputFormat::Csv => csv::parse(text, origin)?,
+        InputFormat::Jsonl => jsonl::parse(text, origin)?,
+        InputFormat::Spo2Assistant => spo2_assistant::parse(text)?,
+    };
+
+    let start_time = overrides.start_time
+        .or(parsed.start_time)
+        .or_else(|| parsed.samples.first().map(|s| s.timestamp))
+        .ok_or_else(|| ParseError::new(0, "file contains no samples"))?;
+    let metadata = RecordingMetadata {
+        device_id: overrides.device_id.clone().or(parsed.device_id),
+        start_time,
+        mode: overrides.mode.or(parsed.mode).unwrap_or(SourceMode::Automatic),
+    };
+
+    Ok(Recording {
+        metadata,
+        samples: parsed.samples,
+    })
+}
+
+
+/// Parses a timestamp in any of the formats output by `TimestampFormatter`. Monotonic timestamps
+/// (seconds since the start) require an origin.
+fn parse_timestamp(value: &str, origin: Option<DateTime<Local>>) -> Result<DateTime<Local>, String> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        let origin = origin
+            .ok_or_else(|| "monotonic timestamps require a start time".to_owned())?;
+        return Ok(origin + Duration::milliseconds((seconds * 1000.0).round() as i64));
+    }
+
+    if let Ok(utc) = DateTime::parse_from_rfc3339(value) {
+        return Ok(utc.with_timezone(&Local));
+    }
+
+    for format in &LOCAL_TIMESTAMP_FORMATS {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(local_from_naive(&naive));
+        }
+    }
+
+    Err(format!("invalid timestamp {:?}", value))
+}
+
+
+/// Parses a pulse or SpO2 value. Empty values are invalid readings, as is 255, which older versions
+/// output in place of invalid readings. Values above `max` are rejected.
+fn parse_value(value: &str, max: u8) -> Result<Option<u8>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let reading: u8 = value.parse()
+        .map_err(|_| format!("invalid value {:?}", value))?;
+    if reading == INVALID_RECORDED_VALUE {
+        Ok(None)
+    } else if reading > max {
+        Err(format!("value {} out of range (at most {})", reading, max))
+    } else {
+        Ok(Some(reading))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::clock::TimestampFormatter;
+    use crate::opts::TimestampMode;
+    use crate::output::{
+        CsvSink, EdfSink, encode_edf, JsonLinesSink, OutputSink, SharedBuffer, write_recording,
+    };
+    use crate::recording::ArtifactFlags;
+
+    fn recording() -> Recording {
+        let start = Local.ymd(2021, 3, 1).and_hms(22, 0, 0);
+        let values = [(Some(61), Some(97)), (None, None), (Some(63), Some(95))];
+        let samples = values.iter().enumerate()
+            .map(|(i, (pulse, spo2))| Sample {
+                timestamp: start + Duration::seconds(i as i64),
+                pulse: *pulse,
+                spo2: *spo2,
+                artifacts: ArtifactFlags {
+                    flatline: i == 2,
+                    ..ArtifactFlags::default()
+                },
+            })
+            .collect();
+        Recording {
+            metadata: RecordingMetadata {
+                device_id: Some("ABC".to_owned()),
+                start_time: start,
+                mode: SourceMode::Manual,
+            },
+            samples,
+        }
+    }
+
+    fn output(recording: &Recording, make_sink: fn(Box<dyn std::io::Write>, TimestampFormatter) -> Box<dyn OutputSink>) -> String {
+        let buffer = SharedBuffer::default();
+        let formatter = TimestampFormatter::new(TimestampMode::Local, recording.metadata.start_time, false);
+        let mut sink = make_sink(Box::new(buffer.clone()), formatter);
+        write_recording(sink.as_mut(), recording).unwrap();
+        buffer.text()
+    }
+
+    fn csv_sink(writer: Box<dyn std::io::Write>, formatter: TimestampFormatter) -> Box<dyn OutputSink> {
+        Box::new(CsvSink::new(writer, formatter))
+    }
+
+    fn jsonl_sink(writer: Box<dyn std::io::Write>, formatter: TimestampFormatter) -> Box<dyn OutputSink> {
+        Box::new(JsonLinesSink::new(writer, formatter))
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let original = recording();
+        let csv = output(&original, csv_sink);
+        assert_eq!(detect_format(&csv), Some(InputFormat::Csv));
+
+        let parsed = parse_recording(InputFormat::Csv, &csv, &MetadataOverrides::default()).unwrap();
+        assert_eq!(parsed, original);
+        assert_eq!(output(&parsed, csv_sink), csv);
+    }
+
+    #[test]
+    fn jsonl_round_trip() {
+        let original = recording();
+        let jsonl = output(&original, jsonl_sink);
+        assert_eq!(detect_format(&jsonl), Some(InputFormat::Jsonl));
+
+        let parsed = parse_recording(InputFormat::Jsonl, &jsonl, &MetadataOverrides::default()).unwrap();
+        assert_eq!(parsed, original);
+        assert_eq!(output(&parsed, jsonl_sink), jsonl);
+    }
+
+    #[test]
+    fn csv_to_jsonl() {
+        let original = recording();
+        let csv = output(&original, csv_sink);
+        let parsed = parse_recording(InputFormat::Csv, &csv, &MetadataOverrides::default()).unwrap();
+        let jsonl = output(&parsed, jsonl_sink);
+        let reparsed = parse_recording(InputFormat::Jsonl, &jsonl, &MetadataOverrides::default()).unwrap();
+        assert_eq!(reparsed.samples, original.samples);
+    }
+
+    #[test]
+    fn csv_to_edf() {
+        let original = recording();
+        let csv = output(&original, csv_sink);
+        let parsed = parse_recording(InputFormat::Csv, &csv, &MetadataOverrides::default()).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut sink = EdfSink::new(Box::new(buffer.clone()));
+        write_recording(&mut sink, &parsed).unwrap();
+        assert_eq!(buffer.bytes(), encode_edf(&original.metadata, &original.samples, &[], &[]));
+    }
+
+    #[test]
+    fn baseline_live_data_rows() {
+        // the first versions of live-data wrote a CSV header, then separated the values by spaces
+        let csv = "timestamp,pulse,spo2\n2021-03-01 22:00:00 127 127\n2021-03-01 22:00:01 62 96\n2021-03-01 22:00:02 127 95\n";
+        assert_eq!(detect_format(csv), Some(InputFormat::Csv));
+
+        let parsed = parse_recording(InputFormat::Csv, csv, &MetadataOverrides::default()).unwrap();
+        let values: Vec<(Option<u8>, Option<u8>)> = parsed.samples.iter()
+            .map(|s| (s.pulse, s.spo2))
+            .collect();
+        assert_eq!(values, vec![(None, None), (Some(62), Some(96)), (Some(127), Some(95))]);
+        assert_eq!(parsed.samples[1].timestamp, Local.ymd(2021, 3, 1).and_hms(22, 0, 1));
+
+        let error = parse_recording(InputFormat::Csv, "timestamp,pulse,spo2\n2021-03-01 22:00:00 62 101\n", &MetadataOverrides::default()).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn baseline_csv_invalid_values() {
+        // the format output by the first versions, with 255 in place of invalid readings
+        let csv = "timestamp,pulse,spo2\n2021-03-01 22:00:00,255,255\n2021-03-01 22:00:01,62,96\n";
+        assert_eq!(detect_format(csv), Some(InputFormat::Csv));
+
+        let parsed = parse_recording(InputFormat::Csv, csv, &MetadataOverrides::default()).unwrap();
+        assert_eq!(parsed.samples.len(), 2);
+        assert_eq!((parsed.samples[0].pulse, parsed.samples[0].spo2), (None, None));
+        assert_eq!((parsed.samples[1].pulse, parsed.samples[1].spo2), (Some(62), Some(96)));
+    }
+
+    #[test]
+    fn impossible_spo2_is_rejected() {
+        let csv = "timestamp,pulse,spo2\n2021-03-01 22:00:00,62,96\n2021-03-01 22:00:01,62,101\n";
+        let error = parse_recording(InputFormat::Csv, csv, &MetadataOverrides::default()).unwrap_err();
+        assert_eq!(error.line, 3);
+    }
+
+    #[test]
+    fn value_parsing() {
+        assert_eq!(parse_value("", MAX_SPO2), Ok(None));
+        assert_eq!(parse_value("255", MAX_SPO2), Ok(None));
+        assert_eq!(parse_value("255", u8::MAX), Ok(None));
+        assert_eq!(parse_value("100", MAX_SPO2), Ok(Some(100)));
+        assert_eq!(parse_value("0", MAX_SPO2), Ok(Some(0)));
+        assert_eq!(parse_value("254", u8::MAX), Ok(Some(254)));
+        assert!(parse_value("101", MAX_SPO2).is_err());
+        assert!(parse_value("256", u8::MAX).is_err());
+        assert!(parse_value("-1", u8::MAX).is_err());
+        assert!(parse_value("abc", u8::MAX).is_err());
+    }
+
+    #[test]
+    fn format_detection() {
+        assert_eq!(detect_format("{\"type\":\"metadata\"}\n"), Some(InputFormat::Jsonl));
+        assert_eq!(detect_format("\u{FEFF}timestamp,pulse,spo2\n"), Some(InputFormat::Csv));
+        assert_eq!(detect_format("timestamp,pulse,spo2,artifacts\r\n"), Some(InputFormat::Csv));
+        assert_eq!(detect_format("# device_id: ABC\n# mode: live\ntimestamp,pulse,spo2,artifacts\n"), Some(InputFormat::Csv));
+        assert_eq!(detect_format("Date, Time, SpO2(%), PR(bpm)\r\n"), Some(InputFormat::Spo2Assistant));
+        assert_eq!(detect_format("something else\n"), None);
+        assert_eq!(detect_format("Hello, world\n"), None);
+        assert_eq!(detect_format("\"Name, first\",\"Name, last\"\n"), None);
+        assert_eq!(detect_format(""), None);
+    }
+
+    #[test]
+    fn csv_metadata_with_monotonic_timestamps() {
+        let original = recording();
+        let buffer = SharedBuffer::default();
+        let formatter = TimestampFormatter::new(TimestampMode::Monotonic, original.metadata.start_time, false);
+        let mut sink = CsvSink::new(Box::new(buffer.clone()), formatter);
+        write_recording(&mut sink, &original).unwrap();
+        let csv = buffer.text();
+        assert!(csv.starts_with("# device_id: ABC\n# start_time: 2021-03-01T22:00:00"));
+        assert!(csv.contains("\n# mode: manual\ntimestamp,pulse,spo2,artifacts\n"));
+
+        // the start time in the metadata is the origin of the monotonic timestamps
+        let parsed = parse_recording(InputFormat::Csv, &csv, &MetadataOverrides::default()).unwrap();
+        assert_eq!(parsed, original);
+    }
+
+    #[test]
+    fn monotonic_timestamps_need_origin() {
+        let csv = "timestamp,pulse,spo2\n0,62,96\n1.5,63,97\n";
+        assert!(parse_recording(InputFormat::Csv, csv, &MetadataOverrides::default()).is_err());
+
+        let start = Local.ymd(2021, 3, 1).and_hms(22, 0, 0);
+        let overrides = MetadataOverrides {
+            start_time: Some(start),
+            ..MetadataOverrides::default()
+        };
+        let parsed = parse_recording(InputFormat::Csv, csv, &overrides).unwrap();
+        assert_eq!(parsed.samples[1].timestamp, start + Duration::milliseconds(1500));
+    }
+}
@@ -0,0 +1,138 @@
+use chrono::{NaiveDate, NaiveTime};
+
+use crate::input::{MAX_SPO2, ParsedInput, ParseError};
+use crate::output::SPO2_ASSISTANT_HEADER;
+use crate::recording::{ArtifactFlags, local_from_naive, Sample};
+
+
+/// The date formats that SpO2 Assistant may use, depending on the regional settings.
+const DATE_FORMATS: [&str; 3] = ["%Y-%m-%d", "%Y/%m/%d", "%d.%m.%Y"];
+
+/// The value written by SpO2 Assistant in place of an invalid reading.
+const INVALID_VALUE: u8 = 0x7F;
+
+
+/// Whether the line is the header row written by `Spo2AssistantSink`, ignoring case and the spaces
+/// around the column names.
+pub(super) fn is_header(line: &str) -> bool {
+    let column_names = |l: &str| -> Vec<String> {
+        l.split(',').map(|c| c.trim().to_lowercase()).collect()
+    };
+    column_names(line) == column_names(SPO2_ASSISTANT_HEADER)
+}
+
+
+fn parse_reading(value: &str) -> Result<u8, String> {
+    value.parse()
+        .map_err(|_| format!("invalid value {:?}", value))
+}
+
+
+/// Parses a CSV file exported by SpO2 Assistant (or output by `Spo2AssistantSink`).
+///
+/// The layout is assumed to be the one written by `Spo2AssistantSink` (date, time, SpO2, pulse),
+/// which has not been verified against an actual export; see there.
+pub(super) fn parse(text: &str) -> Result<ParsedInput, ParseError> {
+    let mut samples = Vec::new();
+
+    // skip the header row
+    for (index, line) in text.lines().enumerate().skip(1) {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() < 4 {
+            return Err(ParseError::new(line_number, "too few columns"));
+        }
+
+        let date = DATE_FORMATS.iter()
+            .find_map(|f| NaiveDate::parse_from_str(fields[0], f).ok())
+            .ok_or_else(|| ParseError::new(line_number, format!("invalid date {:?}", fields[0])))?;
+        let time = NaiveTime::parse_from_str(fields[1], "%H:%M:%S")
+            .map_err(|_| ParseError::new(line_number, format!("invalid time {:?}", fields[1])))?;
+        let spo2 = match parse_reading(fields[2]).map_err(|e| ParseError::new(line_number, e))? {
+            INVALID_VALUE => None,
+            s if s > MAX_SPO2 => return Err(ParseError::new(
+                line_number, format!("value {} out of range (at most {})", s, MAX_SPO2),
+            )),
+            s => Some(s),
+        };
+        // 127 is also a possible pulse rate; as the oximeter only ever loses both values at once,
+        // it only stands for an invalid pulse if the SpO2 value is invalid too
+        let pulse = match parse_reading(fields[3]).map_err(|e| ParseError::new(line_number, e))? {
+            INVALID_VALUE if spo2.is_none() => None,
+            p => Some(p),
+        };
+
+        samples.push(Sample {
+            timestamp: local_from_naive(&date.and_time(time)),
+            pulse,
+            spo2,
+            artifacts: ArtifactFlags::default(),
+        });
+    }
+
+    Ok(ParsedInput {
+        samples,
+        ..ParsedInput::default()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Local, TimeZone};
+    use crate::output::{OutputSink, SharedBuffer, Spo2AssistantSink};
+    use crate::recording::{RecordingMetadata, SourceMode};
+
+    #[test]
+    fn reads_own_output() {
+        let start = Local.ymd(2021, 3, 1).and_hms(22, 0, 0);
+        let samples: Vec<Sample> = [(Some(61), Some(97)), (None, None)].iter().enumerate()
+            .map(|(i, (pulse, spo2))| Sample {
+                timestamp: start + Duration::seconds(i as i64),
+                pulse: *pulse,
+                spo2: *spo2,
+                artifacts: ArtifactFlags::default(),
+            })
+            .collect();
+
+        let buffer = SharedBuffer::default();
+        let mut sink = Spo2AssistantSink::new(Box::new(buffer.clone()));
+        sink.begin(&RecordingMetadata { device_id: None, start_time: start, mode: SourceMode::Automatic }).unwrap();
+        for sample in &samples {
+            sink.write_sample(sample).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let text = buffer.text();
+        assert!(text.lines().nth(2).unwrap().ends_with(", 127, 127"));
+        assert_eq!(parse(&text).unwrap().samples, samples);
+    }
+
+    #[test]
+    fn pulse_of_127_is_valid_with_valid_spo2() {
+        let text = "Date, Time, SpO2(%), PR(bpm)\r\n2021-03-01, 22:00:00, 96, 127\r\n2021-03-01, 22:00:01, 127, 127\r\n";
+        let samples = parse(text).unwrap().samples;
+        assert_eq!((samples[0].spo2, samples[0].pulse), (Some(96), Some(127)));
+        assert_eq!((samples[1].spo2, samples[1].pulse), (None, None));
+    }
+
+    #[test]
+    fn impossible_spo2_is_rejected() {
+        let text = "Date, Time, SpO2(%), PR(bpm)\r\n2021-03-01, 22:00:00, 96, 61\r\n2021-03-01, 22:00:01, 101, 61\r\n";
+        assert_eq!(parse(text).unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn header() {
+        assert!(is_header("Date, Time, SpO2(%), PR(bpm)"));
+        assert!(is_header("date,time,spo2(%),pr(bpm)"));
+        assert!(!is_header("Date, Time, PR(bpm), SpO2(%)"));
+        assert!(!is_header("Hello, world"));
+    }
+}
@@ -0,0 +1,324 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{DateTime, Duration, Local};
+
+use crate::clock::{SampleClock, TimestampFormatter};
+use crate::hrv::{BeatTracker, HrvWindow};
+use crate::opts::LiveDataSubcommand;
+use crate::output::OutputSink;
+use crate::oximeter::{is_checksum_ok, LiveCurvePoint, LiveData, LiveValues};
+use crate::recording::{ArtifactFlags, RecordingMetadata, Sample, WaveformPoint};
+
+
+/// The nominal interval between two pulse curve points, in milliseconds.
+const CURVE_PERIOD_MS: i64 = 50;
+
+/// How far the pulse curve timestamps may deviate from the host clock before resynchronizing.
+const CURVE_TOLERANCE_MS: i64 = 500;
+
+/// The nominal interval between two sets of values, in milliseconds.
+const VALUES_PERIOD_MS: i64 = 1000;
+
+/// How far the values timestamps may deviate from the host clock before resynchronizing.
+const VALUES_TOLERANCE_MS: i64 = 1500;
+
+/// How far the values timestamps may deviate from the host clock before resynchronizing if the
+/// values are transferred in batches (streaming without the pulse curve).
+const VALUES_BATCHED_TOLERANCE_MS: i64 = 4000;
+
+
+/// Statistics about the link to the oximeter during a live session.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct LinkStats {
+    /// The number of live data responses (curve points and values) received.
+    pub packets: u64,
+
+    /// The number of samples (sets of values) received.
+    pub samples: u64,
+
+    /// The number of responses that were discarded because their checksum was wrong.
+    pub checksum_failures: u64,
+
+    /// The number of times streaming had to be requested again because the oximeter stalled.
+    pub restarts: u64,
+
+    /// The number of keepalives sent.
+    pub keepalives: u64,
+}
+
+
+/// Processes the responses streamed by the oximeter in live mode and writes out the results.
+pub struct LiveRecorder {
+    rr_writer: Option<BufWriter<File>>,
+    hrv_writer: Option<BufWriter<File>>,
+    beat_tracker: BeatTracker,
+    hrv_window: HrvWindow,
+    hrv_step: Duration,
+    last_hrv_output: Option<DateTime<Local>>,
+    formatter: TimestampFormatter,
+    sink: Box<dyn OutputSink>,
+    curve_clock: SampleClock,
+    values_clock: SampleClock,
+    curve_batch: Vec<LiveCurvePoint>,
+    values_batch: Vec<LiveValues>,
+    link_stats: LinkStats,
+    latest_curve_point: Option<LiveCurvePoint>,
+    latest_values: Option<LiveValues>,
+}
+impl LiveRecorder {
+    pub fn new(live_data: &LiveDataSubcommand, mut sink: Box<dyn OutputSink>, metadata: &RecordingMetadata) -> Self {
+        let rr_writer = live_data.rr_file.as_ref().map(|path| {
+            let mut writer = BufWriter::new(File::create(path).expect("failed to create RR interval file"));
+            writeln!(writer, "timestamp,rr_ms").expect("failed to write RR interval file header");
+            writer
+        });
+        let hrv_writer = live_data.hrv_file.as_ref().map(|path| {
+            let mut writer = BufWriter::new(File::create(path).expect("failed to create HRV file"));
+            writeln!(writer, "timestamp,intervals,mean_rr_ms,sdnn_ms,rmssd_ms").expect("failed to write HRV file header");
+            writer
+        });
+
+        // without the curve, the values are queued up and arrive in batches every few seconds
+        let values_tolerance = if live_data.no_curve {
+            Duration::milliseconds(VALUES_BATCHED_TOLERANCE_MS)
+        } else {
+            Duration::milliseconds(VALUES_TOLERANCE_MS)
+        };
+
+        sink.begin(metadata)
+            .expect("failed to output metadata");
+
+        Self {
+            rr_writer,
+            hrv_writer,
+            beat_tracker: BeatTracker::new(),
+            hrv_window: HrvWindow::new(Duration::seconds(live_data.hrv_window.into())),
+            hrv_step: Duration::seconds(live_data.hrv_step.into()),
+            last_hrv_output: None,
+            formatter: TimestampFormatter::new(live_data.timestamps, metadata.start_time, true),
+            sink,
+            curve_clock: SampleClock::new(
+                Duration::milliseconds(CURVE_PERIOD_MS),
+                Duration::milliseconds(CURVE_TOLERANCE_MS),
+            ),
+            values_clock: SampleClock::new(
+                Duration::milliseconds(VALUES_PERIOD_MS),
+                values_tolerance,
+            ),
+            curve_batch: Vec::new(),
+            values_batch: Vec::new(),
+            link_stats: LinkStats::default(),
+            latest_curve_point: None,
+            latest_values: None,
+        }
+    }
+
+    /// Processes a response received from the oximeter. Responses that are not live data are
+    /// ignored. Returns whether the response was live data.
+    ///
+    /// The decoded data is only output once `flush_batch` is called.
+    pub fn process(&mut self, command: &[u8]) -> bool {
+        if !is_checksum_ok(command) {
+            // ignore it
+            self.link_stats.checksum_failures += 1;
+            return false;
+        }
+
+        match LiveData::from_response(command) {
+            Some(LiveData::Curve(point)) => {
+                self.link_stats.packets += 1;
+                self.latest_curve_point = Some(point);
+                self.curve_batch.push(point);
+            },
+            Some(LiveData::Values(values)) => {
+                self.link_stats.packets += 1;
+                self.link_stats.samples += 1;
+                self.latest_values = Some(values);
+                self.values_batch.push(values);
+            },
+            None => {
+                // not what we're looking for
+                return false;
+            },
+        }
+
+        true
+    }
+
+    /// Timestamps and outputs the data collected since the last call. Call this once all the
+    /// responses from a read from the oximeter have been processed. Returns the samples that have
+    /// been output.
+    pub fn flush_batch(&mut self) -> Vec<Sample> {
+        let now = Local::now();
+        let mut samples = Vec::new();
+
+        if !self.curve_batch.is_empty() {
+            let timestamps = self.curve_clock.stamp_batch(now, self.curve_batch.len());
+            for (timestamp, point) in timestamps.into_iter().zip(self.curve_batch.drain(..)) {
+                let waveform_point = WaveformPoint {
+                    timestamp,
+                    value: point.value,
+                    beat: point.is_beat(),
+                };
+                self.sink.write_waveform(&waveform_point)
+                    .expect("failed to output pulse curve");
+
+                if let Some(interval) = self.beat_tracker.feed(timestamp, point.is_beat()) {
+                    if let Some(writer) = self.rr_writer.as_mut() {
+                        writeln!(writer, "{},{}", self.formatter.format(&timestamp), interval.num_milliseconds())
+                            .expect("failed to write RR interval");
+                    }
+                    self.hrv_window.push(timestamp, interval);
+                }
+
+                if let Some(writer) = self.hrv_writer.as_mut() {
+                    let last_hrv_output = *self.last_hrv_output.get_or_insert(timestamp);
+                    if timestamp - last_hrv_output >= self.hrv_step {
+                        if let Some(metrics) = self.hrv_window.metrics() {
+                            writeln!(
+                                writer, "{},{},{:.1},{:.1},{:.1}",
+                                self.formatter.format(&timestamp), metrics.interval_count,
+                                metrics.mean_rr_ms, metrics.sdnn_ms, metrics.rmssd_ms,
+                            ).expect("failed to write HRV metrics");
+                            writer.flush()
+                                .expect("failed to flush HRV file");
+                        }
+                        self.last_hrv_output = Some(timestamp);
+                    }
+                }
+            }
+        }
+
+        if !self.values_batch.is_empty() {
+            let timestamps = self.values_clock.stamp_batch(now, self.values_batch.len());
+            for (timestamp, values) in timestamps.into_iter().zip(self.values_batch.drain(..)) {
+                if !values.are_valid() {
+                    // finger is out; intervals across the gap would be bogus
+                    self.beat_tracker.reset();
+                    self.hrv_window.clear();
+                }
+
+                // it's the current readings!
+                let sample = if values.are_valid() {
+                    Sample {
+                        timestamp,
+                        pulse: Some(values.pulse),
+                        spo2: Some(values.spo2),
+                        artifacts: ArtifactFlags::default(),
+                    }
+                } else {
+                    Sample {
+                        timestamp,
+                        pulse: None,
+                        spo2: None,
+                        artifacts: ArtifactFlags::default(),
+                    }
+                };
+                self.sink.write_sample(&sample)
+                    .expect("failed to output sample");
+                samples.push(sample);
+            }
+            self.sink.flush()
+                .expect("failed to flush output");
+
+            if let Some(writer) = self.rr_writer.as_mut() {
+                writer.flush()
+                    .expect("failed to flush RR interval file");
+            }
+        }
+
+        samples
+    }
+
+    /// The statistics about the link to the oximeter so far.
+    pub fn link_stats(&self) -> &LinkStats {
+        &self.link_stats
+    }
+
+    /// The most recently received pulse curve point, if any.
+    pub fn latest_curve_point(&self) -> Option<LiveCurvePoint> {
+        self.latest_curve_point
+    }
+
+    /// The most recently received values, if any.
+    pub fn latest_values(&self) -> Option<LiveValues> {
+        self.latest_values
+    }
+
+    /// Notes that streaming has been requested again because the oximeter stalled.
+    pub fn stream_restarted(&mut self) {
+        self.link_stats.restarts += 1;
+    }
+
+    /// Notes that a keepalive has been sent to the oximeter.
+    pub fn keepalive_sent(&mut self) {
+        self.link_stats.keepalives += 1;
+    }
+
+    /// Outputs any pending values and flushes all output files.
+    pub fn finish(mut self) {
+        self.flush_batch();
+        if let Some(writer) = self.rr_writer.as_mut() {
+            writer.flush()
+                .expect("failed to flush RR interval file");
+        }
+        if let Some(writer) = self.hrv_writer.as_mut() {
+            writer.flush()
+                .expect("failed to flush HRV file");
+        }
+        self.sink.finish()
+            .expect("failed to finish output");
+    }
+}
+
+
+/// Decides when to send keepalives to the oximeter and when to assume that it has stopped
+/// streaming.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct StreamScheduler {
+    keepalive_interval: StdDuration,
+    stall_timeout: StdDuration,
+    last_keepalive: Instant,
+    last_data: Instant,
+}
+impl StreamScheduler {
+    pub fn new(keepalive_interval: StdDuration, stall_timeout: StdDuration) -> Self {
+        let now = Instant::now();
+        Self {
+            keepalive_interval,
+            stall_timeout,
+            last_keepalive: now,
+            last_data: now,
+        }
+    }
+
+    /// Notes that live data has just been received.
+    pub fn data_received(&mut self) {
+        self.last_data = Instant::now();
+    }
+
+    /// Whether it is time for the next keepalive.
+    pub fn is_keepalive_due(&self) -> bool {
+        self.last_keepalive.elapsed() >= self.keepalive_interval
+    }
+
+    /// Notes that a keepalive has just been sent.
+    pub fn keepalive_sent(&mut self) {
+        self.last_keepalive = Instant::now();
+    }
+
+    /// Whether no live data has been received for longer than the stall timeout.
+    pub fn is_stalled(&self) -> bool {
+        self.last_data.elapsed() >= self.stall_timeout
+    }
+
+    /// Notes that streaming has just been requested again. The oximeter is given another stall
+    /// timeout to comply before it is considered stalled again.
+    pub fn restarted(&mut self) {
+        let now = Instant::now();
+        self.last_data = now;
+        self.last_keepalive = now;
+    }
+}
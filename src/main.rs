@@ -1,302 +1,390 @@
+mod alarm;
+mod analysis;
+mod archive;
+mod clock;
+mod dashboard;
+mod files;
+mod hrv;
+mod input;
+mod live;
+mod metrics;
+mod mqtt;
 mod opts;
+mod output;
 mod oximeter;
+mod recording;
+mod server;
 
 
-use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration as StdDuration;
 
 use clap::Clap;
-use chrono::{Duration, NaiveDate, Local};
+use chrono::{Duration, Local};
 use hidapi::{HidApi, HidDevice};
-use log::{self, debug, log_enabled};
+use log::debug;
 use oximeter::RecordingMode;
 
-use crate::opts::{Opts, Subcommand};
+use crate::alarm::{AlarmMonitor, AlarmNotifier};
+use crate::analysis::{
+    Analysis, ArtifactParams, DesaturationParams, filter_artifacts, render_html_report, report_json,
+    write_events_csv, write_text_report,
+};
+use crate::archive::Archive;
+use crate::clock::TimestampFormatter;
+use crate::dashboard::Dashboard;
+use crate::files::{
+    count_auto_files, delete_auto_files, download_file, FileHeader, list_files,
+    read_file_store_info, read_recording_mode,
+};
+use crate::input::{detect_format, MetadataOverrides, parse_recording};
+use crate::live::{LiveRecorder, StreamScheduler};
+use crate::metrics::MetricsServer;
+use crate::mqtt::{device_info, MqttPublisher};
+use crate::opts::{
+    AnalyzeSubcommand, ArchiveAction, ArchiveSubcommand, ArtifactOptions, ConvertSubcommand,
+    DeleteFilesSubcommand, DesaturationOptions, InfluxOptions, InputOptions, LiveDataSubcommand, Opts,
+    OutputFormat, ReadFileSubcommand, ReportFormat, ServeSubcommand, Subcommand, SyncSubcommand,
+};
+use crate::output::{
+    create_sink, EdfAnnotation, encode_edf, file_extension, InfluxSink, max_file_samples,
+    OutputSink, RotatingSink, TeeSink, write_recording,
+};
 use crate::oximeter::{
     calculate_checksum, CommandCode, CommandQueue, INIT_BYTESTRING, is_checksum_ok,
-    PropertyCode, receive_from_oximeter, send_to_oximeter,
+    PropertyCode, receive_from_oximeter, receive_from_oximeter_timeout, send_to_oximeter,
 };
+use crate::recording::{Recording, RecordingMetadata, SourceMode};
+use crate::server::LiveServer;
+
+
+/// How long a single read in live mode may block, so that a stop request is noticed in time.
+const LIVE_READ_TIMEOUT_MS: i32 = 250;
+
+/// How long to wait for the oximeter to acknowledge that it has stopped streaming.
+const STOP_ACK_TIMEOUT_MS: i64 = 2000;
 
+/// How often the file in the output directory is synced to disk if not specified, in seconds.
+const DEFAULT_SYNC_INTERVAL_SECS: u64 = 5;
+
+/// How long a single read may block while waiting for the device name or version information.
+const DEVICE_INFO_READ_TIMEOUT_MS: i32 = 250;
+
+/// How many times to try reading the device name or version information before giving up.
+const DEVICE_INFO_READ_ATTEMPTS: usize = 8;
+
+/// How long to give the oximeter to delete its files before checking whether they are gone.
+const DELETE_SETTLE_TIME_MS: u64 = 1000;
+
+
+fn handle_live(oxdev: &HidDevice, queue: &mut CommandQueue, live_data: &LiveDataSubcommand, extra_sinks: Vec<Box<dyn OutputSink>>) {
+    // only the output directory can continue in a new file once one is full
+    let writes_to_stdout = !live_data.tui && live_data.output_dir.is_none() && live_data.influx.url.is_none();
+    if writes_to_stdout {
+        if let Some(max_samples) = max_file_samples(live_data.format) {
+            eprintln!(
+                "a file in this format holds at most {} seconds of samples; pass --output-dir to start a new file when one is full",
+                max_samples,
+            );
+            process::exit(1);
+        }
+    }
+
+    // stop cleanly on Ctrl+C; a second Ctrl+C aborts if the oximeter does not cooperate
+    let stop_requested = Arc::new(AtomicBool::new(false));
+    {
+        let stop_requested = Arc::clone(&stop_requested);
+        ctrlc::set_handler(move || {
+            if stop_requested.swap(true, Ordering::SeqCst) {
+                process::exit(130);
+            }
+        })
+            .expect("failed to set Ctrl+C handler");
+    }
+    let deadline = live_data.duration
+        .map(|secs| Local::now() + Duration::seconds(secs.into()));
+
+    let metadata = RecordingMetadata {
+        device_id: Some(read_device_id(oxdev, queue)),
+        start_time: Local::now(),
+        mode: SourceMode::Live,
+    };
+    let metrics = live_data.metrics_listen.as_ref().map(|address| {
+        let metrics = MetricsServer::new(metadata.device_id.clone());
+        let local_address = metrics.listen(address)
+            .expect("failed to listen for metrics requests");
+        eprintln!("serving metrics on http://{}/metrics", local_address);
+        metrics
+    });
+
+    let mut mqtt = live_data.mqtt.broker.as_ref().map(|_| {
+        let device_id = metadata.device_id.as_deref().unwrap_or("");
+        let device_name = read_device_name(oxdev, queue);
+        let version_info = read_version_info(oxdev, queue);
+        let info = device_info(
+            device_id, device_name.as_deref(), version_info.as_deref(),
+            read_recording_mode(oxdev, queue), metadata.start_time,
+        );
+        let publisher = MqttPublisher::connect(&live_data.mqtt, device_id, info);
+        publisher.publish_status("streaming", metadata.start_time);
+        publisher
+    });
+
+    let mut dashboard = if live_data.tui {
+        Some(Dashboard::new(live_data.format, live_data.timestamps))
+    } else {
+        None
+    };
+    let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+    if let Some(dashboard) = dashboard.as_ref() {
+        sinks.push(Box::new(dashboard.sink()));
+    }
+    if let Some(output_dir) = live_data.output_dir.as_ref() {
+        fs::create_dir_all(output_dir)
+            .expect("failed to create output directory");
+        sinks.push(Box::new(RotatingSink::new(
+            output_dir,
+            live_data.format,
+            live_data.timestamps,
+            live_data.rotate_size.map(|megabytes| megabytes * 1_000_000),
+            live_data.rotate_interval.map(StdDuration::from_secs),
+            StdDuration::from_secs(live_data.sync_interval.unwrap_or(DEFAULT_SYNC_INTERVAL_SECS)),
+        )));
+    }
+    if let Some(url) = live_data.influx.url.as_deref() {
+        sinks.push(Box::new(InfluxSink::http(url, live_data.influx.token.clone(), live_data.influx.batch_size)));
+    }
+    if sinks.is_empty() {
+        let formatter = TimestampFormatter::new(live_data.timestamps, metadata.start_time, true);
+        sinks.push(create_sink(live_data.format, Box::new(io::stdout()), formatter));
+    }
+    sinks.extend(extra_sinks);
+    let mut recorder = LiveRecorder::new(live_data, Box::new(TeeSink::new(sinks)), &metadata);
+    let mut alarm_monitor = AlarmMonitor::new(&live_data.alarms, metadata.start_time);
+    let mut alarm_notifier = AlarmNotifier::new(&live_data.alarms, metadata.device_id.clone());
 
-fn handle_live(oxdev: &HidDevice, mut queue: &mut CommandQueue) {
     // enable data streaming
-    let mut enable_streaming = Vec::with_capacity(3);
-    enable_streaming.push(CommandCode::LiveDataCommand.into());
-    enable_streaming.push(0x00); // also stream curve (ensures that the values arrive on time)
+    let mut enable_streaming = vec![
+        CommandCode::LiveDataCommand.into(),
+        if live_data.no_curve {
+            0x01 // values only, batched
+        } else {
+            0x00 // also stream curve (ensures that the values arrive on time)
+        },
+    ];
     enable_streaming.push(calculate_checksum(&enable_streaming));
-    send_to_oximeter(&oxdev, &enable_streaming)
+    send_to_oximeter(oxdev, &enable_streaming)
         .expect("failed to enable streaming on oximeter");
 
-    println!("timestamp,pulse,spo2");
+    let mut keepalive = vec![CommandCode::KeepAliveCommand.into()];
+    keepalive.push(calculate_checksum(&keepalive));
 
     // read, read, read
-    let mut keepalive_counter: usize = 0;
+    let mut scheduler = StreamScheduler::new(
+        StdDuration::from_secs(live_data.keepalive_interval.into()),
+        StdDuration::from_secs(live_data.stall_timeout.into()),
+    );
     loop {
-        receive_from_oximeter(&oxdev, &mut queue)
-            .expect("failed to receive live data");
-
-        while let Some(command) = queue.dequeue_command() {
-            if is_checksum_ok(&command) {
-                // ignore it
-                continue;
+        if stop_requested.load(Ordering::SeqCst) {
+            break;
+        }
+        if deadline.map(|dl| Local::now() >= dl).unwrap_or(false) {
+            break;
+        }
+        if let Some(dashboard) = dashboard.as_mut() {
+            if dashboard.handle_input() {
+                break;
             }
+            dashboard.draw_if_due(recorder.link_stats());
+        }
 
-            if command.len() < 2 {
-                // too short for our purposes
-                continue;
+        let mut alarm_events = Vec::new();
+        let received = receive_from_oximeter_timeout(oxdev, queue, LIVE_READ_TIMEOUT_MS)
+            .expect("failed to receive live data");
+        if received {
+            let mut live_data_received = false;
+            while let Some(command) = queue.dequeue_command() {
+                if recorder.process(&command) {
+                    live_data_received = true;
+                }
             }
-            if command[0] != CommandCode::LiveDataResponse.into() {
-                // not what we're looking for
-                continue;
+            let samples = recorder.flush_batch();
+            if let Some(mqtt) = mqtt.as_mut() {
+                mqtt.publish_samples(&samples);
             }
-            if command[1] != 0x01 {
-                // not the current readings
-                continue;
+            if let Some(monitor) = alarm_monitor.as_mut() {
+                for sample in &samples {
+                    alarm_events.extend(monitor.feed(sample));
+                }
             }
-            if command.len() < 8 {
-                // not the correct length for current readings
-                continue;
+
+            if live_data_received {
+                scheduler.data_received();
             }
+        }
 
-            // it's the current readings!
-            let timestamp = Local::now();
-            let pulse = command[3];
-            let spo2 = command[4];
-            println!("{} {} {}", timestamp.format("%Y-%m-%d %H:%M:%S"), pulse, spo2);
+        if let Some(metrics) = metrics.as_ref() {
+            metrics.update(recorder.link_stats(), recorder.latest_values(), recorder.latest_curve_point());
         }
 
-        // send a keepalive every 8 messages
-        keepalive_counter += 1;
-        if keepalive_counter == 8 {
-            let mut keepalive = Vec::with_capacity(2);
-            keepalive.push(CommandCode::KeepAliveCommand.into());
-            keepalive.push(calculate_checksum(&keepalive));
-            send_to_oximeter(&oxdev, &enable_streaming)
-                .expect("failed to send keepalive to oximeter");
+        if let Some(monitor) = alarm_monitor.as_mut() {
+            alarm_events.extend(monitor.check(Local::now()));
+        }
+        for event in &alarm_events {
+            let line = alarm_notifier.notify(event);
+            if let Some(mqtt) = mqtt.as_ref() {
+                mqtt.publish_alarm(event);
+            }
+            match dashboard.as_mut() {
+                Some(dashboard) => dashboard.notice(line),
+                None => if !alarm_notifier.has_log_file() {
+                    eprintln!("{}", line);
+                },
+            }
+        }
 
-            keepalive_counter = 0;
+        if scheduler.is_stalled() {
+            let message = "oximeter has stopped streaming; restarting live data";
+            match dashboard.as_mut() {
+                Some(dashboard) => dashboard.notice(message.to_owned()),
+                None => eprintln!("{}", message),
+            }
+            send_to_oximeter(oxdev, &enable_streaming)
+                .expect("failed to re-enable streaming on oximeter");
+            scheduler.restarted();
+            recorder.stream_restarted();
+            if let Some(mqtt) = mqtt.as_ref() {
+                mqtt.publish_status("restarting", Local::now());
+            }
+        } else if scheduler.is_keepalive_due() {
+            send_to_oximeter(oxdev, &keepalive)
+                .expect("failed to send keepalive to oximeter");
+            scheduler.keepalive_sent();
+            recorder.keepalive_sent();
         }
     };
-}
-
-fn handle_read_auto(oxdev: &HidDevice, mut queue: &mut CommandQueue, file_index: usize) {
-    {
-        let mut count_command = Vec::with_capacity(3);
-        count_command.push(CommandCode::GetAuxiliaryDataCommand.into());
-        count_command.push(PropertyCode::AutoRecordedFiles.into());
-        count_command.push(calculate_checksum(&count_command));
-        send_to_oximeter(&oxdev, &count_command)
-            .expect("failed to send count request");
-    }
 
-    receive_from_oximeter(&oxdev, &mut queue)
-        .expect("failed to receive response to file count request");
-    let mut pulse_count: usize = 0;
-    let mut spo2_count: usize = 0;
-    while let Some(response) = queue.dequeue_command() {
-        if !is_checksum_ok(&response) {
-            continue;
-        }
-        if response[0] != CommandCode::GetAuxiliaryDataResponse.into() {
+    // stop streaming, otherwise the oximeter keeps going until the next session
+    let mut stop_streaming = vec![CommandCode::LiveDataCommand.into(), 0x7F];
+    stop_streaming.push(calculate_checksum(&stop_streaming));
+    send_to_oximeter(oxdev, &stop_streaming)
+        .expect("failed to stop streaming on oximeter");
+
+    // drain whatever is still underway until the oximeter acknowledges
+    let stop_deadline = Local::now() + Duration::milliseconds(STOP_ACK_TIMEOUT_MS);
+    'draining: while Local::now() < stop_deadline {
+        let received = receive_from_oximeter_timeout(oxdev, queue, LIVE_READ_TIMEOUT_MS)
+            .expect("failed to receive remaining live data");
+        if !received {
             continue;
         }
 
-        // topmost bit is not set, so shift up by 7
-        pulse_count = (response[2] as usize) | ((response[3] as usize) << 7);
-        spo2_count = (response[4] as usize) | ((response[5] as usize) << 7);
-
-        break;
+        while let Some(command) = queue.dequeue_command() {
+            let is_stop_ack = is_checksum_ok(&command)
+                && command.len() >= 2
+                && command[0] == u8::from(CommandCode::LiveDataResponse)
+                && command[1] == 0x7F;
+            if is_stop_ack {
+                break 'draining;
+            }
+            recorder.process(&command);
+        }
+        recorder.flush_batch();
     }
 
-    let file_count = pulse_count.min(spo2_count);
-
-    if file_count == 0 {
-        eprintln!("auto recording mode active and no files recorded");
-        return;
+    recorder.finish();
+    if let Some(mqtt) = mqtt {
+        mqtt.finish();
     }
+}
 
-    if file_index > file_count {
-        eprintln!("auto recording mode active, no file {} available (max {})", file_index, file_count);
-        return;
-    }
+fn handle_serve(oxdev: &HidDevice, queue: &mut CommandQueue, serve: &ServeSubcommand) {
+    let server = LiveServer::new();
+    let tcp_address = server.listen_tcp(&serve.tcp)
+        .expect("failed to listen for TCP clients");
+    let websocket_address = server.listen_websocket(&serve.websocket)
+        .expect("failed to listen for WebSocket clients");
+    eprintln!("serving live data via TCP on {} and via WebSocket on {}", tcp_address, websocket_address);
 
-    // ask for metadata
-    for i in 1..=file_count {
-        {
-            let mut advance_and_show_command = Vec::with_capacity(3);
-            advance_and_show_command.push(CommandCode::AdvanceAndShowAutoRecordedFileHeaderCommand.into());
-            advance_and_show_command.push(0x01); // advance by 1
-            advance_and_show_command.push(calculate_checksum(&advance_and_show_command));
-            send_to_oximeter(&oxdev, &advance_and_show_command)
-                .expect("failed to send advance-and-show request");
-        }
+    let sink = server.sink(serve.live.timestamps);
+    handle_live(oxdev, queue, &serve.live, vec![Box::new(sink)]);
+}
 
-        receive_from_oximeter(&oxdev, &mut queue)
-            .expect("failed to receive response to advance-and-show request");
+fn read_device_id(oxdev: &HidDevice, queue: &mut CommandQueue) -> String {
+    let mut device_id_command = vec![CommandCode::ReadPropertyCommand.into(), PropertyCode::DeviceId.into()];
+    device_id_command.push(calculate_checksum(&device_id_command));
+    send_to_oximeter(oxdev, &device_id_command)
+        .expect("failed to send device ID request");
+
+    loop {
+        receive_from_oximeter(oxdev, queue)
+            .expect("failed to receive response to device ID request");
         while let Some(response) = queue.dequeue_command() {
             if !is_checksum_ok(&response) {
                 continue;
             }
-            if response[0] != CommandCode::AdvanceAndShowAutoRecordedFileHeaderResponse.into() {
+            if response.len() < 3 {
                 continue;
             }
-
-            let start_time = NaiveDate::from_ymd(
-                (response[4] as i32) + 2000,
-                response[5] as u32,
-                response[6] as u32,
-            ).and_hms(
-                response[7] as u32,
-                response[8] as u32,
-                response[9] as u32
-            );
-            let this_file_length =
-                (response[10] as usize)
-                | ((response[11] as usize) << 7)
-                | ((response[12] as usize) << 14)
-            ;
-
-            if i == file_index {
-                // alright then, read the file!
-                let mut mode_to_values: HashMap<u8, Vec<u8>> = HashMap::new();
-                for mode in &[1, 2] {
-                    let mut values = Vec::new();
-                    let mut base_value = 0;
-                    let mut base_value_top_nibble = false;
-
-                    {
-                        let mut get_file_command = Vec::with_capacity(9);
-                        get_file_command.push(CommandCode::ReadAutoRecordedFileCommand.into());
-                        get_file_command.push(0x04); // unknown constant
-                        get_file_command.push(*mode);
-                        get_file_command.push(0x01); // unknown constant
-                        get_file_command.push(i.try_into().expect("file number too large"));
-                        get_file_command.push(0x00);
-                        get_file_command.push(0x00);
-                        get_file_command.push(0x00);
-                        get_file_command.push(calculate_checksum(&get_file_command));
-                        send_to_oximeter(&oxdev, &get_file_command)
-                            .expect("failed to send read-auto-file request");
-                    }
-
-                    while values.len() < this_file_length {
-                        // we have more data to fetch
-                        receive_from_oximeter(&oxdev, &mut queue)
-                            .expect("failed to receive response to read-auto-file request");
-                        while let Some(response) = queue.dequeue_command() {
-                            if !is_checksum_ok(&response) {
-                                continue;
-                            }
-                            if response[0] != CommandCode::ReadAutoRecordedFileResponse.into() {
-                                continue;
-                            }
-                            if response.len() != 30 {
-                                continue;
-                            }
-
-                            // again, since the top bit may not be set except at the beginning of a command,
-                            // the sign bits for the top nibbles have been moved to the front
-                            let sign_bits
-                                = (response[5] as u32)
-                                | ((response[6] as u32) << 7)
-                                | ((response[7] as u32) << 14)
-                                ;
-
-                            let mut debug_all_bytes = Vec::new();
-                            for (j, b) in response[8..29].iter().enumerate() {
-                                // top nibble needs the additional bit from the sign_bits
-                                let mut top_nibble = (*b >> 4) & 0x0F;
-                                if sign_bits & (1 << j) != 0 {
-                                    top_nibble |= 0b1000;
-                                }
-
-                                // bottom nibble does not
-                                let bottom_nibble = (*b >> 0) & 0x0F;
-
-                                debug_all_bytes.push(top_nibble << 4 | bottom_nibble);
-
-                                if top_nibble == 0x0F {
-                                    if bottom_nibble == 0x0F && !base_value_top_nibble {
-                                        // invalid value
-                                        // (unless we are waiting for the bottom nibble of the new base value)
-                                        values.push(0xFF);
-                                        values.push(0xFF);
-                                        continue;
-                                    }
-
-                                    // we are changing the base value!
-                                    if base_value_top_nibble {
-                                        base_value |= bottom_nibble;
-                                        base_value_top_nibble = false;
-                                    } else {
-                                        base_value = bottom_nibble << 4;
-                                        base_value_top_nibble = true;
-                                    }
-
-                                    // note that this does not generate a value
-                                } else {
-                                    // the nibbles are (downward) deltas from the current base value
-                                    values.push(base_value - top_nibble);
-                                    if bottom_nibble != 0x0F {
-                                        // 0x0F is invalid
-                                        values.push(base_value - bottom_nibble);
-                                    }
-
-                                    if values.len() == this_file_length {
-                                        // we are done
-                                        break;
-                                    }
-                                }
-                            }
-
-                            if log_enabled!(log::Level::Debug) {
-                                let bstrs: Vec<String> = debug_all_bytes.iter()
-                                    .map(|b| format!("{:02x}", b))
-                                    .collect();
-                                debug!("DATA IS {}", bstrs.join(" "));
-                            }
-                        }
-                    }
-
-                    // and we're done
-                    mode_to_values.insert(*mode, values);
-                }
-
-                // zip the values together and output them
-                let spo2_values = &mode_to_values[&1];
-                let pulse_values = &mode_to_values[&2];
-                let mut cur_time = start_time;
-                println!("timestamp,pulse,spo2");
-                for (spo2, pulse) in spo2_values.iter().zip(pulse_values.iter()) {
-                    println!("{},{},{}", cur_time.format("%Y-%m-%d %H:%M:%S"), *pulse, *spo2);
-                    cur_time += Duration::seconds(1);
-                }
+            if response[0] != u8::from(CommandCode::ReadPropertyResponse) {
+                continue;
+            }
+            if response[1] != u8::from(PropertyCode::DeviceId) {
+                continue;
             }
 
-            break;
+            // the device ID is right-padded with spaces (and possibly NUL bytes)
+            let device_id_bytes = &response[2..response.len()-1];
+            return String::from_utf8_lossy(device_id_bytes)
+                .trim_matches(|c| c == ' ' || c == '\0')
+                .to_owned();
         }
     }
 }
 
-fn handle_read_manual(oxdev: &HidDevice, mut queue: &mut CommandQueue, file_index: usize) {
-    if file_index != 1 {
-        eprintln!("manual recording mode active, file index must be 1");
-        return;
-    }
+/// Asks the oximeter for its device name. Returns `None` if the oximeter does not answer in time.
+fn read_device_name(oxdev: &HidDevice, queue: &mut CommandQueue) -> Option<String> {
+    let payload = query_oximeter(oxdev, queue, CommandCode::GetDeviceNameCommand, CommandCode::GetDeviceNameResponse)?;
+
+    // like the device ID, the name is padded with spaces (and possibly NUL bytes)
+    Some(
+        String::from_utf8_lossy(&payload)
+            .trim_matches(|c| c == ' ' || c == '\0')
+            .to_owned()
+    )
+}
+
+/// Asks the oximeter for its version information. Since the meaning of the individual bytes is
+/// unknown, they are returned as hex digits. Returns `None` if the oximeter does not answer in time.
+fn read_version_info(oxdev: &HidDevice, queue: &mut CommandQueue) -> Option<String> {
+    let payload = query_oximeter(oxdev, queue, CommandCode::GetVersionInfoCommand, CommandCode::GetVersionInfoResponse)?;
+    let byte_strs: Vec<String> = payload.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Some(byte_strs.join(" "))
+}
+
+/// Sends a command without arguments and returns the payload of the response (without the
+/// command code and checksum), or `None` if the oximeter does not answer in time.
+fn query_oximeter(oxdev: &HidDevice, queue: &mut CommandQueue, command: CommandCode, response_code: CommandCode) -> Option<Vec<u8>> {
+    let mut query_command = vec![command.into()];
+    query_command.push(calculate_checksum(&query_command));
+    send_to_oximeter(oxdev, &query_command)
+        .expect("failed to send device information request");
+
+    for _ in 0..DEVICE_INFO_READ_ATTEMPTS {
+        let received = receive_from_oximeter_timeout(oxdev, queue, DEVICE_INFO_READ_TIMEOUT_MS)
+            .expect("failed to receive response to device information request");
+        if !received {
+            continue;
+        }
 
-    {
-        let mut metadata_command = Vec::with_capacity(3);
-        metadata_command.push(CommandCode::ManuallyRecordedFileMetadataCommand.into());
-        metadata_command.push(0x00); // there is only one file
-        metadata_command.push(calculate_checksum(&metadata_command));
-        send_to_oximeter(&oxdev, &metadata_command)
-            .expect("failed to send metadata request");
-    }
-
-    let (start_time, this_file_length) = loop {
-        receive_from_oximeter(&oxdev, &mut queue)
-            .expect("failed to receive response to file metadata request");
-        let mut start_time = None;
-        let mut this_file_length = None;
         while let Some(response) = queue.dequeue_command() {
             if !is_checksum_ok(&response) {
                 continue;
@@ -304,206 +392,285 @@ fn handle_read_manual(oxdev: &HidDevice, mut queue: &mut CommandQueue, file_inde
             if response.len() < 2 {
                 continue;
             }
-            if response[0] != CommandCode::ManuallyRecordedFileMetadataResponse.into() {
-                continue;
-            }
-            if response.len() != 14 {
+            if response[0] != u8::from(response_code) {
                 continue;
             }
 
-            let file_length
-                = (response[10] as usize)
-                | ((response[11] as usize) << 7)
-                | ((response[12] as usize) << 14)
-                ;
+            return Some(response[1..response.len()-1].to_vec());
+        }
+    }
+    None
+}
 
-            if file_length == 0 {
-                eprintln!("no file recorded in manual recording mode");
+fn handle_read_file(oxdev: &HidDevice, queue: &mut CommandQueue, read_file: &ReadFileSubcommand) {
+    let file_index = read_file.file_index;
+    if file_index == 0 {
+        eprintln!("file 0 does not exist");
+    }
+
+    let device_id = read_device_id(oxdev, queue);
+
+    let rec_mode = read_recording_mode(oxdev, queue);
+    let header = match rec_mode {
+        RecordingMode::Automatic => {
+            let headers = list_files(oxdev, queue, rec_mode);
+            if headers.is_empty() {
+                eprintln!("auto recording mode active and no files recorded");
+                return;
+            }
+            match headers.into_iter().find(|h| h.index == file_index) {
+                Some(h) => h,
+                None => {
+                    eprintln!("auto recording mode active, no file {} available", file_index);
+                    return;
+                },
+            }
+        },
+        RecordingMode::Manual => {
+            if file_index != 1 {
+                eprintln!("manual recording mode active, file index must be 1");
                 return;
             }
+            match list_files(oxdev, queue, rec_mode).pop() {
+                Some(h) => h,
+                None => {
+                    eprintln!("no file recorded in manual recording mode");
+                    return;
+                },
+            }
+        },
+        RecordingMode::Other(o) => panic!("unknown recording mode {}", o),
+    };
 
-            // round length down to a multiple of 27
-            let full_chunk_count = file_length / 27;
-
-            start_time = Some(
-                NaiveDate::from_ymd(
-                    (response[2] as i32) + 2000,
-                    response[3] as u32,
-                    response[4] as u32,
-                ).and_hms(
-                    response[5] as u32,
-                    response[6] as u32,
-                    response[7] as u32
-                )
-            );
-            this_file_length = Some(full_chunk_count * 27);
+    let mut archive = read_file.archive.as_ref().map(|path| Archive::open(path)
+        .expect("failed to open archive"));
+    if let Some(archive) = archive.as_ref() {
+        if archive.contains(&device_id, &header).expect("failed to query archive") {
+            eprintln!("file {} is already archived; not downloading it again", file_index);
+            return;
         }
+    }
 
-        if let Some(st) = start_time {
-            if let Some(tfl) = this_file_length {
-                break (st, tfl);
-            }
-        }
-    };
+    let mut recording = download_file(oxdev, queue, &header);
+    recording.metadata.device_id = Some(device_id);
 
-    let read_commands_responses = &[
-        (
-            CommandCode::ReadPulseFromManuallyRecordedFileCommand,
-            CommandCode::ReadPulseFromManuallyRecordedFileResponse,
-        ),
-        (
-            CommandCode::ReadOxygenFromManuallyRecordedFileCommand,
-            CommandCode::ReadOxygenFromManuallyRecordedFileResponse,
-        ),
-    ];
+    if let Some(archive) = archive.as_mut() {
+        // the archive keeps the recording as downloaded
+        archive.insert(&recording, &header, Local::now())
+            .expect("failed to store recording in archive");
+    }
+    filter_artifacts_if_requested(&mut recording, &read_file.artifact_filter);
 
-    // list of lists of values (Gollum English)
-    let mut valueses = Vec::new();
-    for (read_command, read_response) in read_commands_responses {
-        // read the file!
-        {
-            let mut read_pulse_command = Vec::with_capacity(5);
-            read_pulse_command.push(read_command.into());
-            read_pulse_command.push(0x00);
-            read_pulse_command.push(0x00);
-            read_pulse_command.push(0x00);
-            read_pulse_command.push(calculate_checksum(&read_pulse_command));
-            send_to_oximeter(&oxdev, &read_pulse_command)
-                .expect("failed to send read-pulse request");
-        }
+    if let Some(path) = read_file.report.as_ref() {
+        write_report(path, &recording, &read_file.desaturation);
+    }
 
-        let mut values = Vec::new();
-        loop {
-            receive_from_oximeter(&oxdev, &mut queue)
-                .expect("failed to receive read-pulse request");
-            while let Some(response) = queue.dequeue_command() {
-                if !is_checksum_ok(&response) {
-                    continue;
-                }
-                if response.len() < 2 {
-                    continue;
-                }
-                if response[0] != read_response.into() {
-                    continue;
-                }
-                if response.len() != 20 {
-                    continue;
-                }
+    let formatter = TimestampFormatter::new(read_file.timestamps, recording.metadata.start_time, false);
+    let mut sink = create_output_sink(read_file.format, &read_file.influx, formatter);
+    write_recording(sink.as_mut(), &recording)
+        .expect("failed to output recording");
+}
 
-                // once more, the topmost bits have been "outsourced"
-                let signs
-                    = (response[3] as u16)
-                    | ((response[4] as u16) << 7)
-                    ;
-                let mut value_byte = response[5];
-                // the signs also contain the sign for the value byte
-                if signs & 1 != 0 {
-                    value_byte |= 0b1000_0000;
-                }
+/// The name under which a recording is stored in a sync directory.
+fn sync_file_name(device_id: &str, header: &FileHeader, format: OutputFormat) -> String {
+    let safe_device_id: String = if device_id.is_empty() {
+        "unknown".to_owned()
+    } else {
+        device_id.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect()
+    };
+    format!(
+        "{}_{}_{}.{}",
+        safe_device_id, header.start_time.format("%Y%m%d-%H%M%S"), header.length, file_extension(format),
+    )
+}
 
-                // this base value is also part of the output!
-                values.push(value_byte);
-
-                for (i, b) in response[6..19].iter().enumerate() {
-                    // take top nibble sign from signs
-                    let mut top_nibble = (*b >> 4) & 0x0F;
-                    // (adding 1 to the left-shift because 0 is used for the initial value byte)
-                    if signs & (1 << (i + 1)) != 0 {
-                        top_nibble |= 0b1000;
-                    }
-                    let bottom_nibble = *b & 0x0F;
-
-                    for nibble in [top_nibble, bottom_nibble] {
-                        // TODO: handle 0xF nibble as an invalid value
-                        if nibble == 0xF {
-                            value_byte = 0xFF;
-                        } else if nibble & 0b1000 != 0 {
-                            // subtract from base value
-                            value_byte -= nibble & 0b0111;
-                        } else {
-                            // add to base value
-                            value_byte += nibble & 0b0111;
-                        }
-
-                        values.push(value_byte);
-                    }
-                }
-            }
+fn handle_sync(oxdev: &HidDevice, queue: &mut CommandQueue, sync: &SyncSubcommand) {
+    let mut archive = sync.database.as_ref().map(|path| Archive::open(path)
+        .expect("failed to open archive"));
+    if let Some(directory) = sync.directory.as_ref() {
+        fs::create_dir_all(directory)
+            .expect("failed to create sync directory");
+    }
 
-            debug!("values.len(): {}, this_file_length: {}", values.len(), this_file_length);
-            if values.len() >= this_file_length {
-                values.truncate(this_file_length);
-                break;
+    let device_id = read_device_id(oxdev, queue);
+    let rec_mode = read_recording_mode(oxdev, queue);
+    let headers = list_files(oxdev, queue, rec_mode);
+
+    let mut already_stored = 0;
+    let mut downloaded = 0;
+    for header in &headers {
+        let missing_in_archive = match archive.as_ref() {
+            Some(a) => !a.contains(&device_id, header).expect("failed to query archive"),
+            None => false,
+        };
+        let file_path = sync.directory.as_ref()
+            .map(|dir| dir.join(sync_file_name(&device_id, header, sync.format)));
+        let missing_in_directory = file_path.as_ref()
+            .map(|p| !p.exists())
+            .unwrap_or(false);
+
+        if !missing_in_archive && !missing_in_directory {
+            already_stored += 1;
+            continue;
+        }
+
+        eprintln!(
+            "downloading file {} ({}, {} s)",
+            header.index, header.start_time.format("%Y-%m-%d %H:%M:%S"), header.length,
+        );
+        let mut recording = download_file(oxdev, queue, header);
+        recording.metadata.device_id = Some(device_id.clone());
+
+        if missing_in_archive {
+            if let Some(archive) = archive.as_mut() {
+                archive.insert(&recording, header, Local::now())
+                    .expect("failed to store recording in archive");
             }
         }
-        valueses.push(values);
+        if missing_in_directory {
+            if let Some(path) = file_path.as_ref() {
+                // write to a temporary file first so that an interrupted sync does not leave behind
+                // a truncated file that would be taken for a stored recording next time
+                let partial_path = path.with_extension("part");
+                {
+                    let file = File::create(&partial_path)
+                        .expect("failed to create recording file");
+                    let formatter = TimestampFormatter::new(sync.timestamps, recording.metadata.start_time, false);
+                    let mut sink = create_sink(sync.format, Box::new(BufWriter::new(file)), formatter);
+                    write_recording(sink.as_mut(), &recording)
+                        .expect("failed to write recording file");
+                }
+                fs::rename(&partial_path, path)
+                    .expect("failed to rename recording file");
+            }
+        }
+        downloaded += 1;
     }
 
-    // merge the values and output as CSV
-    let pulse_values = &valueses[0];
-    let spo2_values = &valueses[1];
-    let mut cur_time = start_time;
-    println!("timestamp,pulse,spo2");
-    for (spo2, pulse) in spo2_values.iter().zip(pulse_values.iter()) {
-        println!("{},{},{}", cur_time.format("%Y-%m-%d %H:%M:%S"), *pulse, *spo2);
-        cur_time += Duration::seconds(1);
+    println!(
+        "{} recording(s) on the device, {} already stored, {} downloaded",
+        headers.len(), already_stored, downloaded,
+    );
+
+    if sync.delete && !headers.is_empty() {
+        match rec_mode {
+            RecordingMode::Automatic => {
+                if !delete_and_verify(oxdev, queue, headers.len()) {
+                    process::exit(1);
+                }
+            },
+            _ => {
+                eprintln!("deleting recordings is only supported in automatic recording mode; not deleting");
+            },
+        }
     }
 }
 
-fn handle_read_file(oxdev: &HidDevice, mut queue: &mut CommandQueue, file_index: usize) {
-    if file_index == 0 {
-        eprintln!("file 0 does not exist");
+/// Deletes the files stored in automatic recording mode and checks whether the oximeter now reports
+/// fewer than `count_before` files and a changed file store. Returns whether it does; if the
+/// oximeter does not answer either question, the deletion counts as not verified.
+fn delete_and_verify(oxdev: &HidDevice, queue: &mut CommandQueue, count_before: usize) -> bool {
+    let store_before = read_file_store_info(oxdev, queue);
+    debug!("file store info before deletion: {:02x?}", store_before);
+
+    delete_auto_files(oxdev);
+
+    // the oximeter does not acknowledge the deletion; give it a moment
+    thread::sleep(StdDuration::from_millis(DELETE_SETTLE_TIME_MS));
+
+    let count_after = match count_auto_files(oxdev, queue) {
+        Some(c) => c,
+        None => {
+            eprintln!("the device did not report how many files remain; cannot verify the deletion");
+            return false;
+        },
+    };
+    let store_after = read_file_store_info(oxdev, queue);
+    debug!("file store info after deletion: {:02x?}", store_after);
+
+    if count_after >= count_before {
+        eprintln!(
+            "the device still reports {} file(s); deletion does not seem to have worked",
+            count_after,
+        );
+        return false;
     }
 
-    // check if auto or manual
-    let mut rec_mode_command = Vec::with_capacity(3);
-    rec_mode_command.push(CommandCode::ReadPropertyCommand.into());
-    rec_mode_command.push(PropertyCode::RecordingMode.into());
-    rec_mode_command.push(calculate_checksum(&rec_mode_command));
-    send_to_oximeter(&oxdev, &rec_mode_command)
-        .expect("failed to send recording mode request");
+    let store_state = match (&store_before, &store_after) {
+        (Some(before), Some(after)) if before == after => {
+            eprintln!(
+                "the device reports {} file(s) instead of {}, but its file store is unchanged; deletion does not seem to have worked",
+                count_after, count_before,
+            );
+            return false;
+        },
+        (_, Some(after)) if after.iter().all(|b| *b == 0x00) => "file store reported empty",
+        (Some(_), Some(_)) => "file store changed but not reported empty",
+        (None, Some(_)) => {
+            eprintln!(
+                "the device reports {} file(s) instead of {}, but its file store could not be compared with the state before; cannot verify the deletion",
+                count_after, count_before,
+            );
+            return false;
+        },
+        (_, None) => {
+            eprintln!(
+                "the device reports {} file(s) instead of {}, but did not report its file store; cannot verify the deletion",
+                count_after, count_before,
+            );
+            return false;
+        },
+    };
+    println!(
+        "deleted {} file(s) from the device ({} remaining; {})",
+        count_before - count_after, count_after, store_state,
+    );
+    true
+}
 
-    let rec_mode = loop {
-        receive_from_oximeter(&oxdev, &mut queue)
-            .expect("failed to receive response to file metadata request");
-        let mut rm = None;
-        while let Some(response) = queue.dequeue_command() {
-            if !is_checksum_ok(&response) {
-                continue;
-            }
-            if response.len() < 2 {
-                continue;
-            }
-            if response[0] != CommandCode::ReadPropertyResponse.into() {
-                continue;
-            }
-            if response[1] != PropertyCode::RecordingMode.into() {
-                continue;
-            }
-            if response.len() != 5 {
-                continue;
-            }
+fn handle_delete_files(oxdev: &HidDevice, queue: &mut CommandQueue, delete_files: &DeleteFilesSubcommand) {
+    match read_recording_mode(oxdev, queue) {
+        RecordingMode::Automatic => {},
+        _ => {
+            eprintln!("deleting files is only supported in automatic recording mode");
+            process::exit(1);
+        },
+    }
 
-            let rec_mode_code
-                = (response[2] as u16)
-                | ((response[3] as u16) << 7)
-                ;
-            rm = Some(RecordingMode::from(rec_mode_code));
-        }
+    let count_before = match count_auto_files(oxdev, queue) {
+        Some(c) => c,
+        None => {
+            eprintln!("the device did not report how many files it has stored");
+            process::exit(1);
+        },
+    };
+    if count_before == 0 {
+        eprintln!("no files recorded; nothing to delete");
+        return;
+    }
 
-        if let Some(concrete_rm) = rm {
-            break concrete_rm;
+    if !delete_files.yes {
+        eprint!("delete all {} file(s) recorded in automatic mode from the device? [y/N] ", count_before);
+        io::stderr().flush()
+            .expect("failed to flush prompt");
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)
+            .expect("failed to read answer");
+        let answer = answer.trim().to_lowercase();
+        if answer != "y" && answer != "yes" {
+            eprintln!("not deleting");
+            return;
         }
-    };
+    }
 
-    match rec_mode {
-        RecordingMode::Automatic => handle_read_auto(oxdev, queue, file_index),
-        RecordingMode::Manual => handle_read_manual(oxdev, queue, file_index),
-        RecordingMode::Other(o) => panic!("unknown recording mode {}", o),
+    if !delete_and_verify(oxdev, queue, count_before) {
+        process::exit(1);
     }
 }
 
-fn handle_set_device_id(oxdev: &HidDevice, mut queue: &mut CommandQueue, device_id: &str) {
+fn handle_set_device_id(oxdev: &HidDevice, queue: &mut CommandQueue, device_id: &str) {
     let mut device_id_bytes: Vec<u8> = device_id.bytes().collect();
     if device_id_bytes.len() > 7 {
         panic!("device ID cannot be longer than 7 bytes");
@@ -523,11 +690,11 @@ fn handle_set_device_id(oxdev: &HidDevice, mut queue: &mut CommandQueue, device_
         set_command.push(PropertyCode::DeviceId.into());
         set_command.extend_from_slice(&device_id_bytes);
         set_command.push(calculate_checksum(&set_command));
-        send_to_oximeter(&oxdev, &set_command)
+        send_to_oximeter(oxdev, &set_command)
             .expect("failed to set device ID on oximeter");
 
         loop {
-            receive_from_oximeter(&oxdev, &mut queue)
+            receive_from_oximeter(oxdev, queue)
                 .expect("failed to obtain set-device-ID response from oximeter");
             while let Some(response) = queue.dequeue_command() {
                 if !is_checksum_ok(&response) {
@@ -536,7 +703,7 @@ fn handle_set_device_id(oxdev: &HidDevice, mut queue: &mut CommandQueue, device_
                 if response.len() < 2 {
                     continue;
                 }
-                if response[0] != CommandCode::SetPropertyResponse.into() {
+                if response[0] != u8::from(CommandCode::SetPropertyResponse) {
                     continue;
                 }
 
@@ -546,12 +713,191 @@ fn handle_set_device_id(oxdev: &HidDevice, mut queue: &mut CommandQueue, device_
     }
 }
 
+fn handle_archive(archive_sub: &ArchiveSubcommand) {
+    let archive = Archive::open(&archive_sub.database)
+        .expect("failed to open archive");
+
+    match &archive_sub.action {
+        ArchiveAction::List => {
+            let entries = archive.list()
+                .expect("failed to list archive");
+            for entry in entries {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\tdownloaded {}",
+                    entry.id,
+                    if entry.device_id.is_empty() { "-" } else { &entry.device_id },
+                    entry.start_time.format("%Y-%m-%d %H:%M:%S"),
+                    entry.length,
+                    entry.mode.as_str(),
+                    entry.downloaded_at.format("%Y-%m-%d %H:%M:%S"),
+                );
+            }
+        },
+        ArchiveAction::Export(export) => {
+            let mut recording = match archive.load(export.recording_id).expect("failed to load recording from archive") {
+                Some(r) => r,
+                None => {
+                    eprintln!("no recording {} in archive", export.recording_id);
+                    return;
+                },
+            };
+            filter_artifacts_if_requested(&mut recording, &export.artifact_filter);
+
+            let formatter = TimestampFormatter::new(export.timestamps, recording.metadata.start_time, false);
+            let mut sink = create_output_sink(export.format, &export.influx, formatter);
+            write_recording(sink.as_mut(), &recording)
+                .expect("failed to output recording");
+        },
+    }
+}
+
+/// Creates the sink for the samples: one sending them to InfluxDB if requested (ignoring the
+/// format), otherwise one writing them to standard output in the given format.
+fn create_output_sink(format: OutputFormat, influx: &InfluxOptions, formatter: TimestampFormatter) -> Box<dyn OutputSink> {
+    match influx.url.as_deref() {
+        Some(url) => {
+            if format != OutputFormat::Csv && format != OutputFormat::Influx {
+                eprintln!("sending to InfluxDB; ignoring the output format");
+            }
+            Box::new(InfluxSink::http(url, influx.token.clone(), influx.batch_size))
+        },
+        None => create_sink(format, Box::new(io::stdout()), formatter),
+    }
+}
+
+fn desaturation_params(desaturation: &DesaturationOptions) -> DesaturationParams {
+    DesaturationParams {
+        drop: desaturation.drop,
+        baseline_window: Duration::seconds(desaturation.baseline_window.into()),
+        min_duration: Duration::seconds(desaturation.min_duration.into()),
+    }
+}
+
+/// Writes the HTML report for the recording.
+fn write_report(path: &Path, recording: &Recording, desaturation: &DesaturationOptions) {
+    let analysis = Analysis::of(recording, desaturation_params(desaturation));
+    let html = render_html_report(&analysis, &recording.samples);
+    fs::write(path, html)
+        .expect("failed to write report");
+}
+
+/// Runs the artifact filter over the recording if it has been requested.
+fn filter_artifacts_if_requested(recording: &mut Recording, artifact_filter: &ArtifactOptions) {
+    let mode = match artifact_filter.artifacts {
+        Some(m) => m,
+        None => return,
+    };
+    let params = ArtifactParams {
+        mode,
+        max_spo2_rate: artifact_filter.max_spo2_rate,
+        max_pulse_rate: artifact_filter.max_pulse_rate,
+        flatline: Duration::seconds(artifact_filter.flatline.into()),
+        spo2_range: (artifact_filter.min_spo2, 100),
+        pulse_range: (artifact_filter.min_pulse, artifact_filter.max_pulse),
+    };
+    filter_artifacts(&mut recording.samples, &params);
+}
+
+/// Reads a recording from a file previously output by poxymeter, exiting if that fails.
+fn load_input(input: &InputOptions) -> Recording {
+    let text = fs::read_to_string(&input.input)
+        .expect("failed to read input file");
+
+    let input_format = match input.input_format.or_else(|| detect_format(&text)) {
+        Some(f) => f,
+        None => {
+            eprintln!("cannot detect the format of {}; please pass --input-format", input.input.display());
+            process::exit(1);
+        },
+    };
+    let overrides = MetadataOverrides {
+        device_id: input.device_id.clone(),
+        mode: None,
+        start_time: input.start_time,
+    };
+    match parse_recording(input_format, &text, &overrides) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("failed to parse {}: {}", input.input.display(), e);
+            process::exit(1);
+        },
+    }
+}
+
+fn handle_convert(convert: &ConvertSubcommand) {
+    let mut recording = load_input(&convert.input);
+    filter_artifacts_if_requested(&mut recording, &convert.artifact_filter);
+
+    let formatter = TimestampFormatter::new(convert.timestamps, recording.metadata.start_time, false);
+    let mut sink = create_output_sink(convert.format, &convert.influx, formatter);
+    write_recording(sink.as_mut(), &recording)
+        .expect("failed to output recording");
+}
+
+fn handle_analyze(analyze: &AnalyzeSubcommand) {
+    let mut recording = load_input(&analyze.input);
+    filter_artifacts_if_requested(&mut recording, &analyze.artifact_filter);
+
+    let analysis = Analysis::of(&recording, desaturation_params(&analyze.desaturation));
+    let events = &analysis.desaturations;
+    let formatter = TimestampFormatter::new(analyze.timestamps, recording.metadata.start_time, false);
+
+    match analyze.report_format {
+        ReportFormat::Text => {
+            write_text_report(&mut io::stdout(), &analysis, &formatter)
+                .expect("failed to output report");
+        },
+        ReportFormat::Json => {
+            let report = report_json(&analysis, &formatter);
+            println!("{}", serde_json::to_string_pretty(&report).expect("failed to serialize report"));
+        },
+    }
+
+    if let Some(path) = analyze.events_csv.as_ref() {
+        let mut writer = BufWriter::new(File::create(path).expect("failed to create events file"));
+        write_events_csv(&mut writer, events, &formatter)
+            .expect("failed to write events file");
+    }
+    if let Some(path) = analyze.events_edf.as_ref() {
+        let annotations: Vec<EdfAnnotation> = events.iter()
+            .map(|e| EdfAnnotation {
+                onset: e.start,
+                duration: e.duration(),
+                label: format!("Desaturation (nadir {}%)", e.nadir_spo2),
+            })
+            .collect();
+        let bytes = encode_edf(&recording.metadata, &recording.samples, &[], &annotations);
+        fs::write(path, bytes)
+            .expect("failed to write events EDF file");
+    }
+    if let Some(path) = analyze.report.as_ref() {
+        write_report(path, &recording, &analyze.desaturation);
+    }
+}
+
 
 fn main() {
     env_logger::init();
 
     let opts = Opts::parse();
 
+    // some subcommands do not need the oximeter
+    match &opts.subcommand {
+        Subcommand::Archive(archive) => {
+            handle_archive(archive);
+            return;
+        },
+        Subcommand::Convert(convert) => {
+            handle_convert(convert);
+            return;
+        },
+        Subcommand::Analyze(analyze) => {
+            handle_analyze(analyze);
+            return;
+        },
+        _ => {},
+    }
+
     let hidapi = HidApi::new()
         .expect("failed to instantiate HidApi");
 
@@ -573,8 +919,12 @@ fn main() {
     }
 
     match opts.subcommand {
-        Subcommand::LiveData => handle_live(&oxdev, &mut queue),
-        Subcommand::ReadFile(read_file) => handle_read_file(&oxdev, &mut queue, read_file.file_index),
+        Subcommand::LiveData(live_data) => handle_live(&oxdev, &mut queue, &live_data, Vec::new()),
+        Subcommand::Serve(serve) => handle_serve(&oxdev, &mut queue, &serve),
+        Subcommand::ReadFile(read_file) => handle_read_file(&oxdev, &mut queue, &read_file),
+        Subcommand::Sync(sync) => handle_sync(&oxdev, &mut queue, &sync),
+        Subcommand::DeleteFiles(delete_files) => handle_delete_files(&oxdev, &mut queue, &delete_files),
         Subcommand::SetDeviceId(u) => handle_set_device_id(&oxdev, &mut queue, &u.device_id),
+        Subcommand::Archive(_) | Subcommand::Convert(_) | Subcommand::Analyze(_) => unreachable!(),
     };
 }
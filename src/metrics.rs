@@ -0,0 +1,179 @@
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Local};
+use tiny_http::{Header, Response, Server};
+
+use crate::live::LinkStats;
+use crate::oximeter::{LiveCurvePoint, LiveValues};
+
+
+/// The content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+
+/// The most recent state of the live session, as exposed to Prometheus.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+struct MetricsState {
+    device_id: Option<String>,
+    link_stats: LinkStats,
+    latest_values: Option<LiveValues>,
+    latest_curve_point: Option<LiveCurvePoint>,
+    last_sample_received: Option<DateTime<Local>>,
+}
+impl MetricsState {
+    /// Renders the metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut text = String::new();
+
+        let device_id = self.device_id.as_deref().unwrap_or("");
+        write_metric(
+            &mut text, "poxymeter_device_info", "gauge", "Information about the oximeter.",
+            &format!("{{device_id=\"{}\"}}", escape_label_value(device_id)), 1.0,
+        );
+
+        // SpO2 and pulse are not a number while the finger is out
+        let valid_values = self.latest_values.filter(|v| v.are_valid());
+        write_metric(
+            &mut text, "poxymeter_spo2_percent", "gauge", "The current oxygen saturation.",
+            "", valid_values.map(|v| f64::from(v.spo2)).unwrap_or(f64::NAN),
+        );
+        write_metric(
+            &mut text, "poxymeter_pulse_bpm", "gauge", "The current pulse rate.",
+            "", valid_values.map(|v| f64::from(v.pulse)).unwrap_or(f64::NAN),
+        );
+        write_metric(
+            &mut text, "poxymeter_finger_present", "gauge", "Whether a finger is in the oximeter and the values are valid.",
+            "", if valid_values.is_some() { 1.0 } else { 0.0 },
+        );
+        if let Some(values) = self.latest_values {
+            write_metric(
+                &mut text, "poxymeter_signal_flags", "gauge", "The status flags of the most recent values.",
+                "", values.flags.into(),
+            );
+        }
+        if let Some(point) = self.latest_curve_point {
+            write_metric(
+                &mut text, "poxymeter_curve_flags", "gauge", "The status flags of the most recent pulse curve point.",
+                "", point.flags.into(),
+            );
+            write_metric(
+                &mut text, "poxymeter_signal_strength", "gauge", "The signal strength bar of the most recent pulse curve point.",
+                "", point.bar.into(),
+            );
+        }
+        if let Some(received) = self.last_sample_received {
+            write_metric(
+                &mut text, "poxymeter_last_sample_timestamp_seconds", "gauge", "When the most recent sample was received.",
+                "", received.timestamp_millis() as f64 / 1000.0,
+            );
+        }
+
+        let stats = &self.link_stats;
+        write_metric(
+            &mut text, "poxymeter_packets_total", "counter", "Live data responses received.",
+            "", stats.packets as f64,
+        );
+        write_metric(
+            &mut text, "poxymeter_samples_total", "counter", "Samples (sets of values) received.",
+            "", stats.samples as f64,
+        );
+        write_metric(
+            &mut text, "poxymeter_checksum_failures_total", "counter", "Responses discarded because of a wrong checksum.",
+            "", stats.checksum_failures as f64,
+        );
+        write_metric(
+            &mut text, "poxymeter_reconnects_total", "counter", "Times streaming was requested again after the oximeter stalled.",
+            "", stats.restarts as f64,
+        );
+        write_metric(
+            &mut text, "poxymeter_keepalives_total", "counter", "Keepalives sent to the oximeter.",
+            "", stats.keepalives as f64,
+        );
+
+        text
+    }
+}
+
+
+/// Serves the current live readings and link statistics as Prometheus metrics at `/metrics`.
+#[derive(Clone)]
+pub struct MetricsServer {
+    state: Arc<Mutex<MetricsState>>,
+}
+impl MetricsServer {
+    pub fn new(device_id: Option<String>) -> Self {
+        let state = MetricsState {
+            device_id,
+            ..MetricsState::default()
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Starts answering HTTP requests on the given address. Returns the address actually listened
+    /// on.
+    pub fn listen(&self, address: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let local_address = listener.local_addr()?;
+        let server = Server::from_listener(listener, None)
+            .map_err(io::Error::other)?;
+
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let path = request.url().split('?').next().unwrap_or("");
+                let response = if path == "/metrics" {
+                    let text = state.lock().expect("metrics state poisoned").render();
+                    let content_type = Header::from_bytes("Content-Type", CONTENT_TYPE)
+                        .expect("invalid content type header");
+                    Response::from_string(text)
+                        .with_header(content_type)
+                } else {
+                    Response::from_string("not found; try /metrics\n")
+                        .with_status_code(404)
+                };
+                if let Err(e) = request.respond(response) {
+                    eprintln!("failed to answer metrics request: {}", e);
+                }
+            }
+        });
+        Ok(local_address)
+    }
+
+    /// Updates the metrics with the current state of the live session.
+    pub fn update(&self, link_stats: &LinkStats, latest_values: Option<LiveValues>, latest_curve_point: Option<LiveCurvePoint>) {
+        let mut state = self.state.lock().expect("metrics state poisoned");
+        if link_stats.samples != state.link_stats.samples {
+            state.last_sample_received = Some(Local::now());
+        }
+        state.link_stats = *link_stats;
+        state.latest_values = latest_values;
+        state.latest_curve_point = latest_curve_point;
+    }
+}
+
+
+fn write_metric(text: &mut String, name: &str, metric_type: &str, help: &str, labels: &str, value: f64) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, metric_type).unwrap();
+    writeln!(text, "{}{} {}", name, labels, format_value(value)).unwrap();
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,218 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use log::debug;
+use rumqttc::{Client, Connection, Event, LastWill, Outgoing, Packet, QoS};
+use serde_json::{json, Value};
+
+use crate::alarm::AlarmEvent;
+use crate::opts::MqttOptions;
+use crate::oximeter::RecordingMode;
+use crate::recording::Sample;
+
+
+/// The default port of MQTT brokers.
+const DEFAULT_PORT: u16 = 1883;
+
+/// How often the broker is pinged if there is nothing to publish, in seconds.
+const KEEP_ALIVE_SECS: u64 = 30;
+
+/// How many messages may queue up while the broker is unreachable before further messages are
+/// dropped.
+const QUEUE_LENGTH: usize = 1000;
+
+/// How long to wait before trying to reach the broker again, in seconds.
+const RECONNECT_DELAY_SECS: u64 = 5;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+
+/// The topics to publish to, with the device ID filled in.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Topics {
+    sample: String,
+    status: String,
+    alarm: String,
+    info: String,
+    availability: String,
+}
+
+
+/// Publishes the live samples, status changes and alarms to an MQTT broker.
+///
+/// Information about the oximeter is published (retained) whenever the connection to the broker
+/// is established, along with `online` on the availability topic. The broker is asked to publish
+/// `offline` there if the connection is lost; when the session ends, this is done explicitly.
+///
+/// Publishing never holds up the live data: while the broker is unreachable, messages queue up
+/// and are dropped once the queue is full, and the connection is re-established in the
+/// background.
+pub struct MqttPublisher {
+    client: Client,
+    topics: Topics,
+    qos: QoS,
+    device_id: String,
+    finger_in: Option<bool>,
+    stopping: Arc<AtomicBool>,
+    connection_thread: JoinHandle<()>,
+}
+impl MqttPublisher {
+    /// Connects to the broker configured in the options. `info` is published to the info topic.
+    pub fn connect(options: &MqttOptions, device_id: &str, info: Value) -> Self {
+        let broker = options.broker.as_ref()
+            .expect("MQTT publisher created without broker");
+        let port = broker.port.unwrap_or(DEFAULT_PORT);
+        let client_id = options.client_id.clone()
+            .unwrap_or_else(|| format!("poxymeter-{}", device_id));
+        let qos = rumqttc::qos(options.qos)
+            .expect("invalid MQTT quality of service");
+        let topics = Topics {
+            sample: options.sample_topic.replace("{device_id}", device_id),
+            status: options.status_topic.replace("{device_id}", device_id),
+            alarm: options.alarm_topic.replace("{device_id}", device_id),
+            info: options.info_topic.replace("{device_id}", device_id),
+            availability: options.availability_topic.replace("{device_id}", device_id),
+        };
+
+        let mut mqtt_options = rumqttc::MqttOptions::new(client_id, &broker.host, port);
+        mqtt_options.set_keep_alive(StdDuration::from_secs(KEEP_ALIVE_SECS));
+        mqtt_options.set_last_will(LastWill::new(&topics.availability, OFFLINE, QoS::AtLeastOnce, true));
+        if let (Some(username), Some(password)) = (&options.username, &options.password) {
+            mqtt_options.set_credentials(username, password);
+        }
+
+        let (client, connection) = Client::new(mqtt_options, QUEUE_LENGTH);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let connection_thread = {
+            let client = client.clone();
+            let availability_topic = topics.availability.clone();
+            let info_topic = topics.info.clone();
+            let info = info.to_string();
+            let stopping = Arc::clone(&stopping);
+            thread::spawn(move || drive_connection(connection, client, availability_topic, info_topic, info, stopping))
+        };
+
+        Self {
+            client,
+            topics,
+            qos,
+            device_id: device_id.to_owned(),
+            finger_in: None,
+            stopping,
+            connection_thread,
+        }
+    }
+
+    /// Publishes the samples. If the finger has been put in or taken out, the status change is
+    /// published too.
+    pub fn publish_samples(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            let finger_in = sample.spo2.is_some() && sample.pulse.is_some();
+            if self.finger_in != Some(finger_in) {
+                self.finger_in = Some(finger_in);
+                self.publish_status(if finger_in { "finger-in" } else { "finger-out" }, sample.timestamp);
+            }
+
+            let payload = json!({
+                "timestamp": format_timestamp(&sample.timestamp),
+                "device_id": self.device_id,
+                "pulse": sample.pulse,
+                "spo2": sample.spo2,
+            });
+            self.publish(&self.topics.sample, false, &payload);
+        }
+    }
+
+    /// Publishes a status change, such as `finger-out`, which is retained by the broker.
+    pub fn publish_status(&self, status: &str, timestamp: DateTime<Local>) {
+        let payload = json!({
+            "timestamp": format_timestamp(&timestamp),
+            "device_id": self.device_id,
+            "status": status,
+        });
+        self.publish(&self.topics.status, true, &payload);
+    }
+
+    /// Publishes an alarm being raised or cleared.
+    pub fn publish_alarm(&self, event: &AlarmEvent) {
+        let payload = event.to_json(Some(&self.device_id));
+        self.publish(&self.topics.alarm, false, &payload);
+    }
+
+    /// Publishes that the session has ended and disconnects from the broker.
+    pub fn finish(self) {
+        self.publish_status("stopped", Local::now());
+        if let Err(e) = self.client.try_publish(&self.topics.availability, QoS::AtLeastOnce, true, OFFLINE) {
+            debug!("failed to queue MQTT availability: {}", e);
+        }
+
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Err(e) = self.client.try_disconnect() {
+            debug!("failed to queue MQTT disconnection: {}", e);
+        }
+        self.connection_thread.join()
+            .expect("MQTT connection thread panicked");
+    }
+
+    fn publish(&self, topic: &str, retain: bool, payload: &Value) {
+        // never block the live data; if the queue is full, the broker has been gone for a while
+        if let Err(e) = self.client.try_publish(topic, self.qos, retain, payload.to_string()) {
+            debug!("dropping MQTT message to {}: {}", topic, e);
+        }
+    }
+}
+
+
+/// Describes the oximeter for the info topic. The device name and version information are `null`
+/// if the oximeter did not report them.
+pub fn device_info(device_id: &str, device_name: Option<&str>, version_info: Option<&str>, recording_mode: RecordingMode, started: DateTime<Local>) -> Value {
+    let recording_mode = match recording_mode {
+        RecordingMode::Automatic => "automatic".to_owned(),
+        RecordingMode::Manual => "manual".to_owned(),
+        RecordingMode::Other(o) => format!("0x{:04x}", o),
+    };
+    json!({
+        "device_id": device_id,
+        "device_name": device_name,
+        "version_info": version_info,
+        "recording_mode": recording_mode,
+        "session_start": format_timestamp(&started),
+        "software": concat!("poxymeter ", env!("CARGO_PKG_VERSION")),
+    })
+}
+
+
+/// Runs the connection to the broker until the publisher has disconnected. Whenever the connection
+/// is (re-)established, the availability and the device information are published again.
+fn drive_connection(mut connection: Connection, client: Client, availability_topic: String, info_topic: String, info: String, stopping: Arc<AtomicBool>) {
+    for notification in connection.iter() {
+        match notification {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                debug!("connected to MQTT broker");
+                let announced = client.try_publish(&availability_topic, QoS::AtLeastOnce, true, ONLINE)
+                    .and_then(|_| client.try_publish(&info_topic, QoS::AtLeastOnce, true, info.as_str()));
+                if let Err(e) = announced {
+                    debug!("failed to queue MQTT announcement: {}", e);
+                }
+            },
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {},
+            Err(e) => {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                eprintln!("MQTT connection failed: {}; retrying in {} s", e, RECONNECT_DELAY_SECS);
+                thread::sleep(StdDuration::from_secs(RECONNECT_DELAY_SECS));
+            },
+        }
+    }
+}
+
+
+fn format_timestamp(timestamp: &DateTime<Local>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Millis, false)
+}
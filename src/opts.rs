@@ -1,6 +1,10 @@
 use std::num::ParseIntError;
+use std::path::PathBuf;
 
-use clap::Clap;
+use chrono::{DateTime, Local, NaiveDateTime, ParseError};
+use clap::{ArgEnum, Clap};
+
+use crate::recording::local_from_naive;
 
 
 #[derive(Clap, Debug)]
@@ -19,14 +23,197 @@ pub(crate) struct Opts {
 #[derive(Clap, Debug)]
 pub(crate) enum Subcommand {
     ReadFile(ReadFileSubcommand),
-    LiveData,
+    LiveData(LiveDataSubcommand),
     SetDeviceId(SetDeviceIdSubcommand),
+    Sync(SyncSubcommand),
+    DeleteFiles(DeleteFilesSubcommand),
+    Convert(ConvertSubcommand),
+    Analyze(AnalyzeSubcommand),
+    Archive(ArchiveSubcommand),
+    Serve(ServeSubcommand),
 }
 
 
 #[derive(Clap, Debug)]
 pub(crate) struct ReadFileSubcommand {
     pub file_index: usize,
+
+    /// The output format.
+    #[clap(long = "format", arg_enum, default_value = "csv")]
+    pub format: OutputFormat,
+
+    /// How to output timestamps: local time, UTC, or seconds since the start of the recording.
+    #[clap(long = "timestamps", arg_enum, default_value = "local")]
+    pub timestamps: TimestampMode,
+
+    /// Also store the recording in this SQLite archive. If the archive already contains the
+    /// recording, it is not downloaded again.
+    #[clap(long = "archive")]
+    pub archive: Option<PathBuf>,
+
+    /// Also write a self-contained HTML report with charts and summary statistics to this file.
+    #[clap(long = "report")]
+    pub report: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub desaturation: DesaturationOptions,
+
+    #[clap(flatten)]
+    pub artifact_filter: ArtifactOptions,
+
+    #[clap(flatten)]
+    pub influx: InfluxOptions,
+}
+
+
+#[derive(Clap, Debug)]
+pub(crate) struct LiveDataSubcommand {
+    /// Stream only the values, not the pulse curve. The oximeter then transfers the values in
+    /// batches every few seconds, which considerably reduces the traffic.
+    #[clap(long = "no-curve", conflicts_with_all = &["rr-file", "hrv-file"])]
+    pub no_curve: bool,
+
+    /// The output format. An OSCAR file holds at most about 18 hours, so writing OSCAR to standard
+    /// output is refused; use `--output-dir`, which starts a new file when one is full.
+    #[clap(long = "format", arg_enum, default_value = "csv")]
+    pub format: OutputFormat,
+
+    /// How to output timestamps: local time, UTC, or seconds since the start of the session.
+    #[clap(long = "timestamps", arg_enum, default_value = "local")]
+    pub timestamps: TimestampMode,
+
+    /// Stop after this many seconds.
+    #[clap(long = "duration")]
+    pub duration: Option<u32>,
+
+    /// How often to send a keepalive to the oximeter, in seconds.
+    #[clap(long = "keepalive-interval", default_value = "5")]
+    pub keepalive_interval: u32,
+
+    /// If no live data arrives for this many seconds, assume that the oximeter has stopped
+    /// streaming and ask it to start again.
+    #[clap(long = "stall-timeout", default_value = "10")]
+    pub stall_timeout: u32,
+
+    /// Write the beat-to-beat (RR) intervals derived from the pulse curve to this file. As the
+    /// pulse curve has 20 points per second, the intervals are only accurate to about 50 ms.
+    #[clap(long = "rr-file")]
+    pub rr_file: Option<PathBuf>,
+
+    /// Write heart rate variability metrics (mean RR, SDNN, RMSSD) to this file. The RR intervals
+    /// are only accurate to about 50 ms, which is of the same order as typical SDNN and RMSSD
+    /// values, so the metrics are dominated by this quantization noise and only suited to
+    /// following trends.
+    #[clap(long = "hrv-file")]
+    pub hrv_file: Option<PathBuf>,
+
+    /// The length of the rolling window over which HRV metrics are calculated, in seconds.
+    #[clap(long = "hrv-window", default_value = "300")]
+    pub hrv_window: u32,
+
+    /// How often HRV metrics are written, in seconds.
+    #[clap(long = "hrv-step", default_value = "30")]
+    pub hrv_step: u32,
+
+    /// Show a full-screen dashboard instead of writing the samples to standard output. Press `r`
+    /// to start or stop recording the samples into a file in the current directory (in the output
+    /// format) and `q` to quit.
+    #[clap(long = "tui")]
+    pub tui: bool,
+
+    /// Serve the current readings and link statistics as Prometheus metrics via HTTP on this
+    /// address (e.g. `127.0.0.1:9420`) at `/metrics`.
+    #[clap(long = "metrics-listen")]
+    pub metrics_listen: Option<String>,
+
+    /// Write the samples into timestamped files in this directory instead of standard output,
+    /// starting a new file when the current one becomes too large or too old.
+    #[clap(long = "output-dir")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Start a new file in the output directory once the current one has reached this many
+    /// megabytes. For formats only written once the file is finished (EDF, OSCAR and FHIR), the
+    /// size is estimated from the number of samples.
+    #[clap(long = "rotate-size", requires = "output-dir")]
+    pub rotate_size: Option<u64>,
+
+    /// Start a new file in the output directory once the current one is this many seconds old.
+    #[clap(long = "rotate-interval", requires = "output-dir")]
+    pub rotate_interval: Option<u64>,
+
+    /// How often to sync the file in the output directory to disk, in seconds (5 if not given). At
+    /// most this much data is lost if the computer crashes or loses power.
+    #[clap(long = "sync-interval", requires = "output-dir")]
+    pub sync_interval: Option<u64>,
+
+    #[clap(flatten)]
+    pub alarms: AlarmOptions,
+
+    #[clap(flatten)]
+    pub mqtt: MqttOptions,
+
+    #[clap(flatten)]
+    pub influx: InfluxOptions,
+}
+
+
+/// Runs the live data loop like `live-data` and additionally serves the samples and pulse curve
+/// points as JSON to any number of local clients.
+#[derive(Clap, Debug)]
+pub(crate) struct ServeSubcommand {
+    /// Serve the live data as JSON Lines to TCP clients connecting to this address.
+    #[clap(long = "tcp", default_value = "127.0.0.1:4620")]
+    pub tcp: String,
+
+    /// Serve the live data as JSON messages to WebSocket clients connecting to this address.
+    #[clap(long = "websocket", default_value = "127.0.0.1:4621")]
+    pub websocket: String,
+
+    #[clap(flatten)]
+    pub live: LiveDataSubcommand,
+}
+
+
+#[derive(ArgEnum, Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum OutputFormat {
+    Csv,
+    Jsonl,
+    Edf,
+    Spo2Assistant,
+    Oscar,
+    Fhir,
+    Influx,
+}
+
+
+#[derive(ArgEnum, Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum InputFormat {
+    Csv,
+    Jsonl,
+    Spo2Assistant,
+}
+
+
+#[derive(ArgEnum, Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum ArtifactMode {
+    Mark,
+    Drop,
+    Interpolate,
+}
+
+
+#[derive(ArgEnum, Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum ReportFormat {
+    Text,
+    Json,
+}
+
+
+#[derive(ArgEnum, Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum TimestampMode {
+    Local,
+    Utc,
+    Monotonic,
 }
 
 
@@ -36,19 +223,381 @@ pub(crate) struct SetDeviceIdSubcommand {
 }
 
 
-fn try_parse_with_base(mut num_str: &str) -> Result<u16, ParseIntError> {
-    if num_str.starts_with("+") {
-        num_str = &num_str[1..];
-    }
+#[derive(Clap, Debug)]
+pub(crate) struct SyncSubcommand {
+    /// Store the recordings in this SQLite archive.
+    #[clap(long = "database", required_unless_present = "directory")]
+    pub database: Option<PathBuf>,
+
+    /// Store the recordings as files in this directory, one file per recording.
+    #[clap(long = "directory")]
+    pub directory: Option<PathBuf>,
+
+    /// The format of the files stored in the directory.
+    #[clap(long = "format", arg_enum, default_value = "csv")]
+    pub format: OutputFormat,
+
+    /// How to output timestamps in the files stored in the directory.
+    #[clap(long = "timestamps", arg_enum, default_value = "local")]
+    pub timestamps: TimestampMode,
+
+    /// Once all the recordings have been stored, delete them from the oximeter. Only supported in
+    /// automatic recording mode.
+    #[clap(long = "delete")]
+    pub delete: bool,
+}
+
+
+/// Deletes all the files stored on the oximeter in automatic recording mode. Experimental: the
+/// deletion sequence has been observed but is not acknowledged by the oximeter.
+#[derive(Clap, Debug)]
+pub(crate) struct DeleteFilesSubcommand {
+    /// Do not ask for confirmation.
+    #[clap(long = "yes")]
+    pub yes: bool,
+}
+
+
+/// Settings for the detection of oxygen desaturation events.
+#[derive(Clap, Debug)]
+pub(crate) struct DesaturationOptions {
+    /// By how many percentage points SpO2 must drop below the baseline to count as a desaturation
+    /// (commonly 3 or 4).
+    #[clap(long = "drop", default_value = "3")]
+    pub drop: u8,
+
+    /// The period before each sample over which the baseline SpO2 is averaged, in seconds.
+    #[clap(long = "baseline-window", default_value = "120")]
+    pub baseline_window: u32,
+
+    /// How long SpO2 must stay below the threshold to count as a desaturation, in seconds.
+    #[clap(long = "min-duration", default_value = "10")]
+    pub min_duration: u32,
+}
+
+
+/// Settings for the artifact filter, which runs before any statistics are calculated and before
+/// the output is written.
+#[derive(Clap, Debug)]
+pub(crate) struct ArtifactOptions {
+    /// Flag implausible values as artifacts and keep them (mark), make them invalid (drop) or
+    /// replace them by values interpolated from their neighbors (interpolate). The flags are kept
+    /// in the output. Without this option, no artifacts are detected.
+    #[clap(long = "artifacts", arg_enum)]
+    pub artifacts: Option<ArtifactMode>,
+
+    /// The largest plausible change of SpO2 per second, in percentage points.
+    #[clap(long = "max-spo2-rate", default_value = "4")]
+    pub max_spo2_rate: u8,
+
+    /// The largest plausible change of the pulse rate per second, in beats per minute.
+    #[clap(long = "max-pulse-rate", default_value = "10")]
+    pub max_pulse_rate: u8,
+
+    /// How many seconds SpO2 and pulse may stay exactly the same before they are considered a
+    /// flatline; 0 disables flatline detection.
+    #[clap(long = "flatline", default_value = "120")]
+    pub flatline: u32,
+
+    /// The lowest plausible SpO2 value.
+    #[clap(long = "min-spo2", default_value = "50")]
+    pub min_spo2: u8,
+
+    /// The lowest plausible pulse rate.
+    #[clap(long = "min-pulse", default_value = "25")]
+    pub min_pulse: u8,
+
+    /// The highest plausible pulse rate.
+    #[clap(long = "max-pulse", default_value = "250")]
+    pub max_pulse: u8,
+}
+
+
+/// Settings for the alarms raised while monitoring live data.
+#[derive(Clap, Debug)]
+pub(crate) struct AlarmOptions {
+    /// Raise an alarm if SpO2 stays below this value.
+    #[clap(long = "alarm-spo2-below")]
+    pub spo2_below: Option<u8>,
+
+    /// Raise an alarm if the pulse rate stays below this value.
+    #[clap(long = "alarm-pulse-below")]
+    pub pulse_below: Option<u8>,
+
+    /// Raise an alarm if the pulse rate stays above this value.
+    #[clap(long = "alarm-pulse-above")]
+    pub pulse_above: Option<u8>,
 
-    if num_str.starts_with("0x") {
-        u16::from_str_radix(&num_str[2..], 16)
-    } else if num_str.starts_with("0b") {
-        u16::from_str_radix(&num_str[2..], 2)
-    } else if num_str.starts_with("0o") {
+    /// How long SpO2 or the pulse rate must stay beyond a threshold before an alarm is raised, in
+    /// seconds.
+    #[clap(long = "alarm-min-duration", default_value = "10")]
+    pub min_duration: u32,
+
+    /// How far SpO2 or the pulse rate must come back from a threshold (in percentage points or
+    /// beats per minute) before the alarm is cleared.
+    #[clap(long = "alarm-hysteresis", default_value = "2")]
+    pub hysteresis: u8,
+
+    /// Raise an alarm if the finger has been out (or the values have been invalid) for this many
+    /// seconds.
+    #[clap(long = "alarm-finger-out")]
+    pub finger_out: Option<u32>,
+
+    /// Raise an alarm if no live data has arrived for this many seconds.
+    #[clap(long = "alarm-no-data")]
+    pub no_data: Option<u32>,
+
+    /// Run this shell command whenever an alarm is raised or cleared. The event is passed as JSON
+    /// on standard input.
+    #[clap(long = "alarm-command")]
+    pub command: Option<String>,
+
+    /// Append a line to this file whenever an alarm is raised or cleared. Without this option, the
+    /// line is written to standard error.
+    #[clap(long = "alarm-log")]
+    pub log: Option<PathBuf>,
+
+    /// Ring the terminal bell whenever an alarm is raised.
+    #[clap(long = "alarm-bell")]
+    pub bell: bool,
+}
+
+
+/// The address of an MQTT broker, as given on the command line.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct MqttBroker {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+
+/// Settings for publishing live data to an MQTT broker. In the topics, `{device_id}` is replaced by
+/// the ID of the oximeter.
+#[derive(Clap, Debug)]
+pub(crate) struct MqttOptions {
+    /// Publish the live data to the MQTT broker at this host (and optionally port, default 1883).
+    #[clap(long = "mqtt-broker", parse(try_from_str = try_parse_mqtt_broker))]
+    pub broker: Option<MqttBroker>,
+
+    /// The client ID to connect with. Defaults to `poxymeter-` followed by the device ID.
+    #[clap(long = "mqtt-client-id")]
+    pub client_id: Option<String>,
+
+    /// The user name to log in to the broker with.
+    #[clap(long = "mqtt-username", requires = "password")]
+    pub username: Option<String>,
+
+    /// The password to log in to the broker with.
+    #[clap(long = "mqtt-password", requires = "username")]
+    pub password: Option<String>,
+
+    /// The quality of service (0, 1 or 2) with which samples, status changes and alarms are
+    /// published.
+    #[clap(long = "mqtt-qos", default_value = "0")]
+    pub qos: u8,
+
+    /// The topic to which each sample is published.
+    #[clap(long = "mqtt-sample-topic", default_value = "poxymeter/{device_id}/sample")]
+    pub sample_topic: String,
+
+    /// The topic to which status changes (finger in or out, streaming restarted or stopped) are
+    /// published, retained.
+    #[clap(long = "mqtt-status-topic", default_value = "poxymeter/{device_id}/status")]
+    pub status_topic: String,
+
+    /// The topic to which alarms are published as they are raised and cleared.
+    #[clap(long = "mqtt-alarm-topic", default_value = "poxymeter/{device_id}/alarm")]
+    pub alarm_topic: String,
+
+    /// The topic to which information about the oximeter is published, retained.
+    #[clap(long = "mqtt-info-topic", default_value = "poxymeter/{device_id}/info")]
+    pub info_topic: String,
+
+    /// The topic to which `online` is published, retained, while connected. The broker publishes
+    /// `offline` (the last will) if the connection is lost.
+    #[clap(long = "mqtt-availability-topic", default_value = "poxymeter/{device_id}/availability")]
+    pub availability_topic: String,
+}
+
+
+/// Settings for sending the output to an InfluxDB server instead of standard output.
+#[derive(Clap, Debug)]
+pub(crate) struct InfluxOptions {
+    /// Send the samples in the InfluxDB line protocol to this HTTP write endpoint instead of
+    /// writing them to standard output, e.g. `http://localhost:8086/write?db=oximetry` or
+    /// `http://localhost:8086/api/v2/write?org=home&bucket=oximetry`. The output format is then
+    /// ignored, except in live mode, where it still applies to the output directory and the
+    /// dashboard recordings.
+    #[clap(long = "influx-url")]
+    pub url: Option<String>,
+
+    /// The API token to authenticate with (InfluxDB 2).
+    #[clap(long = "influx-token", requires = "url")]
+    pub token: Option<String>,
+
+    /// How many samples to send to InfluxDB at once. Samples are sent at least every few seconds
+    /// even if the batch is not full.
+    #[clap(long = "influx-batch-size", default_value = "5000")]
+    pub batch_size: usize,
+}
+
+
+/// A file previously output by poxymeter (CSV or JSON Lines) or exported by SpO2 Assistant.
+#[derive(Clap, Debug)]
+pub(crate) struct InputOptions {
+    /// The file to read.
+    pub input: PathBuf,
+
+    /// The format of the file. Detected from the first line if not given.
+    #[clap(long = "input-format", arg_enum)]
+    pub input_format: Option<InputFormat>,
+
+    /// The start time of the recording (YYYY-MM-DD HH:MM:SS, local time). Required if the file
+    /// contains monotonic timestamps.
+    #[clap(long = "start-time", parse(try_from_str = try_parse_local_datetime))]
+    pub start_time: Option<DateTime<Local>>,
+
+    /// The device ID to assume, if the file does not contain it.
+    #[clap(long = "device-id")]
+    pub device_id: Option<String>,
+}
+
+
+/// Reads a file previously output by poxymeter (CSV or JSON Lines) or exported by SpO2 Assistant
+/// and outputs it in another format.
+#[derive(Clap, Debug)]
+pub(crate) struct ConvertSubcommand {
+    #[clap(flatten)]
+    pub input: InputOptions,
+
+    /// The output format.
+    #[clap(long = "format", arg_enum, default_value = "csv")]
+    pub format: OutputFormat,
+
+    /// How to output timestamps: local time, UTC, or seconds since the start of the recording.
+    #[clap(long = "timestamps", arg_enum, default_value = "local")]
+    pub timestamps: TimestampMode,
+
+    #[clap(flatten)]
+    pub artifact_filter: ArtifactOptions,
+
+    #[clap(flatten)]
+    pub influx: InfluxOptions,
+}
+
+
+/// Calculates summary statistics for a file previously output by poxymeter, detects oxygen
+/// desaturation events and calculates the oxygen desaturation index (ODI).
+#[derive(Clap, Debug)]
+pub(crate) struct AnalyzeSubcommand {
+    #[clap(flatten)]
+    pub input: InputOptions,
+
+    #[clap(flatten)]
+    pub desaturation: DesaturationOptions,
+
+    /// The format of the report written to standard output.
+    #[clap(long = "report-format", arg_enum, default_value = "text")]
+    pub report_format: ReportFormat,
+
+    /// How to output timestamps in the event list: local time, UTC, or seconds since the start of
+    /// the recording.
+    #[clap(long = "timestamps", arg_enum, default_value = "local")]
+    pub timestamps: TimestampMode,
+
+    /// Write the events to this file as comma-separated values.
+    #[clap(long = "events-csv")]
+    pub events_csv: Option<PathBuf>,
+
+    /// Write the recording to this file as EDF+, with the events as annotations.
+    #[clap(long = "events-edf")]
+    pub events_edf: Option<PathBuf>,
+
+    /// Write a self-contained HTML report with charts to this file.
+    #[clap(long = "report")]
+    pub report: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub artifact_filter: ArtifactOptions,
+}
+
+
+#[derive(Clap, Debug)]
+pub(crate) struct ArchiveSubcommand {
+    /// The SQLite archive to operate on.
+    #[clap(long = "database")]
+    pub database: PathBuf,
+
+    #[clap(subcommand)]
+    pub action: ArchiveAction,
+}
+
+
+#[derive(Clap, Debug)]
+pub(crate) enum ArchiveAction {
+    /// Lists the recordings in the archive.
+    List,
+
+    /// Outputs a recording from the archive.
+    Export(ArchiveExportSubcommand),
+}
+
+
+#[derive(Clap, Debug)]
+pub(crate) struct ArchiveExportSubcommand {
+    /// The ID of the recording, as shown by the list action.
+    pub recording_id: i64,
+
+    /// The output format.
+    #[clap(long = "format", arg_enum, default_value = "csv")]
+    pub format: OutputFormat,
+
+    /// How to output timestamps: local time, UTC, or seconds since the start of the recording.
+    #[clap(long = "timestamps", arg_enum, default_value = "local")]
+    pub timestamps: TimestampMode,
+
+    #[clap(flatten)]
+    pub artifact_filter: ArtifactOptions,
+
+    #[clap(flatten)]
+    pub influx: InfluxOptions,
+}
+
+
+fn try_parse_with_base(num_str: &str) -> Result<u16, ParseIntError> {
+    let num_str = num_str.strip_prefix('+').unwrap_or(num_str);
+
+    if let Some(hex) = num_str.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(bin) = num_str.strip_prefix("0b") {
+        u16::from_str_radix(bin, 2)
+    } else if let Some(oct) = num_str.strip_prefix("0o") {
         // who even uses octal anymore?
-        u16::from_str_radix(&num_str[2..], 8)
+        u16::from_str_radix(oct, 8)
     } else {
-        u16::from_str_radix(num_str, 10)
+        num_str.parse()
     }
 }
+
+fn try_parse_mqtt_broker(broker_str: &str) -> Result<MqttBroker, String> {
+    let (host, port) = match broker_str.rsplit_once(':') {
+        Some((host, port_str)) => {
+            let port = port_str.parse()
+                .map_err(|e| format!("invalid port {:?}: {}", port_str, e))?;
+            (host, Some(port))
+        },
+        None => (broker_str, None),
+    };
+    if host.is_empty() {
+        return Err("missing host name".to_owned());
+    }
+    Ok(MqttBroker {
+        host: host.to_owned(),
+        port,
+    })
+}
+
+fn try_parse_local_datetime(dt_str: &str) -> Result<DateTime<Local>, ParseError> {
+    NaiveDateTime::parse_from_str(dt_str, "%Y-%m-%d %H:%M:%S")
+        .map(|naive| local_from_naive(&naive))
+}
@@ -0,0 +1,65 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use chrono::SecondsFormat;
+
+use crate::clock::TimestampFormatter;
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample};
+
+
+/// Quotes a CSV field if necessary (RFC 4180).
+pub fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains(&[',', '"', '\r', '\n'][..]) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+
+/// Outputs samples as comma-separated values with a header row. Invalid values are left empty; the
+/// artifact flags (if any) are separated by semicolons.
+///
+/// The header row is preceded by the metadata as comment lines (`# device_id: ...`, `# start_time:
+/// ...`, `# mode: ...`). As in `JsonLinesSink`, the start time is always absolute (RFC 3339).
+pub struct CsvSink {
+    writer: Box<dyn Write>,
+    formatter: TimestampFormatter,
+}
+impl CsvSink {
+    pub fn new(writer: Box<dyn Write>, formatter: TimestampFormatter) -> Self {
+        Self {
+            writer,
+            formatter,
+        }
+    }
+}
+impl OutputSink for CsvSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        if let Some(device_id) = &metadata.device_id {
+            writeln!(self.writer, "# device_id: {}", device_id)?;
+        }
+        writeln!(self.writer, "# start_time: {}", metadata.start_time.to_rfc3339_opts(SecondsFormat::AutoSi, false))?;
+        writeln!(self.writer, "# mode: {}", metadata.mode.as_str())?;
+        writeln!(self.writer, "timestamp,pulse,spo2,artifacts")
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        let pulse = sample.pulse.map(|p| p.to_string()).unwrap_or_default();
+        let spo2 = sample.spo2.map(|s| s.to_string()).unwrap_or_default();
+        writeln!(
+            self.writer, "{},{},{},{}",
+            csv_field(&self.formatter.format(&sample.timestamp)), pulse, spo2,
+            sample.artifacts.names().join(";"),
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
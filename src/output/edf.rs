@@ -0,0 +1,487 @@
+use std::io::{self, Write};
+
+use chrono::{DateTime, Datelike, Duration, Local, Timelike};
+
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample, SourceMode, WaveformPoint};
+
+
+/// The nominal number of pulse curve points per second.
+const PLETH_SAMPLES_PER_SECOND: usize = 20;
+
+/// The smallest gap between two live samples, in milliseconds, that is taken for missing samples
+/// rather than jitter.
+const LIVE_GAP_MS: i64 = 2000;
+
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+
+/// The description of a signal in the EDF header.
+struct SignalHeader {
+    label: &'static str,
+    dimension: &'static str,
+    physical_min: i32,
+    physical_max: i32,
+    digital_min: i32,
+    digital_max: i32,
+    samples_per_record: usize,
+}
+
+
+/// An additional annotation to include in an EDF+ file, such as a detected event.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct EdfAnnotation {
+    pub onset: DateTime<Local>,
+    pub duration: Duration,
+    pub label: String,
+}
+
+
+/// Outputs samples as European Data Format (EDF+) with one-second data records.
+///
+/// SpO2 and pulse are stored as 1 Hz signals and, if the pulse curve has been captured, the
+/// plethysmogram as a 20 Hz signal. Invalid values are stored as 0 and marked with an annotation
+/// spanning the invalid period; samples flagged by the artifact filter are annotated likewise. Live
+/// samples fill one data record each, as their timestamps jitter around the second.
+pub struct EdfSink {
+    writer: Box<dyn Write>,
+    metadata: Option<RecordingMetadata>,
+    samples: Vec<Sample>,
+    waveform: Vec<WaveformPoint>,
+}
+impl EdfSink {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer,
+            metadata: None,
+            samples: Vec::new(),
+            waveform: Vec::new(),
+        }
+    }
+}
+impl OutputSink for EdfSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        self.metadata = Some(metadata.clone());
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        self.samples.push(*sample);
+        Ok(())
+    }
+
+    fn write_waveform(&mut self, point: &WaveformPoint) -> io::Result<()> {
+        self.waveform.push(*point);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // nothing can be written before the end
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let metadata = self.metadata.as_ref()
+            .expect("EDF output finished without metadata");
+        let bytes = encode_edf(metadata, &self.samples, &self.waveform, &[]);
+        self.writer.write_all(&bytes)?;
+        self.writer.flush()
+    }
+}
+
+
+/// Encodes a complete EDF+ file, including the given annotations in addition to those for the
+/// invalid periods.
+pub fn encode_edf(metadata: &RecordingMetadata, samples: &[Sample], waveform: &[WaveformPoint], annotations: &[EdfAnnotation]) -> Vec<u8> {
+    let start = metadata.start_time;
+
+    // place the samples into one-second slots relative to the start
+    let slots = if metadata.mode == SourceMode::Live {
+        live_slots(&start, samples)
+    } else {
+        samples.iter()
+            .map(|s| seconds_since(&start, &s.timestamp))
+            .collect()
+    };
+    let record_count = slots.iter()
+        .flatten()
+        .map(|slot| slot + 1)
+        .max()
+        .unwrap_or(0);
+    let mut pulse_slots: Vec<Option<u8>> = vec![None; record_count];
+    let mut spo2_slots: Vec<Option<u8>> = vec![None; record_count];
+    let mut artifact_slots: Vec<Option<String>> = vec![None; record_count];
+    for (sample, slot) in samples.iter().zip(&slots) {
+        if let Some(slot) = *slot {
+            pulse_slots[slot] = sample.pulse;
+            spo2_slots[slot] = sample.spo2;
+            if !sample.artifacts.is_empty() {
+                artifact_slots[slot] = Some(format!("Artifact ({})", sample.artifacts.names().join(", ")));
+            }
+        }
+    }
+
+    let has_pleth = !waveform.is_empty();
+    let mut pleth_slots: Vec<i16> = vec![0; record_count * PLETH_SAMPLES_PER_SECOND];
+    for point in waveform {
+        let offset_ms = (point.timestamp - start).num_milliseconds();
+        if offset_ms < 0 {
+            continue;
+        }
+        let index = (offset_ms as usize) * PLETH_SAMPLES_PER_SECOND / 1000;
+        if index < pleth_slots.len() {
+            pleth_slots[index] = point.value.into();
+        }
+    }
+
+    // collect the annotations for invalid periods by the data record in which they begin
+    let mut record_annotations: Vec<Vec<u8>> = (0..record_count)
+        .map(|r| format!("+{}\x14\x14\x00", r).into_bytes())
+        .collect();
+    for (label, slots) in [("SpO2 invalid", &spo2_slots), ("Pulse invalid", &pulse_slots)] {
+        for (onset, duration) in invalid_runs(slots) {
+            let tal = format!("+{}\x15{}\x14{}\x14\x00", onset, duration, label);
+            record_annotations[onset].extend_from_slice(tal.as_bytes());
+        }
+    }
+    for (onset, duration, label) in label_runs(&artifact_slots) {
+        let tal = format!("+{}\x15{}\x14{}\x14\x00", onset, duration, label);
+        record_annotations[onset].extend_from_slice(tal.as_bytes());
+    }
+    for annotation in annotations {
+        let onset_ms = (annotation.onset - start).num_milliseconds();
+        if onset_ms < 0 || record_count == 0 {
+            continue;
+        }
+        let record = ((onset_ms / 1000) as usize).min(record_count - 1);
+        let tal = format!(
+            "+{}\x15{}\x14{}\x14\x00",
+            format_seconds(onset_ms), format_seconds(annotation.duration.num_milliseconds()), annotation.label,
+        );
+        record_annotations[record].extend_from_slice(tal.as_bytes());
+    }
+    let annotation_bytes = record_annotations.iter()
+        .map(|a| a.len())
+        .max()
+        .unwrap_or(0);
+    let annotation_samples = annotation_bytes.max(2).div_ceil(2);
+
+    let mut signals = vec![
+        SignalHeader {
+            label: "SpO2",
+            dimension: "%",
+            physical_min: 0,
+            physical_max: 100,
+            digital_min: 0,
+            digital_max: 100,
+            samples_per_record: 1,
+        },
+        SignalHeader {
+            label: "Pulse",
+            dimension: "bpm",
+            physical_min: 0,
+            physical_max: 255,
+            digital_min: 0,
+            digital_max: 255,
+            samples_per_record: 1,
+        },
+    ];
+    if has_pleth {
+        signals.push(SignalHeader {
+            label: "Pleth",
+            dimension: "",
+            physical_min: 0,
+            physical_max: 127,
+            digital_min: 0,
+            digital_max: 127,
+            samples_per_record: PLETH_SAMPLES_PER_SECOND,
+        });
+    }
+    signals.push(SignalHeader {
+        label: "EDF Annotations",
+        dimension: "",
+        physical_min: -1,
+        physical_max: 1,
+        digital_min: -32768,
+        digital_max: 32767,
+        samples_per_record: annotation_samples,
+    });
+
+    // main header
+    let device_id = metadata.device_id.as_deref()
+        .map(|d| d.trim().replace(' ', "_"))
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "X".to_owned());
+    let recording_id = format!(
+        "Startdate {:02}-{}-{:04} X X {}",
+        start.day(), MONTHS[start.month0() as usize], start.year(), device_id,
+    );
+
+    let mut bytes = Vec::new();
+    push_field(&mut bytes, "0", 8);
+    push_field(&mut bytes, "X X X X", 80);
+    push_field(&mut bytes, &recording_id, 80);
+    push_field(&mut bytes, &format!("{:02}.{:02}.{:02}", start.day(), start.month(), start.year() % 100), 8);
+    push_field(&mut bytes, &format!("{:02}.{:02}.{:02}", start.hour(), start.minute(), start.second()), 8);
+    push_field(&mut bytes, &(256 * (signals.len() + 1)).to_string(), 8);
+    push_field(&mut bytes, "EDF+C", 44);
+    push_field(&mut bytes, &record_count.to_string(), 8);
+    push_field(&mut bytes, "1", 8);
+    push_field(&mut bytes, &signals.len().to_string(), 4);
+
+    // signal headers (field by field, not signal by signal)
+    for s in &signals { push_field(&mut bytes, s.label, 16); }
+    for _ in &signals { push_field(&mut bytes, "", 80); }
+    for s in &signals { push_field(&mut bytes, s.dimension, 8); }
+    for s in &signals { push_field(&mut bytes, &s.physical_min.to_string(), 8); }
+    for s in &signals { push_field(&mut bytes, &s.physical_max.to_string(), 8); }
+    for s in &signals { push_field(&mut bytes, &s.digital_min.to_string(), 8); }
+    for s in &signals { push_field(&mut bytes, &s.digital_max.to_string(), 8); }
+    for _ in &signals { push_field(&mut bytes, "", 80); }
+    for s in &signals { push_field(&mut bytes, &s.samples_per_record.to_string(), 8); }
+    for _ in &signals { push_field(&mut bytes, "", 32); }
+
+    // data records
+    for r in 0..record_count {
+        push_i16(&mut bytes, spo2_slots[r].unwrap_or(0).into());
+        push_i16(&mut bytes, pulse_slots[r].unwrap_or(0).into());
+        if has_pleth {
+            for value in &pleth_slots[r*PLETH_SAMPLES_PER_SECOND..(r+1)*PLETH_SAMPLES_PER_SECOND] {
+                push_i16(&mut bytes, *value);
+            }
+        }
+
+        let annotation = &record_annotations[r];
+        bytes.extend_from_slice(annotation);
+        bytes.resize(bytes.len() + annotation_samples * 2 - annotation.len(), 0x00);
+    }
+
+    bytes
+}
+
+
+/// Returns the whole number of seconds from `start` to `timestamp`, or `None` if `timestamp` is
+/// before `start`.
+fn seconds_since(start: &DateTime<Local>, timestamp: &DateTime<Local>) -> Option<usize> {
+    let offset_ms = (*timestamp - *start).num_milliseconds();
+    if offset_ms < 0 {
+        None
+    } else {
+        // round to the nearest second; live samples do not fall exactly on the second
+        Some(((offset_ms + 500) / 1000) as usize)
+    }
+}
+
+/// Assigns live samples to one-second slots relative to `start`. Live samples do not arrive exactly
+/// one second apart, and rounding each timestamp could put two of them into the same slot and leave
+/// the slot next to it empty; so each sample takes the slot after the previous one, and slots are
+/// only skipped where the stream had a real gap. Samples before `start` get no slot.
+fn live_slots(start: &DateTime<Local>, samples: &[Sample]) -> Vec<Option<usize>> {
+    let mut slots = Vec::with_capacity(samples.len());
+    let mut previous: Option<(usize, DateTime<Local>)> = None;
+    for sample in samples {
+        let slot = match previous {
+            None => seconds_since(start, &sample.timestamp),
+            Some((previous_slot, previous_time)) => {
+                let gap_ms = (sample.timestamp - previous_time).num_milliseconds();
+                if gap_ms < LIVE_GAP_MS {
+                    Some(previous_slot + 1)
+                } else {
+                    Some(previous_slot + ((gap_ms + 500) / 1000) as usize)
+                }
+            },
+        };
+        if let Some(slot) = slot {
+            previous = Some((slot, sample.timestamp));
+        }
+        slots.push(slot);
+    }
+    slots
+}
+
+/// Formats a number of milliseconds as seconds for a TAL, omitting the fraction if it is zero.
+fn format_seconds(ms: i64) -> String {
+    if ms % 1000 == 0 {
+        (ms / 1000).to_string()
+    } else {
+        format!("{}.{:03}", ms / 1000, ms % 1000)
+    }
+}
+
+/// Returns the start index and length of each run of invalid values.
+fn invalid_runs(slots: &[Option<u8>]) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut run_start = None;
+    for (i, slot) in slots.iter().enumerate() {
+        match (slot, run_start) {
+            (None, None) => run_start = Some(i),
+            (Some(_), Some(start)) => {
+                runs.push((start, i - start));
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+    if let Some(start) = run_start {
+        runs.push((start, slots.len() - start));
+    }
+    runs
+}
+
+/// Returns the start index, length and label of each run of identical labels.
+fn label_runs(slots: &[Option<String>]) -> Vec<(usize, usize, &str)> {
+    let mut runs = Vec::new();
+    let mut run_start = 0;
+    for i in 1..=slots.len() {
+        if i == slots.len() || slots[i] != slots[run_start] {
+            if let Some(label) = &slots[run_start] {
+                runs.push((run_start, i - run_start, label.as_str()));
+            }
+            run_start = i;
+        }
+    }
+    runs
+}
+
+/// Appends a header field, truncated or right-padded with spaces to the given width. Characters
+/// outside printable ASCII are not allowed in EDF headers and are replaced.
+fn push_field(bytes: &mut Vec<u8>, value: &str, width: usize) {
+    let mut field: Vec<u8> = value.bytes()
+        .map(|b| if (0x20..0x7F).contains(&b) { b } else { b'_' })
+        .take(width)
+        .collect();
+    field.resize(width, b' ');
+    bytes.extend_from_slice(&field);
+}
+
+fn push_i16(bytes: &mut Vec<u8>, value: i16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{test_metadata, test_sample};
+
+    /// A header field, without the padding.
+    fn field(bytes: &[u8], offset: usize, width: usize) -> &str {
+        std::str::from_utf8(&bytes[offset..offset + width]).unwrap().trim_end()
+    }
+
+    /// Splits a file without the pulse curve into its data records: SpO2, pulse and annotations.
+    fn records(bytes: &[u8]) -> Vec<(i16, i16, Vec<u8>)> {
+        let header_bytes: usize = field(bytes, 184, 8).parse().unwrap();
+        let signal_count: usize = field(bytes, 252, 4).parse().unwrap();
+        assert_eq!(signal_count, 3);
+        // the number of samples per record comes after 216 bytes of other fields for each signal
+        let annotation_samples: usize = field(bytes, 256 + signal_count * 216 + 2 * 8, 8).parse().unwrap();
+        let record_size = 4 + 2 * annotation_samples;
+        assert_eq!((bytes.len() - header_bytes) % record_size, 0);
+        bytes[header_bytes..].chunks(record_size)
+            .map(|r| (
+                i16::from_le_bytes([r[0], r[1]]),
+                i16::from_le_bytes([r[2], r[3]]),
+                r[4..].to_vec(),
+            ))
+            .collect()
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    #[test]
+    fn header() {
+        let samples: Vec<Sample> = (0..3).map(|i| test_sample(i, Some(60), Some(97))).collect();
+        let bytes = encode_edf(&test_metadata(), &samples, &[], &[]);
+
+        assert_eq!(field(&bytes, 0, 8), "0");
+        assert_eq!(field(&bytes, 8, 80), "X X X X");
+        assert_eq!(field(&bytes, 88, 80), "Startdate 01-MAR-2021 X X my_dev");
+        assert_eq!(field(&bytes, 168, 8), "01.03.21");
+        assert_eq!(field(&bytes, 176, 8), "22.00.00");
+        assert_eq!(field(&bytes, 184, 8), "1024");
+        assert_eq!(field(&bytes, 192, 44), "EDF+C");
+        assert_eq!(field(&bytes, 236, 8), "3");
+        assert_eq!(field(&bytes, 244, 8), "1");
+        assert_eq!(field(&bytes, 252, 4), "3");
+        assert_eq!(field(&bytes, 256, 16), "SpO2");
+        assert_eq!(field(&bytes, 272, 16), "Pulse");
+        assert_eq!(field(&bytes, 288, 16), "EDF Annotations");
+        assert_eq!(records(&bytes).len(), 3);
+    }
+
+    #[test]
+    fn annotations() {
+        let mut samples = vec![
+            test_sample(0, Some(60), Some(97)),
+            test_sample(1, None, None),
+            test_sample(2, Some(61), Some(96)),
+        ];
+        samples[2].artifacts.flatline = true;
+        let desaturation = EdfAnnotation {
+            onset: test_metadata().start_time + Duration::milliseconds(500),
+            duration: Duration::milliseconds(1500),
+            label: "Desaturation".to_owned(),
+        };
+        let bytes = encode_edf(&test_metadata(), &samples, &[], &[desaturation]);
+
+        let records = records(&bytes);
+        assert_eq!((records[0].0, records[0].1), (97, 60));
+        assert_eq!((records[1].0, records[1].1), (0, 0));
+        assert_eq!((records[2].0, records[2].1), (96, 61));
+        for (i, (_, _, annotations)) in records.iter().enumerate() {
+            // every record starts with its time-keeping TAL
+            assert!(annotations.starts_with(format!("+{}\x14\x14\x00", i).as_bytes()));
+        }
+        assert!(contains(&records[0].2, "+0.500\x151.500\x14Desaturation\x14\x00"));
+        assert!(contains(&records[1].2, "+1\x151\x14SpO2 invalid\x14\x00"));
+        assert!(contains(&records[1].2, "+1\x151\x14Pulse invalid\x14\x00"));
+        assert!(contains(&records[2].2, "+2\x151\x14Artifact (flatline)\x14\x00"));
+    }
+
+    #[test]
+    fn stored_samples_are_placed_by_time() {
+        let mut samples = vec![
+            test_sample(0, Some(60), Some(97)),
+            test_sample(1, Some(61), Some(96)),
+            test_sample(3, Some(62), Some(95)),
+        ];
+        samples[1].timestamp = samples[1].timestamp + Duration::milliseconds(400);
+        let bytes = encode_edf(&test_metadata(), &samples, &[], &[]);
+
+        let records = records(&bytes);
+        let spo2: Vec<i16> = records.iter().map(|r| r.0).collect();
+        assert_eq!(spo2, vec![97, 96, 0, 95]);
+        assert!(contains(&records[2].2, "+2\x151\x14SpO2 invalid\x14\x00"));
+    }
+
+    #[test]
+    fn live_samples_are_placed_by_order() {
+        let metadata = RecordingMetadata {
+            mode: SourceMode::Live,
+            ..test_metadata()
+        };
+        // rounding would put the third sample into slot 3 and the fourth into slot 4, leaving
+        // slot 2 empty; after the gap, slots are skipped
+        let samples: Vec<Sample> = [0, 1450, 2550, 3500, 9000].iter().enumerate()
+            .map(|(i, ms)| Sample {
+                timestamp: metadata.start_time + Duration::milliseconds(*ms),
+                ..test_sample(0, Some(60), Some(90 + i as u8))
+            })
+            .collect();
+        let bytes = encode_edf(&metadata, &samples, &[], &[]);
+
+        let records = records(&bytes);
+        let spo2: Vec<i16> = records.iter().map(|r| r.0).collect();
+        assert_eq!(spo2, vec![90, 91, 92, 93, 0, 0, 0, 0, 0, 94]);
+        let invalid_annotations = records.iter()
+            .filter(|r| contains(&r.2, "SpO2 invalid"))
+            .count();
+        assert_eq!(invalid_annotations, 1);
+        assert!(contains(&records[4].2, "+4\x155\x14SpO2 invalid\x14\x00"));
+    }
+}
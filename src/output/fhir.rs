@@ -0,0 +1,269 @@
+use std::io::{self, Write};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample};
+
+
+/// The interval between two samples, in milliseconds.
+const SAMPLE_PERIOD_MS: u32 = 1000;
+
+const SPO2: VitalSign = VitalSign {
+    // the oxygen saturation profile asks for the general code, pulse oximetry for the specific one
+    loinc_codes: &[
+        ("2708-6", "Oxygen saturation in Arterial blood"),
+        ("59408-5", "Oxygen saturation in Arterial blood by Pulse oximetry"),
+    ],
+    ucum_unit: "%",
+    upper_limit: 100,
+};
+
+const PULSE_RATE: VitalSign = VitalSign {
+    loinc_codes: &[("8867-4", "Heart rate")],
+    ucum_unit: "/min",
+    upper_limit: 255,
+};
+
+
+/// The coding of a vital sign observed by the oximeter.
+struct VitalSign {
+    /// The LOINC codes and their display names.
+    loinc_codes: &'static [(&'static str, &'static str)],
+    ucum_unit: &'static str,
+    upper_limit: u8,
+}
+
+
+/// Outputs samples as an HL7 FHIR R4 transaction `Bundle`.
+///
+/// The bundle contains a `Patient` resource for the person wearing the oximeter, a `Device`
+/// resource for the oximeter and one `Observation` each for SpO2 (LOINC 2708-6 and 59408-5) and
+/// pulse rate (LOINC 8867-4), with the once-per-second series as `valueSampledData`. As the
+/// oximeter knows nothing about the patient, the `Patient` resource is empty; the vital signs
+/// profiles require the observations to refer to one all the same. Invalid values are encoded as `E` (error). As sampled data may not be empty,
+/// the observations are left out if there are no samples at all.
+pub struct FhirSink {
+    writer: Box<dyn Write>,
+    metadata: Option<RecordingMetadata>,
+    samples: Vec<Sample>,
+}
+impl FhirSink {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer,
+            metadata: None,
+            samples: Vec::new(),
+        }
+    }
+}
+impl OutputSink for FhirSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        self.metadata = Some(metadata.clone());
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        self.samples.push(*sample);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // nothing can be written before the end
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let metadata = self.metadata.as_ref()
+            .expect("FHIR output finished without metadata");
+        let bundle = encode_bundle(metadata, &self.samples);
+        serde_json::to_writer_pretty(&mut self.writer, &bundle)?;
+        writeln!(self.writer)?;
+        self.writer.flush()
+    }
+}
+
+
+/// Assembles the transaction bundle.
+pub fn encode_bundle(metadata: &RecordingMetadata, samples: &[Sample]) -> Value {
+    let patient_url = format!("urn:uuid:{}", Uuid::new_v4());
+    let device_url = format!("urn:uuid:{}", Uuid::new_v4());
+    let start = metadata.start_time;
+    let end = samples.last()
+        .map(|s| s.timestamp)
+        .unwrap_or(start);
+
+    let patient = json!({
+        "resourceType": "Patient",
+    });
+    let mut device = json!({
+        "resourceType": "Device",
+        "type": {
+            "text": "Pulse oximeter",
+        },
+        "patient": {
+            "reference": patient_url,
+        },
+    });
+    if let Some(device_id) = &metadata.device_id {
+        device["identifier"] = json!([{ "value": device_id }]);
+        device["deviceName"] = json!([{ "name": device_id, "type": "user-friendly-name" }]);
+    }
+
+    let mut entries = vec![
+        bundle_entry(patient_url.clone(), patient, "Patient"),
+        bundle_entry(device_url.clone(), device, "Device"),
+    ];
+    if !samples.is_empty() {
+        let spo2_observation = observation(
+            &SPO2, &patient_url, &device_url, &start, &end, samples.iter().map(|s| s.spo2),
+        );
+        let pulse_observation = observation(
+            &PULSE_RATE, &patient_url, &device_url, &start, &end, samples.iter().map(|s| s.pulse),
+        );
+        entries.push(bundle_entry(format!("urn:uuid:{}", Uuid::new_v4()), spo2_observation, "Observation"));
+        entries.push(bundle_entry(format!("urn:uuid:{}", Uuid::new_v4()), pulse_observation, "Observation"));
+    }
+
+    json!({
+        "resourceType": "Bundle",
+        "type": "transaction",
+        "timestamp": fhir_date_time(&Local::now()),
+        "entry": entries,
+    })
+}
+
+fn bundle_entry(full_url: String, resource: Value, resource_type: &str) -> Value {
+    json!({
+        "fullUrl": full_url,
+        "resource": resource,
+        "request": {
+            "method": "POST",
+            "url": resource_type,
+        },
+    })
+}
+
+fn observation<I: Iterator<Item = Option<u8>>>(
+    vital_sign: &VitalSign,
+    patient_url: &str,
+    device_url: &str,
+    start: &DateTime<Local>,
+    end: &DateTime<Local>,
+    values: I,
+) -> Value {
+    let data: Vec<String> = values
+        .map(|v| v.map(|v| v.to_string()).unwrap_or_else(|| "E".to_owned()))
+        .collect();
+    let codings: Vec<Value> = vital_sign.loinc_codes.iter()
+        .map(|(code, display)| json!({
+            "system": "http://loinc.org",
+            "code": code,
+            "display": display,
+        }))
+        .collect();
+
+    json!({
+        "resourceType": "Observation",
+        "status": "final",
+        "category": [{
+            "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+                "code": "vital-signs",
+                "display": "Vital Signs",
+            }],
+        }],
+        "code": {
+            "coding": codings,
+        },
+        "subject": {
+            "reference": patient_url,
+        },
+        "effectivePeriod": {
+            "start": fhir_date_time(start),
+            "end": fhir_date_time(end),
+        },
+        "device": {
+            "reference": device_url,
+        },
+        "valueSampledData": {
+            "origin": {
+                "value": 0,
+                "unit": vital_sign.ucum_unit,
+                "system": "http://unitsofmeasure.org",
+                "code": vital_sign.ucum_unit,
+            },
+            "period": SAMPLE_PERIOD_MS,
+            "lowerLimit": 0,
+            "upperLimit": vital_sign.upper_limit,
+            "dimensions": 1,
+            "data": data.join(" "),
+        },
+    })
+}
+
+fn fhir_date_time(timestamp: &DateTime<Local>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Secs, false)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{test_metadata, test_sample};
+
+    fn resource_types(bundle: &Value) -> Vec<&str> {
+        bundle["entry"].as_array().unwrap().iter()
+            .map(|e| e["resource"]["resourceType"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn empty_recording_has_no_observations() {
+        let bundle = encode_bundle(&test_metadata(), &[]);
+        assert_eq!(resource_types(&bundle), vec!["Patient", "Device"]);
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        let samples: Vec<Sample> = (0..3)
+            .map(|i| test_sample(i, None, None))
+            .collect();
+        let bundle = encode_bundle(&test_metadata(), &samples);
+        assert_eq!(resource_types(&bundle), vec!["Patient", "Device", "Observation", "Observation"]);
+        for entry in &bundle["entry"].as_array().unwrap()[2..] {
+            assert_eq!(entry["resource"]["valueSampledData"]["data"], json!("E E E"));
+        }
+    }
+
+    /// Returns the LOINC codes of an observation.
+    fn loinc_codes(observation: &Value) -> Vec<&str> {
+        observation["code"]["coding"].as_array().unwrap().iter()
+            .inspect(|c| assert_eq!(c["system"], json!("http://loinc.org")))
+            .map(|c| c["code"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn vital_signs_profile_fields() {
+        let samples = vec![test_sample(0, Some(61), Some(97)), test_sample(1, Some(62), Some(96))];
+        let bundle = encode_bundle(&test_metadata(), &samples);
+        let patient_url = &bundle["entry"][0]["fullUrl"];
+        let device_url = &bundle["entry"][1]["fullUrl"];
+        assert_eq!(bundle["entry"][1]["resource"]["patient"]["reference"], *patient_url);
+
+        let spo2 = &bundle["entry"][2]["resource"];
+        let pulse = &bundle["entry"][3]["resource"];
+        assert_eq!(loinc_codes(spo2), vec!["2708-6", "59408-5"]);
+        assert_eq!(loinc_codes(pulse), vec!["8867-4"]);
+        for observation in [spo2, pulse] {
+            assert_eq!(observation["subject"]["reference"], *patient_url);
+            assert_eq!(observation["device"]["reference"], *device_url);
+            assert_eq!(observation["category"][0]["coding"][0]["code"], json!("vital-signs"));
+        }
+        assert_eq!(spo2["valueSampledData"]["data"], json!("97 96"));
+        assert_eq!(pulse["valueSampledData"]["data"], json!("61 62"));
+    }
+}
@@ -0,0 +1,391 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{DateTime, Local};
+
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample};
+
+
+/// The name of the measurement into which the samples are written.
+const MEASUREMENT: &str = "oximetry";
+
+/// How long lines may wait for a batch to fill up before they are sent anyway, in seconds.
+const BATCH_INTERVAL_SECS: u64 = 10;
+
+/// How long a request to the endpoint may take before it is abandoned, in seconds.
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+/// How many batches are kept while the endpoint cannot be reached before the oldest lines are
+/// dropped.
+const MAX_PENDING_BATCHES: usize = 10;
+
+
+/// Where the lines go.
+enum InfluxTarget {
+    /// A file or standard output, written line by line.
+    Stream(Box<dyn Write>),
+
+    /// The HTTP write endpoint of an InfluxDB server, written in batches.
+    Http(InfluxHttpClient),
+}
+
+
+/// Sends batches of lines to the HTTP write endpoint of an InfluxDB server (`/write` in version 1,
+/// `/api/v2/write` in version 2).
+///
+/// The requests are made by a background thread so that a slow or unreachable server never holds
+/// up the samples; failures are only reported.
+struct InfluxHttpClient {
+    lines: Option<Sender<String>>,
+    sender_thread: Option<JoinHandle<()>>,
+}
+impl InfluxHttpClient {
+    fn new(url: String, token: Option<String>, batch_size: usize) -> Self {
+        let (lines, receiver) = mpsc::channel();
+        let batcher = InfluxBatcher {
+            agent: ureq::AgentBuilder::new()
+                .timeout(StdDuration::from_secs(REQUEST_TIMEOUT_SECS))
+                .build(),
+            url,
+            token,
+            batch_size,
+            pending: Vec::new(),
+            oldest_pending: None,
+        };
+        let sender_thread = thread::spawn(move || batcher.run(receiver));
+        Self {
+            lines: Some(lines),
+            sender_thread: Some(sender_thread),
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if let Some(lines) = self.lines.as_ref() {
+            // if the thread is gone, it has already complained
+            let _ = lines.send(line);
+        }
+    }
+
+    /// Sends the remaining lines and waits for the background thread to finish.
+    fn finish(&mut self) {
+        // closing the channel tells the thread to send what it has and stop
+        self.lines = None;
+        if let Some(sender_thread) = self.sender_thread.take() {
+            if sender_thread.join().is_err() {
+                eprintln!("InfluxDB sender thread panicked");
+            }
+        }
+    }
+}
+
+
+/// Collects the lines into batches and sends them; runs on the background thread of an
+/// `InfluxHttpClient`.
+struct InfluxBatcher {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+    batch_size: usize,
+    pending: Vec<String>,
+    oldest_pending: Option<Instant>,
+}
+impl InfluxBatcher {
+    fn run(mut self, lines: Receiver<String>) {
+        let batch_interval = StdDuration::from_secs(BATCH_INTERVAL_SECS);
+        loop {
+            let wait = self.oldest_pending
+                .map(|t| batch_interval.saturating_sub(t.elapsed()))
+                .unwrap_or(batch_interval);
+            match lines.recv_timeout(wait) {
+                Ok(line) => {
+                    self.oldest_pending.get_or_insert_with(Instant::now);
+                    self.pending.push(line);
+                    if self.pending.len() >= self.batch_size {
+                        self.send_or_keep();
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    if !self.pending.is_empty() {
+                        self.send_or_keep();
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Err(e) = self.send() {
+                        eprintln!("failed to write to InfluxDB ({}); {} sample(s) lost", e, self.pending.len());
+                    }
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Sends the pending lines. If this fails, they are kept to be sent along with the next batch;
+    /// if too many have piled up, the oldest are dropped.
+    fn send_or_keep(&mut self) {
+        if let Err(e) = self.send() {
+            eprintln!("failed to write to InfluxDB ({}); retrying with the next batch", e);
+            let max_pending = self.batch_size * MAX_PENDING_BATCHES;
+            if self.pending.len() > max_pending {
+                let excess = self.pending.len() - max_pending;
+                self.pending.drain(..excess);
+            }
+
+            // don't retry before the next interval
+            self.oldest_pending = Some(Instant::now());
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut body = self.pending.join("\n");
+        body.push('\n');
+        let mut request = self.agent.post(&self.url)
+            .set("Content-Type", "text/plain; charset=utf-8");
+        if let Some(token) = self.token.as_ref() {
+            request = request.set("Authorization", &format!("Token {}", token));
+        }
+        request.send_string(&body)
+            .map_err(io::Error::other)?;
+
+        self.pending.clear();
+        self.oldest_pending = None;
+        Ok(())
+    }
+}
+
+
+/// Outputs samples in the InfluxDB line protocol, one line per sample with second precision.
+///
+/// The device ID and the recording mode are tags; SpO2 and pulse are integer fields, which are
+/// left out if invalid, and `status` is a string field (`ok`, `invalid` or `artifact`).
+///
+/// Live samples do not fall exactly on the second, so two of them may round to the same second.
+/// As InfluxDB keeps only one point per series and timestamp, the later one is then moved to the
+/// next second instead.
+pub struct InfluxSink {
+    target: InfluxTarget,
+    tags: String,
+
+    /// The timestamp of the previous line, in seconds since the Unix epoch.
+    last_timestamp: Option<i64>,
+}
+impl InfluxSink {
+    /// Creates a sink writing the lines to a file or standard output.
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            target: InfluxTarget::Stream(writer),
+            tags: String::new(),
+            last_timestamp: None,
+        }
+    }
+
+    /// Creates a sink sending the lines in batches of `batch_size` to the HTTP write endpoint at
+    /// `url`, authenticating with `token` if given (InfluxDB 2).
+    pub fn http(url: &str, token: Option<String>, batch_size: usize) -> Self {
+        // the timestamps are in seconds; tell the server
+        let url = if url.contains("precision=") {
+            url.to_owned()
+        } else if url.contains('?') {
+            format!("{}&precision=s", url)
+        } else {
+            format!("{}?precision=s", url)
+        };
+
+        Self {
+            target: InfluxTarget::Http(InfluxHttpClient::new(url, token, batch_size.max(1))),
+            tags: String::new(),
+            last_timestamp: None,
+        }
+    }
+}
+impl OutputSink for InfluxSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        // tags in lexicographic order, as recommended for performance
+        self.tags.clear();
+        if let Some(device_id) = metadata.device_id.as_deref().filter(|d| !d.is_empty()) {
+            self.tags.push_str(",device_id=");
+            self.tags.push_str(&escape_tag_value(device_id));
+        }
+        self.tags.push_str(",mode=");
+        self.tags.push_str(&escape_tag_value(metadata.mode.as_str()));
+        self.last_timestamp = None;
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        let mut fields = Vec::new();
+        if let Some(pulse) = sample.pulse {
+            fields.push(format!("pulse={}i", pulse));
+        }
+        if let Some(spo2) = sample.spo2 {
+            fields.push(format!("spo2={}i", spo2));
+        }
+        let status = if sample.pulse.is_none() || sample.spo2.is_none() {
+            "invalid"
+        } else if !sample.artifacts.is_empty() {
+            "artifact"
+        } else {
+            "ok"
+        };
+        fields.push(format!("status=\"{}\"", status));
+
+        let timestamp = match self.last_timestamp {
+            Some(last) => epoch_seconds(&sample.timestamp).max(last + 1),
+            None => epoch_seconds(&sample.timestamp),
+        };
+        self.last_timestamp = Some(timestamp);
+
+        let line = format!("{}{} {} {}", MEASUREMENT, self.tags, fields.join(","), timestamp);
+        match &mut self.target {
+            InfluxTarget::Stream(writer) => writeln!(writer, "{}", line),
+            InfluxTarget::Http(client) => {
+                client.push(line);
+                Ok(())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.target {
+            InfluxTarget::Stream(writer) => writer.flush(),
+            // the background thread sends the batches when they are due
+            InfluxTarget::Http(_) => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match &mut self.target {
+            InfluxTarget::Stream(writer) => writer.flush(),
+            InfluxTarget::Http(client) => {
+                client.finish();
+                Ok(())
+            },
+        }
+    }
+}
+
+
+/// Returns the timestamp as whole seconds since the Unix epoch, rounded to the nearest second
+/// (live samples do not fall exactly on the second).
+fn epoch_seconds(timestamp: &DateTime<Local>) -> i64 {
+    (timestamp.timestamp_millis() + 500).div_euclid(1000)
+}
+
+/// Escapes commas, equals signs and spaces in a tag value.
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use crate::output::{SharedBuffer, test_metadata, test_sample};
+
+    fn write_all(sink: &mut InfluxSink, samples: &[Sample]) {
+        sink.begin(&test_metadata()).unwrap();
+        for s in samples {
+            sink.write_sample(s).unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn line_protocol() {
+        let buffer = SharedBuffer::default();
+        let mut sink = InfluxSink::new(Box::new(buffer.clone()));
+        write_all(&mut sink, &[test_sample(0, Some(61), Some(97)), test_sample(1, None, None)]);
+
+        let epoch = test_metadata().start_time.timestamp();
+        assert_eq!(buffer.text(), format!(
+            "oximetry,device_id=my\\ dev,mode=automatic pulse=61i,spo2=97i,status=\"ok\" {}\n\
+             oximetry,device_id=my\\ dev,mode=automatic status=\"invalid\" {}\n",
+            epoch, epoch + 1,
+        ));
+    }
+
+    #[test]
+    fn timestamps_in_the_same_second_stay_apart() {
+        let buffer = SharedBuffer::default();
+        let mut sink = InfluxSink::new(Box::new(buffer.clone()));
+        // 0.4 s and 1 s both round to the same second as the sample before
+        let mut samples = vec![
+            test_sample(0, Some(61), Some(97)),
+            test_sample(0, Some(62), Some(96)),
+            test_sample(1, Some(63), Some(95)),
+        ];
+        samples[1].timestamp = samples[1].timestamp + chrono::Duration::milliseconds(400);
+        write_all(&mut sink, &samples);
+
+        let epoch = test_metadata().start_time.timestamp();
+        let timestamps: Vec<i64> = buffer.text().lines()
+            .map(|l| l.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(timestamps, vec![epoch, epoch + 1, epoch + 2]);
+    }
+
+    /// Answers one HTTP request with 204 and returns its request line, headers and body.
+    fn receive_request(listener: &TcpListener) -> (String, Vec<String>, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            headers.push(header.trim().to_owned());
+        }
+        let length: usize = headers.iter()
+            .find_map(|h| h.to_lowercase().strip_prefix("content-length:").map(|l| l.trim().parse().unwrap()))
+            .unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (&stream).write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").unwrap();
+        (request_line.trim().to_owned(), headers, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn http_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v2/write?bucket=b", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            (0..2).map(|_| receive_request(&listener)).collect::<Vec<_>>()
+        });
+
+        let mut sink = InfluxSink::http(&url, Some("secret".to_owned()), 2);
+        write_all(&mut sink, &[test_sample(0, Some(61), Some(97)), test_sample(1, Some(62), Some(96)), test_sample(2, Some(63), Some(95))]);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].0, "POST /api/v2/write?bucket=b&precision=s HTTP/1.1");
+        assert!(requests[0].1.iter().any(|h| h == "Authorization: Token secret"));
+        assert_eq!(requests[0].2.lines().count(), 2);
+        assert_eq!(requests[1].2.lines().count(), 1);
+    }
+
+    #[test]
+    fn unreachable_server_does_not_fail() {
+        // nothing listens on the port once the listener is gone
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut sink = InfluxSink::http(&format!("http://{}/write", address), None, 1);
+        write_all(&mut sink, &[test_sample(0, Some(61), Some(97)), test_sample(1, Some(62), Some(96))]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{self, Write};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde_json::{json, Value};
+
+use crate::clock::TimestampFormatter;
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample};
+
+
+/// Outputs one JSON object per line: first the metadata (`"type": "metadata"`), then one object
+/// per sample (`"type": "sample"`). Invalid values are `null`; `"artifacts"` lists the artifact
+/// flags, if any.
+///
+/// The start time in the metadata is always absolute (RFC 3339), even if the sample timestamps are
+/// monotonic, so that the recording can still be placed in time.
+pub struct JsonLinesSink {
+    writer: Box<dyn Write>,
+    formatter: TimestampFormatter,
+}
+impl JsonLinesSink {
+    pub fn new(writer: Box<dyn Write>, formatter: TimestampFormatter) -> Self {
+        Self {
+            writer,
+            formatter,
+        }
+    }
+
+    fn timestamp_value(&self, timestamp: &DateTime<Local>) -> Value {
+        let formatted = self.formatter.format(timestamp);
+        if self.formatter.is_numeric() {
+            formatted.parse::<f64>()
+                .map(Value::from)
+                .unwrap_or(Value::String(formatted))
+        } else {
+            Value::String(formatted)
+        }
+    }
+
+    fn write_line(&mut self, value: &Value) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, value)?;
+        writeln!(self.writer)
+    }
+}
+impl OutputSink for JsonLinesSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        let line = json!({
+            "type": "metadata",
+            "device_id": metadata.device_id,
+            "start_time": metadata.start_time.to_rfc3339_opts(SecondsFormat::AutoSi, false),
+            "mode": metadata.mode.as_str(),
+        });
+        self.write_line(&line)
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        let line = json!({
+            "type": "sample",
+            "timestamp": self.timestamp_value(&sample.timestamp),
+            "pulse": sample.pulse,
+            "spo2": sample.spo2,
+            "artifacts": sample.artifacts.names(),
+        });
+        self.write_line(&line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use crate::opts::TimestampMode;
+    use crate::output::SharedBuffer;
+    use crate::recording::{ArtifactFlags, SourceMode};
+
+    #[test]
+    fn monotonic_timestamps_keep_absolute_start() {
+        let start = Local.ymd(2021, 3, 1).and_hms(22, 0, 0);
+        let buffer = SharedBuffer::default();
+        let formatter = TimestampFormatter::new(TimestampMode::Monotonic, start, false);
+        let mut sink = JsonLinesSink::new(Box::new(buffer.clone()), formatter);
+        let metadata = RecordingMetadata {
+            device_id: Some("ABC".to_owned()),
+            start_time: start,
+            mode: SourceMode::Automatic,
+        };
+        sink.begin(&metadata).unwrap();
+        sink.write_sample(&Sample {
+            timestamp: start + Duration::seconds(4),
+            pulse: Some(60),
+            spo2: None,
+            artifacts: ArtifactFlags::default(),
+        }).unwrap();
+        sink.finish().unwrap();
+
+        let lines: Vec<Value> = buffer.text().lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let start_time = DateTime::parse_from_rfc3339(lines[0]["start_time"].as_str().unwrap()).unwrap();
+        assert_eq!(start_time, start);
+        assert_eq!(lines[1]["timestamp"], json!(4.0));
+        assert_eq!(lines[1]["spo2"], Value::Null);
+    }
+}
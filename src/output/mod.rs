@@ -0,0 +1,173 @@
+mod csv;
+mod edf;
+mod fhir;
+mod influx;
+mod jsonl;
+mod oscar;
+mod rotating;
+mod spo2_assistant;
+mod tee;
+
+
+use std::io::{self, Write};
+
+use crate::clock::TimestampFormatter;
+use crate::opts::OutputFormat;
+use crate::recording::{Recording, RecordingMetadata, Sample, WaveformPoint};
+
+pub use self::csv::{csv_field, CsvSink};
+pub use self::edf::{EdfAnnotation, EdfSink, encode_edf};
+pub use self::fhir::FhirSink;
+pub use self::influx::InfluxSink;
+pub use self::jsonl::JsonLinesSink;
+pub use self::oscar::OscarSink;
+pub use self::rotating::RotatingSink;
+pub use self::spo2_assistant::{SPO2_ASSISTANT_HEADER, Spo2AssistantSink};
+pub use self::tee::TeeSink;
+
+
+/// Serializes samples into an output format.
+///
+/// Formats whose header depends on the series as a whole (EDF, OSCAR and FHIR) collect everything
+/// in memory and only write it out when the output is finished; flushing them does nothing.
+pub trait OutputSink {
+    /// Outputs the information about the series as a whole. Called once, before any samples.
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()>;
+
+    /// Outputs a single sample.
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()>;
+
+    /// Outputs a single point of the pulse curve. Only available in live mode; most formats
+    /// ignore it.
+    fn write_waveform(&mut self, _point: &WaveformPoint) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Ensures that everything output until now has been written, e.g. after a batch of live
+    /// samples.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Completes the output. Called once, after all samples.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+
+/// Creates the sink for the given format, writing to the given writer.
+pub fn create_sink(format: OutputFormat, writer: Box<dyn Write>, formatter: TimestampFormatter) -> Box<dyn OutputSink> {
+    match format {
+        OutputFormat::Csv => Box::new(CsvSink::new(writer, formatter)),
+        OutputFormat::Jsonl => Box::new(JsonLinesSink::new(writer, formatter)),
+        OutputFormat::Edf => Box::new(EdfSink::new(writer)),
+        OutputFormat::Spo2Assistant => Box::new(Spo2AssistantSink::new(writer)),
+        OutputFormat::Oscar => Box::new(OscarSink::new(writer)),
+        OutputFormat::Fhir => Box::new(FhirSink::new(writer)),
+        OutputFormat::Influx => Box::new(InfluxSink::new(writer)),
+    }
+}
+
+
+/// The customary file name extension (without the dot) for files in the given format.
+pub fn file_extension(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Csv => "csv",
+        OutputFormat::Jsonl => "jsonl",
+        OutputFormat::Edf => "edf",
+        OutputFormat::Spo2Assistant => "csv",
+        OutputFormat::Oscar => "spoR",
+        OutputFormat::Fhir => "json",
+        OutputFormat::Influx => "lp",
+    }
+}
+
+
+/// For the formats that are only written once the output is finished, roughly how many bytes each
+/// sample (including the pulse curve points that come with it) adds to the file; `None` for the
+/// formats that are written as the samples arrive.
+pub fn buffered_sample_size(format: OutputFormat) -> Option<u64> {
+    match format {
+        OutputFormat::Csv => None,
+        OutputFormat::Jsonl => None,
+        // SpO2, pulse, 20 pleth points and an annotation of a few bytes per data record
+        OutputFormat::Edf => Some(56),
+        OutputFormat::Spo2Assistant => None,
+        OutputFormat::Oscar => Some(2),
+        // a value and a space in each of the two observations
+        OutputFormat::Fhir => Some(8),
+        OutputFormat::Influx => None,
+    }
+}
+
+
+/// The maximum number of samples a file in the given format can hold, if limited.
+pub fn max_file_samples(format: OutputFormat) -> Option<usize> {
+    match format {
+        OutputFormat::Oscar => Some(oscar::MAX_SAMPLES),
+        _ => None,
+    }
+}
+
+
+/// Outputs a complete recording to the given sink.
+pub fn write_recording(sink: &mut dyn OutputSink, recording: &Recording) -> io::Result<()> {
+    sink.begin(&recording.metadata)?;
+    for sample in &recording.samples {
+        sink.write_sample(sample)?;
+    }
+    sink.finish()
+}
+
+
+/// The metadata of the series written in the sink tests.
+#[cfg(test)]
+pub(crate) fn test_metadata() -> RecordingMetadata {
+    use chrono::{Local, TimeZone};
+    use crate::recording::SourceMode;
+
+    RecordingMetadata {
+        device_id: Some("my dev".to_owned()),
+        start_time: Local.ymd(2021, 3, 1).and_hms(22, 0, 0),
+        mode: SourceMode::Automatic,
+    }
+}
+
+/// A sample of the series written in the sink tests, `seconds` after its start.
+#[cfg(test)]
+pub(crate) fn test_sample(seconds: i64, pulse: Option<u8>, spo2: Option<u8>) -> Sample {
+    use chrono::Duration;
+    use crate::recording::ArtifactFlags;
+
+    Sample {
+        timestamp: test_metadata().start_time + Duration::seconds(seconds),
+        pulse,
+        spo2,
+        artifacts: ArtifactFlags::default(),
+    }
+}
+
+
+/// A writer whose contents can still be inspected after it has been handed to a sink.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+#[cfg(test)]
+impl SharedBuffer {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8(self.bytes())
+            .expect("output is not UTF-8")
+    }
+}
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
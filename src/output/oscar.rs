@@ -0,0 +1,137 @@
+use std::io::{self, Write};
+
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample};
+
+
+/// The offset at which the samples start; everything before it is header.
+const DATA_OFFSET: u16 = 0x2C;
+
+/// The offset of the start timestamp in the header.
+const START_TIME_OFFSET: usize = 8;
+
+/// The maximum number of characters of the start timestamp.
+const START_TIME_CHARS: usize = 18;
+
+/// The maximum number of samples (seconds) in a file, as limited by the 16-bit duration field.
+pub const MAX_SAMPLES: usize = u16::MAX as usize;
+
+
+/// Outputs samples as a SpO2 Review `.spoR` file, which OSCAR (Open Source CPAP Analysis Reporter)
+/// imports as CMS50 oximetry data.
+///
+/// The file consists of a header and one pulse byte and one SpO2 byte per second. The header starts
+/// with the little-endian offset of the samples, the constant 2 and the duration in seconds,
+/// followed by the start time as a UTF-16 string in the format `MM/dd/yy HH:mm:ss`. Invalid values
+/// are stored as 0, which OSCAR skips.
+///
+/// The layout follows the `.spoR` reader in OSCAR (`CMS50Loader::readSpoRFile` in
+/// `oscar/SleepLib/loader_plugins/cms50_loader.cpp`); it has not been compared against a file
+/// written by the vendor software. As the duration is a 16-bit value, a file can hold at most
+/// 65535 seconds (about 18 hours); longer recordings are rejected rather than cut short.
+pub struct OscarSink {
+    writer: Box<dyn Write>,
+    metadata: Option<RecordingMetadata>,
+    samples: Vec<Sample>,
+}
+impl OscarSink {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer,
+            metadata: None,
+            samples: Vec::new(),
+        }
+    }
+}
+impl OutputSink for OscarSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        self.metadata = Some(metadata.clone());
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        if self.samples.len() >= MAX_SAMPLES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("OSCAR files can hold at most {} seconds of samples; split the recording", MAX_SAMPLES),
+            ));
+        }
+        self.samples.push(*sample);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // nothing can be written before the end
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let metadata = self.metadata.as_ref()
+            .expect("OSCAR output finished without metadata");
+
+        let duration: u16 = self.samples.len().try_into()
+            .expect("more samples collected than fit into an OSCAR file");
+        let mut bytes = Vec::with_capacity(usize::from(DATA_OFFSET) + 2 * self.samples.len());
+        bytes.extend_from_slice(&DATA_OFFSET.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&duration.to_le_bytes());
+        bytes.resize(START_TIME_OFFSET, 0x00);
+
+        let start_time = metadata.start_time.format("%m/%d/%y %H:%M:%S").to_string();
+        for unit in start_time.encode_utf16().take(START_TIME_CHARS) {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        bytes.resize(DATA_OFFSET.into(), 0x00);
+
+        for sample in &self.samples {
+            bytes.push(sample.pulse.unwrap_or(0));
+            bytes.push(sample.spo2.unwrap_or(0));
+        }
+
+        self.writer.write_all(&bytes)?;
+        self.writer.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{SharedBuffer, test_metadata, test_sample};
+
+    #[test]
+    fn layout() {
+        let buffer = SharedBuffer::default();
+        let mut sink = OscarSink::new(Box::new(buffer.clone()));
+        sink.begin(&test_metadata()).unwrap();
+        sink.write_sample(&test_sample(0, Some(61), Some(97))).unwrap();
+        sink.write_sample(&test_sample(1, None, None)).unwrap();
+        sink.write_sample(&test_sample(2, Some(63), Some(95))).unwrap();
+        sink.finish().unwrap();
+
+        let mut expected = vec![
+            0x2C, 0x00, // offset of the samples
+            0x02, 0x00, // constant
+            0x03, 0x00, // duration
+            0x00, 0x00,
+        ];
+        for c in "03/01/21 22:00:00".encode_utf16() {
+            expected.extend_from_slice(&c.to_le_bytes());
+        }
+        expected.resize(0x2C, 0x00);
+        expected.extend_from_slice(&[61, 97, 0, 0, 63, 95]);
+        assert_eq!(buffer.bytes(), expected);
+    }
+
+    #[test]
+    fn rejects_overlong_recording() {
+        let mut sink = OscarSink::new(Box::new(SharedBuffer::default()));
+        sink.begin(&test_metadata()).unwrap();
+        for i in 0..MAX_SAMPLES {
+            sink.write_sample(&test_sample(i as i64, Some(60), Some(98))).unwrap();
+        }
+        let error = sink.write_sample(&test_sample(MAX_SAMPLES as i64, Some(60), Some(98)))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,404 @@
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{DateTime, Local};
+
+use crate::clock::TimestampFormatter;
+use crate::opts::{OutputFormat, TimestampMode};
+use crate::output::{buffered_sample_size, create_sink, file_extension, max_file_samples, OutputSink};
+use crate::recording::{RecordingMetadata, Sample, WaveformPoint};
+
+
+/// Passes the bytes on to the inner writer and counts them.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+}
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.set(self.count.get() + written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// The file currently being written by a `RotatingSink`.
+struct OpenFile {
+    sink: Box<dyn OutputSink>,
+
+    /// Another handle to the file, used to sync it to disk.
+    file: File,
+
+    /// How many bytes have been written into the file.
+    bytes: Rc<Cell<u64>>,
+
+    /// How many samples have been passed to the sink.
+    samples: u64,
+
+    /// The time of the first sample in the file.
+    start_time: DateTime<Local>,
+
+    last_synced: Instant,
+}
+impl OpenFile {
+    /// How large the file is or, for formats that are only written once finished, is going to be.
+    fn size(&self, format: OutputFormat) -> u64 {
+        match buffered_sample_size(format) {
+            Some(sample_size) => self.bytes.get() + self.samples * sample_size,
+            None => self.bytes.get(),
+        }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.sink.finish()?;
+        self.file.sync_all()
+    }
+}
+
+
+/// Writes the samples into a sequence of files in a directory, starting a new file whenever the
+/// current one has reached a given size or age, or is full.
+///
+/// Every file is complete in itself: it is named after the time of its first sample and begins
+/// with its own header. A new file is only started before a sample, so a sample is never split
+/// across two files. The age of a file is measured by the timestamps of its samples. For formats
+/// that are only written once the file is finished, the size is estimated from the number of
+/// samples, and formats that can only hold a limited number of samples (OSCAR) always get a new
+/// file once the current one is full. The current file is regularly synced to disk so that little is lost if the
+/// computer crashes or loses power; this only helps formats that are written as the samples
+/// arrive, not those (such as EDF) that can only be written once the file is complete.
+pub struct RotatingSink {
+    directory: PathBuf,
+    format: OutputFormat,
+    timestamps: TimestampMode,
+    max_bytes: Option<u64>,
+    max_age: Option<StdDuration>,
+    sync_interval: StdDuration,
+    metadata: Option<RecordingMetadata>,
+    current: Option<OpenFile>,
+}
+impl RotatingSink {
+    pub fn new(
+        directory: &Path, format: OutputFormat, timestamps: TimestampMode, max_bytes: Option<u64>,
+        max_age: Option<StdDuration>, sync_interval: StdDuration,
+    ) -> Self {
+        Self {
+            directory: directory.to_owned(),
+            format,
+            timestamps,
+            max_bytes,
+            max_age,
+            sync_interval,
+            metadata: None,
+            current: None,
+        }
+    }
+
+    /// Whether a new file has to be started before the given sample.
+    fn is_rotation_due(&self, sample: &Sample) -> bool {
+        let current = match self.current.as_ref() {
+            Some(c) => c,
+            None => return true,
+        };
+        if current.samples == 0 {
+            // starting yet another file would not make this one any smaller
+            return false;
+        }
+        let is_too_big = self.max_bytes
+            .map(|max| current.size(self.format) >= max)
+            .unwrap_or(false);
+        // a sample from before the start of the file (the clock was set back) does not count as old
+        let age = (sample.timestamp - current.start_time).to_std().ok();
+        let is_too_old = match (self.max_age, age) {
+            (Some(max), Some(age)) => age >= max,
+            _ => false,
+        };
+        let is_full = max_file_samples(self.format)
+            .map(|max| current.samples >= max as u64)
+            .unwrap_or(false);
+        is_too_big || is_too_old || is_full
+    }
+
+    /// Finishes the current file, if any, and starts a new one whose series begins at `start_time`.
+    fn rotate(&mut self, start_time: DateTime<Local>) -> io::Result<()> {
+        if let Some(current) = self.current.take() {
+            current.finish()?;
+        }
+
+        let (path, file) = self.create_file(start_time)?;
+        let sync_handle = file.try_clone()?;
+        let bytes = Rc::new(Cell::new(0));
+        let writer = CountingWriter {
+            inner: BufWriter::new(file),
+            count: Rc::clone(&bytes),
+        };
+        let formatter = TimestampFormatter::new(self.timestamps, start_time, true);
+        let mut sink = create_sink(self.format, Box::new(writer), formatter);
+
+        let mut metadata = self.metadata.clone()
+            .expect("rotating output started before metadata");
+        metadata.start_time = start_time;
+        sink.begin(&metadata)?;
+        sink.flush()?;
+
+        eprintln!("writing to {}", path.display());
+        self.current = Some(OpenFile {
+            sink,
+            file: sync_handle,
+            bytes,
+            samples: 0,
+            start_time,
+            last_synced: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Creates a new file named after the given time, adding a counter if a file of that name
+    /// already exists.
+    fn create_file(&self, start_time: DateTime<Local>) -> io::Result<(PathBuf, File)> {
+        let stem = format!("poxymeter-live-{}", start_time.format("%Y%m%d-%H%M%S"));
+        let extension = file_extension(self.format);
+        let mut counter = 0;
+        loop {
+            let name = if counter == 0 {
+                format!("{}.{}", stem, extension)
+            } else {
+                format!("{}-{}.{}", stem, counter, extension)
+            };
+            let path = self.directory.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => counter += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+impl OutputSink for RotatingSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        self.metadata = Some(metadata.clone());
+        self.rotate(metadata.start_time)
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        if self.is_rotation_due(sample) {
+            self.rotate(sample.timestamp)?;
+        }
+        let current = self.current.as_mut()
+            .expect("no current file after rotation");
+        current.sink.write_sample(sample)?;
+        current.samples += 1;
+        Ok(())
+    }
+
+    fn write_waveform(&mut self, point: &WaveformPoint) -> io::Result<()> {
+        // pulse curve points go into the file of the preceding sample
+        match self.current.as_mut() {
+            Some(current) => current.sink.write_waveform(point),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let current = match self.current.as_mut() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        current.sink.flush()?;
+        if current.last_synced.elapsed() >= self.sync_interval {
+            current.file.sync_data()?;
+            current.last_synced = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.current.take() {
+            Some(current) => current.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::output::{test_metadata, test_sample};
+    use crate::output::oscar::MAX_SAMPLES;
+
+    /// Creates an empty directory for the files of a test.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir()
+            .join(format!("poxymeter-test-{}-rotating-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Returns the names of the files in the directory and their contents, ordered by name.
+    fn files(directory: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<(String, Vec<u8>)> = fs::read_dir(directory).unwrap()
+            .map(|e| {
+                let entry = e.unwrap();
+                (entry.file_name().into_string().unwrap(), fs::read(entry.path()).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn write_all(sink: &mut RotatingSink, samples: &[Sample]) {
+        sink.begin(&test_metadata()).unwrap();
+        for sample in samples {
+            sink.write_sample(sample).unwrap();
+            sink.flush().unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    fn csv_sink(directory: &Path, max_bytes: Option<u64>, max_age: Option<StdDuration>) -> RotatingSink {
+        RotatingSink::new(directory, OutputFormat::Csv, TimestampMode::Monotonic, max_bytes, max_age, StdDuration::ZERO)
+    }
+
+    /// Returns the rows of a CSV file after checking that it starts with the metadata and header and
+    /// ends with a complete row.
+    fn csv_rows(contents: &[u8]) -> Vec<String> {
+        let text = String::from_utf8(contents.to_vec()).unwrap();
+        assert!(text.ends_with('\n'));
+        let mut lines = text.lines().skip_while(|l| l.starts_with('#'));
+        assert!(text.starts_with("# "));
+        assert_eq!(lines.next(), Some("timestamp,pulse,spo2,artifacts"));
+        lines.map(|l| l.to_owned()).collect()
+    }
+
+    #[test]
+    fn rotates_by_size_between_samples() {
+        let directory = test_directory("size");
+        // the metadata and header (109 bytes) fit, but not them and a row (13 bytes)
+        let mut sink = csv_sink(&directory, Some(120), None);
+        write_all(&mut sink, &[
+            test_sample(0, Some(61), Some(97)),
+            test_sample(1, Some(62), Some(96)),
+            test_sample(2, Some(63), Some(95)),
+        ]);
+
+        let files = files(&directory);
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec![
+            "poxymeter-live-20210301-220000.csv",
+            "poxymeter-live-20210301-220001.csv",
+            "poxymeter-live-20210301-220002.csv",
+        ]);
+        for ((_, contents), pulse) in files.iter().zip(61..) {
+            let rows = csv_rows(contents);
+            assert_eq!(rows.len(), 1);
+            assert!(rows[0].ends_with(&format!(",{},{},", pulse, 158 - pulse)));
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_age_of_samples() {
+        let directory = test_directory("age");
+        let mut sink = csv_sink(&directory, None, Some(StdDuration::from_secs(60)));
+        let samples: Vec<Sample> = (0..5)
+            .map(|i| test_sample(i * 30, Some(60), Some(98)))
+            .collect();
+        write_all(&mut sink, &samples);
+
+        let files = files(&directory);
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec![
+            "poxymeter-live-20210301-220000.csv",
+            "poxymeter-live-20210301-220100.csv",
+            "poxymeter-live-20210301-220200.csv",
+        ]);
+        let row_counts: Vec<usize> = files.iter().map(|(_, c)| csv_rows(c).len()).collect();
+        assert_eq!(row_counts, vec![2, 2, 1]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn does_not_rotate_before_the_first_sample() {
+        let directory = test_directory("header");
+        // not even the header fits, but the first file still receives a sample
+        let mut sink = csv_sink(&directory, Some(40), None);
+        write_all(&mut sink, &[test_sample(5, Some(61), Some(97))]);
+
+        let files = files(&directory);
+        assert_eq!(files.len(), 1);
+        assert_eq!(csv_rows(&files[0].1).len(), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn numbers_files_started_in_the_same_second() {
+        let directory = test_directory("collision");
+        let mut sink = csv_sink(&directory, Some(40), None);
+        let samples = vec![test_sample(0, Some(60), Some(98)); 3];
+        write_all(&mut sink, &samples);
+
+        let files = files(&directory);
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec![
+            "poxymeter-live-20210301-220000-1.csv",
+            "poxymeter-live-20210301-220000-2.csv",
+            "poxymeter-live-20210301-220000.csv",
+        ]);
+        for (_, contents) in &files {
+            assert_eq!(csv_rows(contents).len(), 1);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn estimates_size_of_buffered_formats() {
+        let directory = test_directory("buffered");
+        // OSCAR stores two bytes per sample, so five samples make up ten bytes
+        let mut sink = RotatingSink::new(
+            &directory, OutputFormat::Oscar, TimestampMode::Local, Some(10), None, StdDuration::from_secs(60),
+        );
+        let samples: Vec<Sample> = (0..12)
+            .map(|i| test_sample(i, Some(60), Some(98)))
+            .collect();
+        write_all(&mut sink, &samples);
+
+        let files = files(&directory);
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec![
+            "poxymeter-live-20210301-220000.spoR",
+            "poxymeter-live-20210301-220005.spoR",
+            "poxymeter-live-20210301-220010.spoR",
+        ]);
+        let sizes: Vec<usize> = files.iter().map(|(_, c)| c.len()).collect();
+        assert_eq!(sizes, vec![0x2C + 10, 0x2C + 10, 0x2C + 4]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn starts_new_file_when_full() {
+        let directory = test_directory("full");
+        let mut sink = RotatingSink::new(
+            &directory, OutputFormat::Oscar, TimestampMode::Local, None, None, StdDuration::from_secs(60),
+        );
+        let samples: Vec<Sample> = (0..=MAX_SAMPLES as i64)
+            .map(|i| test_sample(i, Some(60), Some(98)))
+            .collect();
+        write_all(&mut sink, &samples);
+
+        let sizes: Vec<usize> = files(&directory).iter().map(|(_, c)| c.len()).collect();
+        assert_eq!(sizes, vec![0x2C + 2 * MAX_SAMPLES, 0x2C + 2]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::{self, Write};
+
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample};
+
+
+/// The header row. Only the existence of a header row is known; the column names are a guess.
+pub const SPO2_ASSISTANT_HEADER: &str = "Date, Time, SpO2(%), PR(bpm)";
+
+/// The separator between the columns.
+const SEPARATOR: &str = ", ";
+
+/// The value written by SpO2 Assistant in place of an invalid reading.
+const INVALID_VALUE: u8 = 0x7F;
+
+
+/// Outputs samples in an approximation of the CSV layout exported by the vendor's SpO2 Assistant
+/// software.
+///
+/// The layout has not been verified against an actual export. All that is known (from
+/// `contrib/cmp_jsoncap_csv.py`) is that the columns are separated by a comma and a space, that the
+/// first row is a header and that 127 takes the place of invalid values. The column names, the
+/// split into separate date and time columns, their formats, the order of the SpO2 and pulse
+/// columns and the Windows line endings are assumptions. Spreadsheets and scripts built for the
+/// vendor software may therefore need adjustments.
+///
+/// As 127 is also a possible pulse rate, an invalid pulse next to a valid SpO2 value (which only
+/// the artifact filter produces) is read back as a pulse of 127.
+pub struct Spo2AssistantSink {
+    writer: Box<dyn Write>,
+}
+impl Spo2AssistantSink {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer,
+        }
+    }
+}
+impl OutputSink for Spo2AssistantSink {
+    fn begin(&mut self, _metadata: &RecordingMetadata) -> io::Result<()> {
+        write!(self.writer, "{}\r\n", SPO2_ASSISTANT_HEADER)
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        write!(
+            self.writer, "{}{}{}{}{}{}{}\r\n",
+            sample.timestamp.format("%Y-%m-%d"), SEPARATOR,
+            sample.timestamp.format("%H:%M:%S"), SEPARATOR,
+            sample.spo2.unwrap_or(INVALID_VALUE), SEPARATOR,
+            sample.pulse.unwrap_or(INVALID_VALUE),
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,53 @@
+use std::io;
+
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample, WaveformPoint};
+
+
+/// Passes everything on to multiple sinks.
+pub struct TeeSink {
+    sinks: Vec<Box<dyn OutputSink>>,
+}
+impl TeeSink {
+    pub fn new(sinks: Vec<Box<dyn OutputSink>>) -> Self {
+        Self {
+            sinks,
+        }
+    }
+}
+impl OutputSink for TeeSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.begin(metadata)?;
+        }
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.write_sample(sample)?;
+        }
+        Ok(())
+    }
+
+    fn write_waveform(&mut self, point: &WaveformPoint) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.write_waveform(point)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::cmp::Ordering;
 use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
 
 use hidapi::{HidDevice, HidResult};
 use log::{debug, log_enabled};
@@ -42,7 +43,7 @@ pub fn is_checksum_ok(bytes: &[u8]) -> bool {
 ///
 /// The corresponding response code to a command code is mostly `command_code ^ 0x70` and vice
 /// versa. However, there are exceptions to this rule.
-#[derive(Clone, Copy, Debug, Hash)]
+#[derive(Clone, Copy, Debug)]
 pub enum CommandCode {
     ReadyCommand,
     GetDeviceNameCommand,
@@ -208,21 +209,27 @@ impl Eq for CommandCode {
 }
 impl PartialOrd for CommandCode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let self_u8: u8 = self.into();
-        let other_u8: u8 = other.into();
-        Some(self_u8.cmp(&other_u8))
+        Some(self.cmp(other))
     }
 }
 impl Ord for CommandCode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        let self_u8: u8 = self.into();
+        let other_u8: u8 = other.into();
+        self_u8.cmp(&other_u8)
+    }
+}
+impl Hash for CommandCode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let self_u8: u8 = self.into();
+        self_u8.hash(state);
     }
 }
 
 
 /// The code of a property. Properties can be read using `CommandCode::ReadPropertyCommand` and
 /// written using `CommandCode::SetPropertyCommand`.
-#[derive(Clone, Copy, Debug, Hash)]
+#[derive(Clone, Copy, Debug)]
 pub enum PropertyCode {
     DeviceId,
     UnknownProperty04,
@@ -271,20 +278,26 @@ impl Eq for PropertyCode {
 }
 impl PartialOrd for PropertyCode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let self_u8: u8 = self.into();
-        let other_u8: u8 = other.into();
-        Some(self_u8.cmp(&other_u8))
+        Some(self.cmp(other))
     }
 }
 impl Ord for PropertyCode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        let self_u8: u8 = self.into();
+        let other_u8: u8 = other.into();
+        self_u8.cmp(&other_u8)
+    }
+}
+impl Hash for PropertyCode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let self_u8: u8 = self.into();
+        self_u8.hash(state);
     }
 }
 
 
 /// The recording mode for which the oximeter is currently configured.
-#[derive(Clone, Copy, Debug, Hash)]
+#[derive(Clone, Copy, Debug)]
 pub enum RecordingMode {
     Automatic,
     Manual,
@@ -327,14 +340,100 @@ impl Eq for RecordingMode {
 }
 impl PartialOrd for RecordingMode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let self_u16: u16 = self.into();
-        let other_u16: u16 = other.into();
-        Some(self_u16.cmp(&other_u16))
+        Some(self.cmp(other))
     }
 }
 impl Ord for RecordingMode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        let self_u16: u16 = self.into();
+        let other_u16: u16 = other.into();
+        self_u16.cmp(&other_u16)
+    }
+}
+impl Hash for RecordingMode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let self_u16: u16 = self.into();
+        self_u16.hash(state);
+    }
+}
+
+
+/// A point on the pulse curve (plethysmogram), as streamed in live mode.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct LiveCurvePoint {
+    pub flags: u8,
+    pub value: u8,
+    pub bar: u8,
+}
+impl LiveCurvePoint {
+    /// Whether the oximeter flags this point as a pulse beat (the point at which it beeps).
+    pub fn is_beat(&self) -> bool {
+        self.flags & 0x40 != 0
+    }
+}
+
+
+/// The current pulse and SpO2 values, as streamed in live mode.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct LiveValues {
+    pub flags: u8,
+    pub pulse: u8,
+    pub spo2: u8,
+}
+impl LiveValues {
+    /// Whether the values are to be trusted. If the finger is out or the signal is bad, the
+    /// oximeter sets a flag and pads the values with 0x7F.
+    pub fn are_valid(&self) -> bool {
+        self.flags & 0x02 == 0
+            && self.pulse != 0x7F
+            && self.spo2 != 0x7F
+    }
+}
+
+
+/// A decoded `CommandCode::LiveDataResponse`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LiveData {
+    /// A pulse curve point (type 0x00); arrives about once every 0.05 seconds.
+    Curve(LiveCurvePoint),
+
+    /// The current values (type 0x01); arrive about once per second.
+    Values(LiveValues),
+}
+impl LiveData {
+    /// Decodes a live data response. Returns `None` if the response is not a live data response,
+    /// is of an unknown type or has the wrong length. The checksum is not verified.
+    pub fn from_response(response: &[u8]) -> Option<Self> {
+        if response.len() < 2 {
+            return None;
+        }
+        if response[0] != u8::from(CommandCode::LiveDataResponse) {
+            return None;
+        }
+
+        match response[1] {
+            0x00 => {
+                if response.len() != 6 {
+                    return None;
+                }
+                Some(Self::Curve(LiveCurvePoint {
+                    flags: response[2],
+                    value: response[3],
+                    bar: response[4],
+                }))
+            },
+            0x01 => {
+                if response.len() < 8 {
+                    return None;
+                }
+                Some(Self::Values(LiveValues {
+                    flags: response[2],
+                    pulse: response[3],
+                    spo2: response[4],
+                }))
+            },
+            _ => None,
+        }
     }
 }
 
@@ -372,6 +471,22 @@ pub fn receive_from_oximeter(device: &HidDevice, queue: &mut CommandQueue) -> Hi
     Ok(())
 }
 
+/// Like `receive_from_oximeter`, but gives up after `timeout_ms` milliseconds. Returns whether any
+/// data has been received.
+pub fn receive_from_oximeter_timeout(device: &HidDevice, queue: &mut CommandQueue, timeout_ms: i32) -> HidResult<bool> {
+    let mut incoming_data = vec![0; 64];
+    debug!("reading with timeout...");
+    let bytes_read = device.read_timeout(&mut incoming_data, timeout_ms)?;
+    if bytes_read == 0 {
+        return Ok(false);
+    }
+    incoming_data.truncate(bytes_read);
+
+    queue.add_from_buffer(&incoming_data);
+
+    Ok(true)
+}
+
 /// Returns the index of the first byte in the slice where a new command starts. If no such byte is
 /// found, `None` is returned.
 pub fn index_of_command_start(bytes: &[u8]) -> Option<usize> {
@@ -387,7 +502,7 @@ pub fn index_of_command_start(bytes: &[u8]) -> Option<usize> {
 /// Returns a `Some(usize)` value if the correct length for the given command can be determined and
 /// `None` if not.
 fn command_expected_length(command: &[u8]) -> Option<usize> {
-    if command.len() == 0 {
+    if command.is_empty() {
         return None;
     }
 
@@ -432,13 +547,13 @@ impl CommandQueue {
             None => {
                 // (1) is apparently true; (2) might be as well (check that later)
                 // append the bytes to the holder
-                self.holder.extend_from_slice(&bytes);
+                self.holder.extend_from_slice(bytes);
             },
             Some(csi) => {
                 // append everything until this index to the holder
                 self.holder.extend_from_slice(&bytes[0..csi]);
 
-                if self.holder.len() > 0 {
+                if !self.holder.is_empty() {
                     // the holder now contains a full command; shunt it to the queue
                     // (the command might be invalid checksum-wise, but it's better to forward it to the
                     // user than to silently drop it)
@@ -502,7 +617,7 @@ impl CommandQueue {
             self.holder.truncate(self.holder.len() - 1);
         }
 
-        if self.holder.len() == 0 {
+        if self.holder.is_empty() {
             // the holder is empty; all commands are enqueued
             // everything is coming up daisies
             return;
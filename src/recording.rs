@@ -0,0 +1,168 @@
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone};
+
+
+/// The value with which the oximeter marks invalid samples in stored recordings.
+pub const INVALID_RECORDED_VALUE: u8 = 0xFF;
+
+
+/// Where a series of samples comes from.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SourceMode {
+    /// Streamed live from the oximeter.
+    Live,
+
+    /// Stored on the oximeter in automatic recording mode.
+    Automatic,
+
+    /// Stored on the oximeter in manual recording mode.
+    Manual,
+}
+impl SourceMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Live => "live",
+            Self::Automatic => "automatic",
+            Self::Manual => "manual",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "live" => Some(Self::Live),
+            "automatic" => Some(Self::Automatic),
+            "manual" => Some(Self::Manual),
+            _ => None,
+        }
+    }
+}
+
+
+/// Information about a series of samples as a whole.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RecordingMetadata {
+    pub device_id: Option<String>,
+    pub start_time: DateTime<Local>,
+    pub mode: SourceMode,
+}
+
+
+/// The reasons for which the artifact filter has flagged a sample.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ArtifactFlags {
+    /// A value changed faster than is physiologically plausible.
+    pub rate_of_change: bool,
+
+    /// The values did not change at all for suspiciously long.
+    pub flatline: bool,
+
+    /// A value was outside the plausible range.
+    pub out_of_range: bool,
+
+    /// A flagged value has been replaced by one interpolated from its neighbors.
+    pub interpolated: bool,
+}
+impl ArtifactFlags {
+    const NAMES: [&'static str; 4] = ["rate-of-change", "flatline", "out-of-range", "interpolated"];
+
+    fn as_array(&self) -> [bool; 4] {
+        [self.rate_of_change, self.flatline, self.out_of_range, self.interpolated]
+    }
+
+    /// Whether the sample has not been flagged at all.
+    pub fn is_empty(&self) -> bool {
+        self.as_array().iter().all(|f| !f)
+    }
+
+    /// Whether the sample has been flagged as an artifact (as opposed to only being interpolated).
+    pub fn is_artifact(&self) -> bool {
+        self.rate_of_change || self.flatline || self.out_of_range
+    }
+
+    /// The names of the set flags.
+    pub fn names(&self) -> Vec<&'static str> {
+        Self::NAMES.iter()
+            .zip(self.as_array().iter())
+            .filter(|(_, set)| **set)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Obtains the flags from their names, as returned by `names`. Returns `None` if a name is
+    /// unknown.
+    pub fn from_names<'a, I: Iterator<Item = &'a str>>(names: I) -> Option<Self> {
+        let mut flags = Self::default();
+        for name in names {
+            match name {
+                "rate-of-change" => flags.rate_of_change = true,
+                "flatline" => flags.flatline = true,
+                "out-of-range" => flags.out_of_range = true,
+                "interpolated" => flags.interpolated = true,
+                _ => return None,
+            }
+        }
+        Some(flags)
+    }
+}
+
+
+/// A single pulse and SpO2 reading. Values the oximeter has marked as invalid (e.g. because the
+/// finger was out) are `None`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Sample {
+    pub timestamp: DateTime<Local>,
+    pub pulse: Option<u8>,
+    pub spo2: Option<u8>,
+    pub artifacts: ArtifactFlags,
+}
+
+
+/// A point on the pulse curve (plethysmogram), only available in live mode.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct WaveformPoint {
+    pub timestamp: DateTime<Local>,
+    pub value: u8,
+    pub beat: bool,
+}
+
+
+/// A complete series of samples, such as a file stored on the oximeter.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Recording {
+    pub metadata: RecordingMetadata,
+    pub samples: Vec<Sample>,
+}
+impl Recording {
+    /// Assembles a recording from the once-per-second pulse and SpO2 values stored on the
+    /// oximeter, starting at the start time in the metadata.
+    pub fn from_series(metadata: RecordingMetadata, pulse_values: &[u8], spo2_values: &[u8]) -> Self {
+        let mut cur_time = metadata.start_time;
+        let mut samples = Vec::with_capacity(pulse_values.len().min(spo2_values.len()));
+        for (pulse, spo2) in pulse_values.iter().zip(spo2_values.iter()) {
+            samples.push(Sample {
+                timestamp: cur_time,
+                pulse: Some(*pulse).filter(|p| *p != INVALID_RECORDED_VALUE),
+                spo2: Some(*spo2).filter(|s| *s != INVALID_RECORDED_VALUE),
+                artifacts: ArtifactFlags::default(),
+            });
+            cur_time = cur_time + Duration::seconds(1);
+        }
+
+        Self {
+            metadata,
+            samples,
+        }
+    }
+}
+
+
+/// Interprets a timestamp from the oximeter's clock, which runs on local time.
+pub fn local_from_naive(naive: &NaiveDateTime) -> DateTime<Local> {
+    Local.from_local_datetime(naive)
+        .earliest()
+        .unwrap_or_else(|| {
+            // the clock was in a gap (the switch to daylight saving time); skip over it
+            Local.from_local_datetime(&(*naive + Duration::hours(1)))
+                .earliest()
+                .unwrap_or_else(|| Local.from_utc_datetime(naive))
+        })
+}
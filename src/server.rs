@@ -0,0 +1,343 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde_json::{json, Value};
+use tungstenite::Message;
+
+use crate::clock::TimestampFormatter;
+use crate::opts::TimestampMode;
+use crate::output::OutputSink;
+use crate::recording::{RecordingMetadata, Sample, WaveformPoint};
+
+
+/// How many messages may queue up for a client before it is considered too slow to keep up and
+/// disconnected.
+const CLIENT_QUEUE_LENGTH: usize = 1024;
+
+/// How long sending to a client may block before the client is considered gone, in seconds.
+const CLIENT_WRITE_TIMEOUT_SECS: u64 = 10;
+
+/// How often a WebSocket client is checked for pings and closing while no messages are being sent,
+/// in milliseconds.
+const WEBSOCKET_POLL_INTERVAL_MS: u64 = 50;
+
+
+/// The clients connected to a `LiveServer`.
+#[derive(Default)]
+struct ClientList {
+    /// The message describing the session, which every client receives first.
+    greeting: Option<Arc<str>>,
+
+    /// The queues of the messages for the individual clients.
+    queues: Vec<SyncSender<Arc<str>>>,
+}
+
+
+/// Distributes the live data to any number of clients connecting via TCP or WebSocket.
+///
+/// Each message is a JSON object: first the metadata (`"type": "metadata"`, with an absolute start
+/// time), then a stream of samples (`"type": "sample"`) and pulse curve points (`"type":
+/// "waveform"`). TCP clients receive one object per line; WebSocket clients receive one object per
+/// text message. Clients may connect and disconnect at any time; a client that connects later
+/// receives the metadata first and then the data from that point on.
+///
+/// Every client is served by its own thread so that a slow client does not hold up the live data
+/// or the other clients; if too many messages queue up for a client, it is disconnected.
+#[derive(Clone, Default)]
+pub struct LiveServer {
+    clients: Arc<Mutex<ClientList>>,
+}
+impl LiveServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts accepting TCP clients on the given address. Returns the address actually listened
+    /// on.
+    pub fn listen_tcp(&self, address: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let local_address = listener.local_addr()?;
+        let server = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream.and_then(prepare_client_stream) {
+                    Ok(s) => server.serve_tcp(s),
+                    Err(e) => eprintln!("failed to accept TCP client: {}", e),
+                }
+            }
+        });
+        Ok(local_address)
+    }
+
+    /// Starts accepting WebSocket clients on the given address. Returns the address actually
+    /// listened on.
+    pub fn listen_websocket(&self, address: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let local_address = listener.local_addr()?;
+        let server = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream.and_then(prepare_client_stream) {
+                    Ok(s) => {
+                        // the handshake may take a while; don't keep the other clients waiting
+                        let server = server.clone();
+                        thread::spawn(move || server.serve_websocket(s));
+                    },
+                    Err(e) => eprintln!("failed to accept WebSocket client: {}", e),
+                }
+            }
+        });
+        Ok(local_address)
+    }
+
+    /// Returns a sink which sends the live data to all connected clients. Timestamps are
+    /// formatted according to the given mode.
+    pub fn sink(&self, timestamps: TimestampMode) -> ServerSink {
+        ServerSink {
+            server: self.clone(),
+            timestamps,
+            formatter: None,
+        }
+    }
+
+    fn serve_tcp(&self, mut stream: TcpStream) {
+        let peer = describe_peer(&stream);
+        let messages = self.register();
+        eprintln!("TCP client {} connected", peer);
+        thread::spawn(move || {
+            for message in messages {
+                let result = stream.write_all(message.as_bytes())
+                    .and_then(|_| stream.write_all(b"\n"));
+                if result.is_err() {
+                    break;
+                }
+            }
+            eprintln!("TCP client {} disconnected", peer);
+        });
+    }
+
+    fn serve_websocket(&self, stream: TcpStream) {
+        let peer = describe_peer(&stream);
+        let mut socket = match tungstenite::accept(stream) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("WebSocket handshake with {} failed: {}", peer, e);
+                return;
+            },
+        };
+        let messages = self.register();
+        eprintln!("WebSocket client {} connected", peer);
+
+        // reads only wait briefly so that the client's pings and closing are handled in between
+        socket.get_ref().set_read_timeout(Some(StdDuration::from_millis(WEBSOCKET_POLL_INTERVAL_MS)))
+            .expect("failed to set WebSocket read timeout");
+        loop {
+            let mut pending: Vec<Arc<str>> = Vec::new();
+            match messages.recv_timeout(StdDuration::from_millis(WEBSOCKET_POLL_INTERVAL_MS)) {
+                Ok(message) => {
+                    pending.push(message);
+                    pending.extend(messages.try_iter());
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let mut sent = true;
+            for message in pending {
+                if socket.send(Message::Text(message.to_string())).is_err() {
+                    sent = false;
+                    break;
+                }
+            }
+            if !sent {
+                break;
+            }
+
+            match socket.read() {
+                Ok(_) => {},
+                Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+                Err(_) => break,
+            }
+        }
+        let _ = socket.close(None);
+        eprintln!("WebSocket client {} disconnected", peer);
+    }
+
+    /// Adds a client. Returns the messages for the client.
+    fn register(&self) -> Receiver<Arc<str>> {
+        let (sender, receiver) = mpsc::sync_channel(CLIENT_QUEUE_LENGTH);
+        let mut clients = self.clients.lock().expect("client list poisoned");
+        if let Some(greeting) = clients.greeting.as_ref() {
+            sender.try_send(Arc::clone(greeting))
+                .expect("failed to queue greeting");
+        }
+        clients.queues.push(sender);
+        receiver
+    }
+
+    /// Sends a message to all clients, dropping those that have gone away or cannot keep up.
+    fn broadcast(&self, message: &Value, is_greeting: bool) {
+        let message: Arc<str> = message.to_string().into();
+        let mut clients = self.clients.lock().expect("client list poisoned");
+        if is_greeting {
+            clients.greeting = Some(Arc::clone(&message));
+        }
+        clients.queues.retain(|queue| match queue.try_send(Arc::clone(&message)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                eprintln!("client is not keeping up; disconnecting it");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+
+fn prepare_client_stream(stream: TcpStream) -> io::Result<TcpStream> {
+    stream.set_write_timeout(Some(StdDuration::from_secs(CLIENT_WRITE_TIMEOUT_SECS)))?;
+    Ok(stream)
+}
+
+fn describe_peer(stream: &TcpStream) -> String {
+    stream.peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| "(unknown)".to_owned())
+}
+
+
+/// An output sink which sends the live data to the clients of a `LiveServer`.
+pub struct ServerSink {
+    server: LiveServer,
+    timestamps: TimestampMode,
+    formatter: Option<TimestampFormatter>,
+}
+impl ServerSink {
+    fn timestamp_value(&self, timestamp: &DateTime<Local>) -> Value {
+        let formatter = self.formatter.as_ref()
+            .expect("live data sent before metadata");
+        let formatted = formatter.format(timestamp);
+        if formatter.is_numeric() {
+            formatted.parse::<f64>()
+                .map(Value::from)
+                .unwrap_or(Value::String(formatted))
+        } else {
+            Value::String(formatted)
+        }
+    }
+}
+impl OutputSink for ServerSink {
+    fn begin(&mut self, metadata: &RecordingMetadata) -> io::Result<()> {
+        self.formatter = Some(TimestampFormatter::new(self.timestamps, metadata.start_time, true));
+        let message = json!({
+            "type": "metadata",
+            "device_id": metadata.device_id,
+            "start_time": metadata.start_time.to_rfc3339_opts(SecondsFormat::AutoSi, false),
+            "mode": metadata.mode.as_str(),
+        });
+        self.server.broadcast(&message, true);
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        let message = json!({
+            "type": "sample",
+            "timestamp": self.timestamp_value(&sample.timestamp),
+            "pulse": sample.pulse,
+            "spo2": sample.spo2,
+        });
+        self.server.broadcast(&message, false);
+        Ok(())
+    }
+
+    fn write_waveform(&mut self, point: &WaveformPoint) -> io::Result<()> {
+        let message = json!({
+            "type": "waveform",
+            "timestamp": self.timestamp_value(&point.timestamp),
+            "value": point.value,
+            "beat": point.beat,
+        });
+        self.server.broadcast(&message, false);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+    use crate::output::{test_metadata, test_sample};
+
+    /// Waits until the given number of clients has been registered.
+    fn wait_for_clients(server: &LiveServer, count: usize) {
+        let deadline = Instant::now() + StdDuration::from_secs(5);
+        while server.clients.lock().unwrap().queues.len() < count {
+            assert!(Instant::now() < deadline, "clients did not connect");
+            thread::sleep(StdDuration::from_millis(10));
+        }
+    }
+
+    fn connect(address: SocketAddr) -> impl Iterator<Item = Value> {
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(StdDuration::from_secs(5))).unwrap();
+        BufReader::new(stream).lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+    }
+
+    #[test]
+    fn tcp_clients_receive_greeting_then_data() {
+        let server = LiveServer::new();
+        let address = server.listen_tcp("127.0.0.1:0").unwrap();
+        let mut sink = server.sink(TimestampMode::Monotonic);
+        sink.begin(&test_metadata()).unwrap();
+
+        let mut clients = vec![connect(address), connect(address)];
+        wait_for_clients(&server, 2);
+        sink.write_sample(&test_sample(1, Some(61), Some(97))).unwrap();
+
+        for client in &mut clients {
+            let greeting = client.next().unwrap();
+            assert_eq!(greeting["type"], "metadata");
+            assert_eq!(greeting["device_id"], "my dev");
+            assert_eq!(greeting["mode"], "automatic");
+
+            let sample = client.next().unwrap();
+            assert_eq!(sample["type"], "sample");
+            assert_eq!(sample["timestamp"], 1.0);
+            assert_eq!((&sample["pulse"], &sample["spo2"]), (&json!(61), &json!(97)));
+        }
+    }
+
+    #[test]
+    fn slow_client_is_dropped() {
+        let server = LiveServer::new();
+        let mut sink = server.sink(TimestampMode::Monotonic);
+        sink.begin(&test_metadata()).unwrap();
+        let slow = server.register();
+        let fast = server.register();
+
+        // the greeting already occupies one place in the queue
+        for i in 0..CLIENT_QUEUE_LENGTH {
+            sink.write_sample(&test_sample(i as i64, Some(60), Some(98))).unwrap();
+            assert_eq!(fast.try_iter().count(), if i == 0 { 2 } else { 1 });
+        }
+        assert_eq!(server.clients.lock().unwrap().queues.len(), 1);
+
+        // the slow client still gets what was queued before it was dropped
+        assert_eq!(slow.iter().count(), CLIENT_QUEUE_LENGTH);
+    }
+}